@@ -1,6 +1,7 @@
 use proc_macro::TokenStream;
 use syn::{parse_macro_input, DeriveInput};
 
+mod map_net_entities;
 mod net_bundle;
 
 #[proc_macro_derive(NetBundle, attributes(networked))]
@@ -8,3 +9,9 @@ pub fn net_bundle_derive(input: TokenStream) -> TokenStream {
 	let input = parse_macro_input!(input as DeriveInput);
 	net_bundle::net_bundle_derive_help(input).unwrap_or_else(|r| r.to_compile_error().into())
 }
+
+#[proc_macro_derive(MapNetEntities, attributes(net_entity))]
+pub fn map_net_entities_derive(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	map_net_entities::map_net_entities_derive_help(input).unwrap_or_else(|r| r.to_compile_error().into())
+}
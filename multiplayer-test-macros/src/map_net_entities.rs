@@ -0,0 +1,51 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{Data, DataStruct, DeriveInput, Error, Index, Result};
+
+pub fn map_net_entities_derive_help(input: DeriveInput) -> Result<TokenStream> {
+	let name = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+	let data: DataStruct = match input.data {
+		Data::Struct(s) => s,
+		Data::Enum(e) => {
+			return Err(Error::new(
+				e.enum_token.span,
+				"Cannot derive MapNetEntities on an enum, only on a struct.",
+			))
+		}
+		Data::Union(u) => {
+			return Err(Error::new(
+				u.union_token.span,
+				"Cannot derive MapNetEntities on an union, only on a struct.",
+			))
+		}
+	};
+
+	let mut mapped = Vec::new();
+	for (i, field) in data.fields.iter().enumerate() {
+		if !field.attrs.iter().any(|attr| attr.path.is_ident("net_entity")) {
+			continue;
+		}
+		mapped.push(match field.ident.clone() {
+			Some(ident) => quote!(#ident),
+			None => {
+				let index = Index::from(i);
+				quote!(#index)
+			}
+		});
+	}
+
+	Ok(quote! {
+		impl #impl_generics ::multiplayer_test::replication::map_entities::MapNetEntities for #name #ty_generics #where_clause {
+			fn net_entities(&self, f: &mut dyn FnMut(::bevy::prelude::Entity)) {
+				#(::multiplayer_test::replication::map_entities::MapNetEntities::net_entities(&self.#mapped, f);)*
+			}
+
+			fn map_net_entities(&mut self, f: &mut dyn FnMut(&mut ::bevy::prelude::Entity)) {
+				#(::multiplayer_test::replication::map_entities::MapNetEntities::map_net_entities(&mut self.#mapped, f);)*
+			}
+		}
+	}
+	.into())
+}
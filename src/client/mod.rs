@@ -1,65 +1,168 @@
 use std::{marker::{PhantomData, Send, Sync}, net::{SocketAddrV4, Ipv4Addr}};
 
 use bevy::prelude::*;
-use serde::{Deserialize, Serialize};
 use tokio::net::ToSocketAddrs;
 
 use tokio::runtime::Handle;
 
 use crate::{
-	connection::{ext::Event, ConnectionHandle},
-	NetStage,
+	connection::{ext::Event, ConnectionConfig, ConnectionHandle, FlushMode},
+	messaging::{NetContext, NetDeserialize, NetSerialize},
+	tick::NetTick,
+	NetStage, NetSystem,
 };
 
-#[derive(Default, Debug)]
-pub struct ClientPlugin<S, R>
+/// Adds a [Client] resource and its [FromServer] events to the app.
+///
+/// The instance label `I` distinguishes multiple clients with the same message types,
+/// e.g. `ClientPlugin::<Msg, Msg, GameServer>` and `ClientPlugin::<Msg, Msg, ChatRelay>`.
+#[derive(Debug)]
+pub struct ClientPlugin<S, R, I = ()>
 where
-	S: Serialize + Send + 'static,
-	R: for<'de> Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
+	I: Send + Sync + 'static,
 {
 	_s: PhantomData<S>,
 	_r: PhantomData<R>,
+	_i: PhantomData<I>,
 }
 
-impl<S, R> Plugin for ClientPlugin<S, R>
+impl<S, R, I> Default for ClientPlugin<S, R, I>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
+	I: Send + Sync + 'static,
+{
+	fn default() -> Self {
+		Self {
+			_s: PhantomData,
+			_r: PhantomData,
+			_i: PhantomData,
+		}
+	}
+}
+
+impl<S, R, I> Plugin for ClientPlugin<S, R, I>
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
 {
 	fn build(&self, app: &mut App) {
-		app.add_system_to_stage(NetStage::Receive, Client::<S, R>::event_system)
-			.insert_resource(Client::<S, R>(None))
-			.add_event::<FromServer<R>>();
+		let ctx = NetContext::from_app(app);
+		let config = app.world.get_resource::<ConnectionConfig>().cloned().unwrap_or_default();
+		app.add_system_to_stage(NetStage::Receive, Client::<S, R, I>::event_system.label(NetSystem::Receive))
+			.add_system_to_stage(NetStage::Flush, Client::<S, R, I>::flush_system)
+			.insert_resource(Client::<S, R, I>::new(None, ctx, config))
+			.add_event::<FromServer<R, I>>();
 	}
 }
 
-#[derive(Debug, Deref, DerefMut)]
-pub struct FromServer<R>(pub Event<R>);
+#[derive(Debug)]
+pub struct FromServer<R, I = ()>(pub Event<R>, Option<NetTick>, PhantomData<I>);
+
+impl<R, I> FromServer<R, I> {
+	/// An event for a message the server sent at `tick`.
+	pub fn stamped(event: Event<R>, tick: NetTick) -> Self {
+		Self(event, Some(tick), PhantomData)
+	}
 
-impl<R> From<Event<R>> for FromServer<R> {
+	/// The server's [NetTick] when it sent the message, if this is a message.
+	pub fn tick(&self) -> Option<NetTick> {
+		self.1
+	}
+}
+
+impl<R, I> std::ops::Deref for FromServer<R, I> {
+	type Target = Event<R>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl<R, I> std::ops::DerefMut for FromServer<R, I> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.0
+	}
+}
+
+impl<R, I> From<Event<R>> for FromServer<R, I> {
 	fn from(value: Event<R>) -> Self {
-		Self(value)
+		Self(value, None, PhantomData)
 	}
 }
 
-#[derive(Resource, Debug, Deref, DerefMut)]
-pub struct Client<S, R>(Option<ConnectionHandle<S, R>>)
+#[derive(Resource, Debug)]
+pub struct Client<S, R, I = ()>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static;
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	handle: Option<ConnectionHandle<S, R>>,
+	ctx: NetContext,
+	config: ConnectionConfig,
+	_i: PhantomData<I>,
+}
+
+impl<S, R, I> std::ops::Deref for Client<S, R, I>
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	type Target = Option<ConnectionHandle<S, R>>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.handle
+	}
+}
+
+impl<S, R, I> std::ops::DerefMut for Client<S, R, I>
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.handle
+	}
+}
 
-impl<S, R> Client<S, R>
+impl<S, R, I> Client<S, R, I>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
 {
+	pub fn new(handle: Option<ConnectionHandle<S, R>>, ctx: NetContext, config: ConnectionConfig) -> Self {
+		Self {
+			handle,
+			ctx,
+			config,
+			_i: PhantomData,
+		}
+	}
+
 	pub fn connect<A>(&mut self, addr: A, rt: Handle)
 	where
 		A: ToSocketAddrs + Send + 'static,
 	{
-		self.0 = Some(ConnectionHandle::connect(addr, rt));
+		self.handle = Some(ConnectionHandle::connect(addr, rt, self.ctx.clone(), self.config.clone()));
+	}
+	/// Flushes the connection once per frame in [FlushMode::Manual], the writer flushes by itself otherwise.
+	pub fn flush_system(client: Res<Client<S, R, I>>) {
+		if client.config.flush_mode != FlushMode::Manual {
+			return;
+		}
+		if let Some(client) = &**client {
+			// A disconnected connection reports that through `event_system` instead.
+			let _ = client.flush();
+		}
 	}
-	pub fn event_system(client: Res<Client<S, R>>, mut eventwriter: EventWriter<FromServer<R>>, mut first_msg: Local<bool>) {
+	pub fn event_system(client: Res<Client<S, R, I>>, mut eventwriter: EventWriter<FromServer<R, I>>, mut first_msg: Local<bool>) {
 		let Some(client) = &**client else {
 			return
 		};
@@ -68,12 +171,12 @@ where
 			eventwriter.send(Event::Connected(std::net::SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)), client.uuid).into())
 		}
 		loop {
-			let recv = client.try_recv();
+			let recv = client.try_recv_stamped();
 			if let Ok(opt) = recv {
-				let Some(val) = opt else {
+				let Some((val, tick)) = opt else {
 					break;
 				};
-				eventwriter.send(Event::Message(val, client.uuid).into());
+				eventwriter.send(FromServer::stamped(Event::Message(val, client.uuid), tick));
 			} else if let Err(err) = recv {
 				eventwriter.send(Event::Error(err, client.uuid).into());
 				break;
@@ -0,0 +1,292 @@
+//! Estimates the server's clock on the client, NTP-style.
+//!
+//! Every [ClockSyncConfig::interval] the client sends a [ClockRequest] stamped with its local time, which the
+//! server answers with its time when it received the request and when it responded. From those four
+//! timestamps the client derives a sample of the offset between both clocks, and of the round trip delay.
+//! [ServerClock] keeps the last [ClockSyncConfig::samples], trusts the one with the lowest delay the most,
+//! and fits the drift between both clocks through all of them.
+//!
+//! Server time is measured in seconds of [NetTick]s, so it converts directly to and from ticks.
+//! Both sides are assumed to use the same [crate::tick::NetTickConfig::tick_rate].
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	client::Client,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg,
+	},
+	server::Server,
+	tick::{NetTick, NetTickTimer},
+	NetStage, NetSystem,
+};
+
+/// Sent by the client, with its local time in seconds.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct ClockRequest {
+	pub client_time: f64,
+}
+
+/// The server's times in seconds when it received a [ClockRequest] and when it answered it.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct ClockResponse {
+	pub client_time: f64,
+	pub server_received: f64,
+	pub server_sent: f64,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct ClockSyncConfig {
+	/// How often the client refreshes its estimate.
+	pub interval: Duration,
+	/// Number of samples the estimate is based on.
+	pub samples: usize,
+}
+
+impl Default for ClockSyncConfig {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(1),
+			samples: 8,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockSample {
+	/// Local time at which the response was received.
+	local: f64,
+	/// Server time minus local time.
+	offset: f64,
+	/// Round trip time, without the time the server took to respond.
+	delay: f64,
+}
+
+/// The client's estimate of the server's clock.
+#[derive(Resource, Debug)]
+pub struct ServerClock {
+	/// Local times are measured in seconds since this instant.
+	epoch: Instant,
+	step: Duration,
+	samples: VecDeque<ClockSample>,
+	drift: f64,
+	last_request: Option<Instant>,
+}
+
+impl ServerClock {
+	pub fn new(step: Duration) -> Self {
+		Self {
+			epoch: Instant::now(),
+			step,
+			samples: VecDeque::new(),
+			drift: 0.0,
+			last_request: None,
+		}
+	}
+
+	/// Whether a response was received yet, without which there is no estimate.
+	pub fn is_synced(&self) -> bool {
+		!self.samples.is_empty()
+	}
+
+	/// The sample with the lowest delay, whose offset is the most accurate.
+	fn best(&self) -> Option<&ClockSample> {
+		self.samples.iter().min_by(|a, b| a.delay.total_cmp(&b.delay))
+	}
+
+	/// Server time minus local time, at `instant`.
+	pub fn offset(&self, instant: Instant) -> Option<f64> {
+		let best = self.best()?;
+		Some(best.offset + self.drift * (self.local(instant) - best.local))
+	}
+
+	/// How many seconds the server clock gains on the local clock per second.
+	pub fn drift(&self) -> f64 {
+		self.drift
+	}
+
+	/// The estimated offset is accurate to within plus or minus this duration.
+	///
+	/// That is half the round trip delay of the best sample, as the request and response may have taken
+	/// any share of it.
+	pub fn confidence(&self) -> Option<Duration> {
+		Some(Duration::from_secs_f64(self.best()?.delay / 2.0))
+	}
+
+	/// The server's time in seconds at the local `instant`.
+	pub fn server_time(&self, instant: Instant) -> Option<f64> {
+		Some(self.local(instant) + self.offset(instant)?)
+	}
+
+	/// The server's tick at the local `instant`, including the fraction of the current tick.
+	pub fn server_tick(&self, instant: Instant) -> Option<f64> {
+		Some(self.server_time(instant)? / self.step.as_secs_f64())
+	}
+
+	/// The [NetTick] the server is at, at the local `instant`.
+	pub fn tick_at(&self, instant: Instant) -> Option<NetTick> {
+		Some(NetTick(self.server_tick(instant)?.floor() as u32))
+	}
+
+	/// The [NetTick] the server is at now.
+	pub fn now(&self) -> Option<NetTick> {
+		self.tick_at(Instant::now())
+	}
+
+	/// The local instant at which the server reaches `tick`.
+	///
+	/// `None` without an estimate, or if that instant can't be represented.
+	pub fn instant_at(&self, tick: NetTick) -> Option<Instant> {
+		let best = self.best()?;
+		let server = tick.0 as f64 * self.step.as_secs_f64();
+		// Solves `server = local + best.offset + drift * (local - best.local)` for `local`.
+		let local = (server - best.offset + self.drift * best.local) / (1.0 + self.drift);
+		if local >= 0.0 {
+			Some(self.epoch + Duration::from_secs_f64(local))
+		} else {
+			// Before the epoch, which may not be representable on this platform.
+			self.epoch.checked_sub(Duration::from_secs_f64(-local))
+		}
+	}
+
+	fn local(&self, instant: Instant) -> f64 {
+		match instant.checked_duration_since(self.epoch) {
+			Some(since) => since.as_secs_f64(),
+			None => -self.epoch.duration_since(instant).as_secs_f64(),
+		}
+	}
+
+	fn add_sample(&mut self, response: &ClockResponse, received: Instant, max_samples: usize) {
+		let (t0, t1, t2, t3) = (
+			response.client_time,
+			response.server_received,
+			response.server_sent,
+			self.local(received),
+		);
+		self.samples.push_back(ClockSample {
+			local: t3,
+			offset: ((t1 - t0) + (t2 - t3)) / 2.0,
+			delay: ((t3 - t0) - (t2 - t1)).max(0.0),
+		});
+		while self.samples.len() > max_samples {
+			self.samples.pop_front();
+		}
+		self.drift = self.fit_drift();
+	}
+
+	/// Least squares slope of the offsets over local time.
+	fn fit_drift(&self) -> f64 {
+		let n = self.samples.len() as f64;
+		if n < 2.0 {
+			return 0.0;
+		}
+		let mean_local = self.samples.iter().map(|s| s.local).sum::<f64>() / n;
+		let mean_offset = self.samples.iter().map(|s| s.offset).sum::<f64>() / n;
+		let (mut cov, mut var) = (0.0, 0.0);
+		for sample in self.samples.iter() {
+			cov += (sample.local - mean_local) * (sample.offset - mean_offset);
+			var += (sample.local - mean_local).powi(2);
+		}
+		if var > 0.0 {
+			cov / var
+		} else {
+			0.0
+		}
+	}
+}
+
+fn server_time(tick: &NetTick, timer: &NetTickTimer) -> f64 {
+	(tick.0 as f64 + timer.overstep() as f64) * timer.step().as_secs_f64()
+}
+
+fn request_sync(
+	config: Res<ClockSyncConfig>,
+	mut clock: ResMut<ServerClock>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let Some(conn) = client.as_deref().and_then(|client| client.as_ref()) else {
+		return;
+	};
+	let now = Instant::now();
+	if matches!(clock.last_request, Some(last) if now - last < config.interval) {
+		return;
+	}
+	clock.last_request = Some(now);
+	let request = ClockRequest {
+		client_time: clock.local(now),
+	};
+	if let Err(err) = conn.send_blocking(NetMsg::new(request)) {
+		warn!("Unable to request the server's clock: {}", err);
+	}
+}
+
+fn receive_sync(
+	config: Res<ClockSyncConfig>,
+	mut clock: ResMut<ServerClock>,
+	mut responses: EventReader<Received<ClockResponse>>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let Some(server) = client.as_deref().and_then(|client| client.as_ref()).map(|conn| conn.uuid) else {
+		responses.clear();
+		return;
+	};
+	let now = Instant::now();
+	for Received { msg, .. } in responses.iter().filter(|received| received.from == server) {
+		clock.add_sample(msg, now, config.samples);
+	}
+}
+
+fn answer_sync(
+	mut requests: EventReader<Received<ClockRequest>>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	tick: Res<NetTick>,
+	timer: Res<NetTickTimer>,
+) {
+	let Some(server) = server else {
+		return;
+	};
+	for Received { msg, from, .. } in requests.iter() {
+		let Some(conn) = server.connections.get(from) else {
+			continue;
+		};
+		// Requests are answered in the frame they are dispatched in, so both times are the same.
+		let now = server_time(&tick, &timer);
+		let response = ClockResponse {
+			client_time: msg.client_time,
+			server_received: now,
+			server_sent: now,
+		};
+		if let Err(err) = conn.send_blocking(NetMsg::new(response)) {
+			warn!("Unable to answer a clock request from {}: {}", from, err);
+		}
+	}
+}
+
+/// Keeps a [ServerClock] on the client in sync with the server.
+///
+/// Add it after [crate::MultiplayerPlugin] on both sides, and insert a [ClockSyncConfig] first to change
+/// how often it is refreshed. The [crate::replication::ReplicationPlugin] adds it as well.
+#[derive(Debug, Default)]
+pub struct ClockSyncPlugin;
+
+impl Plugin for ClockSyncPlugin {
+	fn build(&self, app: &mut App) {
+		if app.world.contains_resource::<ServerClock>() {
+			return;
+		}
+		app.world.get_resource_or_insert_with(ClockSyncConfig::default);
+		let step = app.world.resource::<NetTickTimer>().step();
+		app.add_net_message::<ClockRequest>()
+			.add_net_message::<ClockResponse>()
+			.insert_resource(ServerClock::new(step))
+			.add_system_to_stage(NetStage::Receive, answer_sync.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Receive, receive_sync.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Send, request_sync);
+	}
+}
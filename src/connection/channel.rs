@@ -0,0 +1,203 @@
+//! Logical channels multiplexed over the stream of a connection.
+//!
+//! Every message is sent on one of the channels in [super::ConnectionConfig::channels], with
+//! [super::ConnectionHandle::send_on]. Messages on the same channel arrive in the order they were sent, but the
+//! writer interleaves the channels: messages are split into fragments of at most
+//! [super::ConnectionConfig::fragment_size] bytes, and each round every channel gets to write a share proportional
+//! to its [ChannelConfig::priority] (deficit round robin). A large download so doesn't hold up urgent messages on
+//! another channel, while still making progress itself.
+
+use std::collections::VecDeque;
+
+use crate::tick::NetTick;
+
+pub type ChannelId = u8;
+
+/// The channel [super::ConnectionHandle::send_blocking] sends on.
+pub const DEFAULT_CHANNEL: ChannelId = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelOrdering {
+	/// Every message is delivered, in order.
+	#[default]
+	Ordered,
+	/// Only the latest message matters: messages that haven't started being written are dropped when a newer one
+	/// is sent on the channel. Those that are delivered are still in order.
+	Sequenced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelConfig {
+	pub ordering: ChannelOrdering,
+	/// Relative share of the stream the channel gets while others are busy as well, at least 1.
+	pub priority: u8,
+}
+
+impl Default for ChannelConfig {
+	fn default() -> Self {
+		Self {
+			ordering: ChannelOrdering::default(),
+			priority: 1,
+		}
+	}
+}
+
+/// An encoded message waiting to be written.
+#[derive(Debug)]
+pub(crate) struct Queued {
+	/// Order in which messages were queued, over all channels.
+	seq: u64,
+	pub data: Vec<u8>,
+	pub flags: u8,
+	pub tick: NetTick,
+	/// Bytes compression kept from being sent.
+	pub saved: usize,
+	/// Bytes already written as fragments.
+	written: usize,
+}
+
+/// The part of a message to write next.
+#[derive(Debug)]
+pub(crate) struct Fragment {
+	pub channel: ChannelId,
+	pub data: Vec<u8>,
+	pub flags: u8,
+	pub tick: NetTick,
+	/// Set on the last fragment of a message, with the message it completes.
+	pub completes: Option<(usize, usize)>,
+}
+
+#[derive(Debug)]
+struct Channel {
+	config: ChannelConfig,
+	queue: VecDeque<Queued>,
+	/// Bytes the channel may still write this round.
+	deficit: usize,
+	/// Whether the channel got its share for this round yet.
+	credited: bool,
+}
+
+/// The per channel queues of a connection's writer.
+#[derive(Debug)]
+pub(crate) struct Channels {
+	channels: Vec<Channel>,
+	/// Channel indices by descending priority, the order they are visited in every round.
+	order: Vec<usize>,
+	/// Position in `order` in the current round.
+	cursor: usize,
+	fragment_size: usize,
+	next_seq: u64,
+}
+
+impl Channels {
+	pub fn new(configs: &[ChannelConfig], fragment_size: usize) -> Self {
+		let mut order: Vec<_> = (0..configs.len()).collect();
+		order.sort_by_key(|index| std::cmp::Reverse(configs[*index].priority));
+		Self {
+			channels: configs
+				.iter()
+				.map(|config| Channel {
+					config: *config,
+					queue: VecDeque::new(),
+					deficit: 0,
+					credited: false,
+				})
+				.collect(),
+			order,
+			cursor: 0,
+			fragment_size: fragment_size.max(1),
+			next_seq: 0,
+		}
+	}
+
+	/// Queues a message on `channel`, returning how many queued messages it replaced, see [ChannelOrdering::Sequenced].
+	///
+	/// Messages for unknown channels are dropped, and count as replaced.
+	pub fn push(&mut self, channel: ChannelId, data: Vec<u8>, flags: u8, tick: NetTick, saved: usize) -> usize {
+		let Some(channel) = self.channels.get_mut(channel as usize) else {
+			return 1;
+		};
+		let mut replaced = 0;
+		if channel.config.ordering == ChannelOrdering::Sequenced {
+			let before = channel.queue.len();
+			channel.queue.retain(|queued| queued.written > 0);
+			replaced = before - channel.queue.len();
+		}
+		channel.queue.push_back(Queued {
+			seq: self.next_seq,
+			data,
+			flags,
+			tick,
+			saved,
+			written: 0,
+		});
+		self.next_seq += 1;
+		replaced
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.channels.iter().all(|channel| channel.queue.is_empty())
+	}
+
+	/// Sequence number the next queued message will get.
+	pub fn next_seq(&self) -> u64 {
+		self.next_seq
+	}
+
+	/// Sequence number of the oldest message that hasn't been written completely.
+	pub fn oldest(&self) -> Option<u64> {
+		self.channels.iter().filter_map(|channel| channel.queue.front()).map(|queued| queued.seq).min()
+	}
+
+	/// The next fragment to write in the current round, or `None` once the round is over.
+	pub fn next_fragment(&mut self) -> Option<Fragment> {
+		while let Some(index) = self.order.get(self.cursor).copied() {
+			let channel = &mut self.channels[index];
+			let Some(front) = channel.queue.front_mut() else {
+				channel.deficit = 0;
+				self.cursor += 1;
+				continue;
+			};
+			if !channel.credited {
+				channel.deficit += self.fragment_size * channel.config.priority.max(1) as usize;
+				channel.credited = true;
+			}
+			let len = (front.data.len() - front.written).min(self.fragment_size);
+			if len > channel.deficit {
+				self.cursor += 1;
+				continue;
+			}
+			channel.deficit -= len;
+			let last = front.written + len == front.data.len();
+			if !last {
+				let data = front.data[front.written..front.written + len].to_vec();
+				front.written += len;
+				return Some(Fragment {
+					channel: index as ChannelId,
+					data,
+					flags: front.flags | crate::messaging::FLAG_FRAGMENT,
+					tick: front.tick,
+					completes: None,
+				});
+			}
+			let queued = channel.queue.pop_front().expect("The front message was just looked at.");
+			let size = queued.data.len();
+			let data = match queued.written {
+				0 => queued.data,
+				written => queued.data[written..].to_vec(),
+			};
+			return Some(Fragment {
+				channel: index as ChannelId,
+				data,
+				flags: queued.flags,
+				tick: queued.tick,
+				completes: Some((size, queued.saved)),
+			});
+		}
+		self.cursor = 0;
+		for channel in self.channels.iter_mut() {
+			channel.credited = false;
+		}
+		None
+	}
+}
@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::messaging::{self, NetContext};
+
+use super::{ConnectionConfig, ConnectionError};
+
+/// Exchanged by both sides before any messages, to agree on how messages are encoded.
+///
+/// Always encoded with postcard, as the codec isn't agreed on yet.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Handshake {
+	/// The sender's registered net message type paths, indexed by [crate::messaging::protocol::NetMessageId].
+	pub messages: Vec<String>,
+	/// Whether the sender wants to exchange compressed frames. Compression is only used if both sides do.
+	pub compression: bool,
+	/// The [crate::messaging::codec::Codec::name] of the sender's codec.
+	pub codec: String,
+}
+
+impl Handshake {
+	pub fn new(ctx: &NetContext, config: &ConnectionConfig) -> Self {
+		Self {
+			messages: ctx.messages.names(),
+			compression: config.compression.is_some(),
+			codec: config.codec.name().to_owned(),
+		}
+	}
+}
+
+/// Sends our [Handshake] and returns the peer's.
+pub(crate) async fn handshake<Rd, Wr>(
+	read: &mut Rd,
+	write: &mut Wr,
+	ctx: &NetContext,
+	config: &ConnectionConfig,
+) -> Result<Handshake, ConnectionError>
+where
+	Rd: AsyncRead + Unpin,
+	Wr: AsyncWrite + Unpin,
+{
+	let ours = postcard::to_stdvec(&Handshake::new(ctx, config))?;
+	messaging::send_msg(write, ours).await?;
+	let (_header, theirs) = messaging::recv_msg(read, &config.buffer_pool, config.max_message_size).await?;
+	let theirs: Handshake = postcard::from_bytes(&theirs)?;
+	if theirs.codec != config.codec.name() {
+		return Err(ConnectionError::CodecMismatch {
+			ours: config.codec.name().to_owned(),
+			theirs: theirs.codec,
+		});
+	}
+	Ok(theirs)
+}
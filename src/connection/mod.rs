@@ -1,11 +1,14 @@
-use std::sync::{
-	atomic::{AtomicBool, Ordering},
-	Arc,
+use std::{
+	collections::HashMap,
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc,
+	},
 };
 
 use async_channel::{unbounded, Receiver, RecvError, SendError, Sender};
-use bevy::utils::Uuid;
-use serde::{Deserialize, Serialize};
+use bevy::{prelude::Resource, utils::Uuid};
+use bytes::BytesMut;
 use thiserror::Error;
 use tokio::{
 	io::{self, AsyncWriteExt, BufReader, BufWriter},
@@ -17,44 +20,74 @@ use tokio::{
 	task::{JoinError, JoinHandle},
 };
 
-use crate::messaging;
+use crate::{
+	messaging::{
+		self,
+		buffer::BufferPool,
+		codec::{Codec, CodecError, PostcardCodec},
+		protocol::ProtocolError,
+		FrameHeader, NetContext, NetDeserialize, NetSerialize,
+	},
+	tick::{NetTick, SharedNetTick},
+};
 
+pub mod channel;
 pub mod ext;
+pub mod handshake;
+mod stats;
+
+pub use stats::ConnectionStats;
+
+use self::channel::{ChannelConfig, ChannelId, Channels, DEFAULT_CHANNEL};
 
 pub type ConnectionId = Uuid;
 
 #[derive(Debug)]
 pub struct ConnectionHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
 {
-	to_conn: Sender<S>,
-	from_conn: Receiver<R>,
+	to_conn: Sender<Outgoing<S>>,
+	from_conn: Receiver<Result<(R, NetTick), ConnectionError>>,
 	pub uuid: ConnectionId,
+	tick: SharedNetTick,
 	running: Arc<AtomicBool>,
+	stats: Arc<ConnectionStats>,
+	/// Messages sent that haven't been written to the stream yet.
+	pending: Arc<AtomicUsize>,
+	channels: usize,
 	runtime: Handle,
 	task: Option<JoinHandle<Result<(), ConnectionError>>>,
 }
 
 impl<S, R> ConnectionHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
 {
-	pub fn connect<A>(addr: A, rt: Handle) -> ConnectionHandle<S, R>
+	pub fn connect<A>(addr: A, rt: Handle, ctx: NetContext, config: ConnectionConfig) -> ConnectionHandle<S, R>
 	where
 		A: ToSocketAddrs + Send + 'static,
 	{
-		let (to_conn, from_handle) = unbounded::<S>();
-		let (to_handle, from_conn) = unbounded::<R>();
+		let (to_conn, from_handle) = unbounded::<Outgoing<S>>();
+		let (to_handle, from_conn) = unbounded::<Result<(R, NetTick), ConnectionError>>();
+		let tick = ctx.tick.clone();
 
 		let running = Arc::new(AtomicBool::new(true));
+		let stats = Arc::new(ConnectionStats::default());
+		let pending = Arc::new(AtomicUsize::new(0));
+		let channels = config.channels.len();
 
 		let connection = Connection {
 			to_handle,
 			from_handle,
 			running: running.clone(),
+			stats: stats.clone(),
+			pending: pending.clone(),
+			ctx,
+			config,
+			compression: None,
 		};
 
 		let task = Some(rt.spawn(connection.connect(addr)));
@@ -65,22 +98,35 @@ where
 			to_conn,
 			from_conn,
 			uuid,
+			tick,
 			running,
+			stats,
+			pending,
+			channels,
 			runtime: rt.clone(),
 			task,
 		}
 	}
 
-	pub fn with_stream(stream: TcpStream, rt: Handle) -> ConnectionHandle<S, R> {
-		let (to_conn, from_handle) = unbounded::<S>();
-		let (to_handle, from_conn) = unbounded::<R>();
+	pub fn with_stream(stream: TcpStream, rt: Handle, ctx: NetContext, config: ConnectionConfig) -> ConnectionHandle<S, R> {
+		let (to_conn, from_handle) = unbounded::<Outgoing<S>>();
+		let (to_handle, from_conn) = unbounded::<Result<(R, NetTick), ConnectionError>>();
+		let tick = ctx.tick.clone();
 
 		let running = Arc::new(AtomicBool::new(true));
+		let stats = Arc::new(ConnectionStats::default());
+		let pending = Arc::new(AtomicUsize::new(0));
+		let channels = config.channels.len();
 
 		let connection = Connection {
 			to_handle,
 			from_handle,
 			running: running.clone(),
+			stats: stats.clone(),
+			pending: pending.clone(),
+			ctx,
+			config,
+			compression: None,
 		};
 
 		let task = Some(rt.spawn(connection.run(stream)));
@@ -91,7 +137,11 @@ where
 			to_conn,
 			from_conn,
 			uuid,
+			tick,
 			running,
+			stats,
+			pending,
+			channels,
 			runtime: rt.clone(),
 			task,
 		}
@@ -118,18 +168,51 @@ where
 		self.internal_disconnect_blocking()
 	}
 
+	/// Sends `data` on the [DEFAULT_CHANNEL], stamped with the current [NetTick].
 	pub fn send_blocking(&self, data: S) -> Result<(), ConnectionError> {
-		self.to_conn.send_blocking(data)?;
+		self.send_on(DEFAULT_CHANNEL, data)
+	}
+
+	/// Sends `data` on `channel`, one of [ConnectionConfig::channels], stamped with the current [NetTick].
+	pub fn send_on(&self, channel: ChannelId, data: S) -> Result<(), ConnectionError> {
+		if channel as usize >= self.channels {
+			return Err(ConnectionError::UnknownChannel(channel));
+		}
+		self.pending.fetch_add(1, Ordering::Relaxed);
+		if let Err(err) = self.to_conn.send_blocking(Outgoing::Message(data, self.tick.get(), channel)) {
+			self.pending.fetch_sub(1, Ordering::Relaxed);
+			return Err(err.into());
+		}
 		Ok(())
 	}
 
+	/// Number of messages waiting to be written to the stream.
+	pub fn pending(&self) -> usize {
+		self.pending.load(Ordering::Relaxed)
+	}
+
+	/// Flushes everything sent so far, see [FlushMode::Manual].
+	pub fn flush(&self) -> Result<(), ConnectionError> {
+		self.to_conn.try_send(Outgoing::Flush).map_err(|_| ConnectionError::Disconnected)
+	}
+
+	pub fn stats(&self) -> &ConnectionStats {
+		&self.stats
+	}
+
 	pub fn is_running(&self) -> bool {
 		self.running.load(Ordering::Relaxed)
 	}
 
+	/// Non-fatal errors like [ProtocolError]s are returned in order with the messages around them.
 	pub fn try_recv(&self) -> Result<Option<R>, ConnectionError> {
+		Ok(self.try_recv_stamped()?.map(|(data, _)| data))
+	}
+
+	/// Like [ConnectionHandle::try_recv], but also returns the peer's [NetTick] when the message was sent.
+	pub fn try_recv_stamped(&self) -> Result<Option<(R, NetTick)>, ConnectionError> {
 		return match self.from_conn.try_recv() {
-			Ok(val) => Ok(Some(val)),
+			Ok(val) => val.map(Some),
 
 			Err(err) => match err {
 				async_channel::TryRecvError::Empty => Ok(None),
@@ -141,8 +224,8 @@ where
 
 impl<S, R> Drop for ConnectionHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
 {
 	fn drop(&mut self) {
 		if !self.running.load(Ordering::Relaxed) {
@@ -151,42 +234,118 @@ where
 	}
 }
 
+/// When the writer flushes the messages it has buffered to the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushMode {
+	/// Flush as soon as no more messages are waiting to be written.
+	#[default]
+	Immediate,
+	/// Only flush when [ConnectionHandle::flush] is called, e.g. once per frame in [crate::NetStage::Flush].
+	Manual,
+}
+
+/// Compresses frames with lz4 once their payload reaches `threshold` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+	pub threshold: usize,
+}
+
+impl Default for Compression {
+	fn default() -> Self {
+		Self { threshold: 1024 }
+	}
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct ConnectionConfig {
+	pub flush_mode: FlushMode,
+	/// Disabled if `None`, or if the peer doesn't enable it during the handshake.
+	pub compression: Option<Compression>,
+	/// Has to be the same on both sides, defaults to [PostcardCodec].
+	pub codec: Arc<dyn Codec>,
+	pub buffer_pool: BufferPool,
+	/// The channels messages can be sent on, indexed by [ChannelId]. Has to be the same on both sides,
+	/// defaults to a single ordered channel. Messages on other channels close the connection.
+	pub channels: Vec<ChannelConfig>,
+	/// Messages are written in fragments of at most this many bytes, see [channel].
+	pub fragment_size: usize,
+	/// Largest message the peer may send, in bytes after reassembling its fragments and after decompression.
+	/// Larger ones close the connection.
+	pub max_message_size: usize,
+}
+
+impl Default for ConnectionConfig {
+	fn default() -> Self {
+		Self {
+			flush_mode: FlushMode::default(),
+			compression: None,
+			codec: Arc::new(PostcardCodec),
+			buffer_pool: BufferPool::default(),
+			channels: vec![ChannelConfig::default()],
+			fragment_size: 16 * 1024,
+			max_message_size: 16 * 1024 * 1024,
+		}
+	}
+}
+
+#[derive(Debug)]
+enum Outgoing<S> {
+	Message(S, NetTick, ChannelId),
+	Flush,
+}
+
 struct Connection<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
 {
-	to_handle: Sender<R>,
-	from_handle: Receiver<S>,
+	to_handle: Sender<Result<(R, NetTick), ConnectionError>>,
+	from_handle: Receiver<Outgoing<S>>,
 	running: Arc<AtomicBool>,
+	stats: Arc<ConnectionStats>,
+	pending: Arc<AtomicUsize>,
+	ctx: NetContext,
+	config: ConnectionConfig,
+	/// The compression agreed on during the handshake.
+	compression: Option<Compression>,
 }
 
 impl<S, R> Connection<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + 'static,
 {
-	async fn run(self, stream: TcpStream) -> Result<(), ConnectionError> {
+	async fn run(mut self, stream: TcpStream) -> Result<(), ConnectionError> {
 		//Split the stream up to be able to split sending and receiving
 		let (read, write) = stream.into_split();
 
 		let mut read = BufReader::new(read);
 		let mut write = BufWriter::new(write);
 
+		let peer = match handshake::handshake(&mut read, &mut write, &self.ctx, &self.config).await {
+			Ok(peer) => peer,
+			Err(err) => {
+				self.fail(err)?;
+				return Err(ConnectionError::Disconnected);
+			}
+		};
+		self.ctx.peer_messages = Some(Arc::new(peer.messages));
+		self.compression = self.config.compression.filter(|_| peer.compression);
+
 		//Spawn listening and write tasks.
 		let output = tokio::select!(
 			result = async {
 				if let Err(err) = self.listen_to_stream(&mut read).await {
-					self.stop()? /*?*/; //TODO: is this correct?
-					Err(err)
+					self.fail(err)?;
+					Err(ConnectionError::Disconnected)
 				} else {
 					Ok(())
 				}
 			} => result,
 			result = async {
 				if let Err(err) = self.write_to_stream(&mut write).await {
-					self.stop()? /*?*/; //TODO: is this correct?
-					Err(err)
+					self.fail(err)?;
+					Err(ConnectionError::Disconnected)
 				} else {
 					Ok(())
 				}
@@ -214,31 +373,156 @@ where
 		&self,
 		write: &mut BufWriter<OwnedWriteHalf>,
 	) -> Result<(), ConnectionError> {
+		let mut channels = Channels::new(&self.config.channels, self.config.fragment_size);
+		// Flush once every message queued before this sequence number is written.
+		let mut flush_before = None;
+		// Whether anything was written since the last flush.
+		let mut unflushed = false;
 		while self.running.load(Ordering::Relaxed) {
-			let Ok(msg) = self.from_handle.recv().await else {
-				// If the channel returns an error and running is true, error.
-				if self.running.load(Ordering::Relaxed) {
-					return Err(ConnectionError::Disconnected)
+			if channels.is_empty() && flush_before.is_none() {
+				let Ok(outgoing) = self.from_handle.recv().await else {
+					// If the channel returns an error and running is true, error.
+					if self.running.load(Ordering::Relaxed) {
+						return Err(ConnectionError::Disconnected)
+					}
+					// Otherwise, the handler has signaled a disconnect.
+					break;
+				};
+				self.queue_outgoing(&mut channels, &mut flush_before, outgoing).await?;
+			}
+			// Queue everything that is already waiting, so it is part of the next round.
+			while let Ok(outgoing) = self.from_handle.try_recv() {
+				self.queue_outgoing(&mut channels, &mut flush_before, outgoing).await?;
+			}
+
+			let mut written = false;
+			while let Some(fragment) = channels.next_fragment() {
+				let header = FrameHeader {
+					flags: fragment.flags,
+					channel: fragment.channel,
+					tick: fragment.tick,
+				};
+				messaging::write_msg(write, &fragment.data, header).await?;
+				if let Some((size, saved)) = fragment.completes {
+					self.stats.record_sent(size, saved);
+					self.pending.fetch_sub(1, Ordering::Relaxed);
 				}
-				// Otherwise, the handler has signaled a disconnect.
-				break;
+				written = true;
+			}
+			unflushed |= written;
+
+			let flush = match flush_before {
+				Some(seq) => !matches!(channels.oldest(), Some(oldest) if oldest < seq),
+				None => false,
 			};
-			let bytes = postcard::to_stdvec(&msg)?;
-			messaging::send_msg(write, bytes).await?;
+			if flush {
+				flush_before = None;
+			}
+			if unflushed && (flush || self.config.flush_mode == FlushMode::Immediate) {
+				write.flush().await?;
+				self.stats.record_flush();
+				unflushed = false;
+			}
 		}
 
 		Ok(())
 	}
 
+	/// Encodes and queues a message, or notes a requested flush.
+	async fn queue_outgoing(
+		&self,
+		channels: &mut Channels,
+		flush_before: &mut Option<u64>,
+		outgoing: Outgoing<S>,
+	) -> Result<(), ConnectionError> {
+		let (msg, tick, channel) = match outgoing {
+			Outgoing::Message(msg, tick, channel) => (msg, tick, channel),
+			Outgoing::Flush => {
+				*flush_before = Some(channels.next_seq());
+				return Ok(());
+			}
+		};
+		let bytes = match messaging::encode(&msg, &self.ctx, &*self.config.codec) {
+			Ok(bytes) => bytes,
+			Err(ConnectionError::ProtocolError(err)) => {
+				self.pending.fetch_sub(1, Ordering::Relaxed);
+				self.report(err).await?;
+				return Ok(());
+			}
+			Err(err) => return Err(err),
+		};
+		let mut flags = 0;
+		let mut saved = 0;
+		let bytes = match self.compression {
+			Some(compression) if bytes.len() >= compression.threshold => {
+				let compressed = lz4_flex::compress_prepend_size(&bytes);
+				if compressed.len() < bytes.len() {
+					flags |= messaging::FLAG_COMPRESSED;
+					saved = bytes.len() - compressed.len();
+					compressed
+				} else {
+					bytes
+				}
+			}
+			_ => bytes,
+		};
+		let replaced = channels.push(channel, bytes, flags, tick, saved);
+		self.pending.fetch_sub(replaced, Ordering::Relaxed);
+		Ok(())
+	}
+
 	async fn listen_to_stream(
 		&self,
 		read: &mut BufReader<OwnedReadHalf>,
 	) -> Result<(), ConnectionError> {
+		// Fragments of the message being received on each channel.
+		let mut partial: HashMap<ChannelId, BytesMut> = HashMap::new();
 		while self.running.load(Ordering::Relaxed) {
-			match messaging::recv_msg(read).await {
-				Ok(bytes) => {
-					let data: R = postcard::from_bytes(&*bytes)?;
-					if let Err(_err) = self.to_handle.send(data).await {
+			match messaging::recv_msg(read, &self.config.buffer_pool, self.config.max_message_size).await {
+				Ok((header, mut frame)) => {
+					if header.channel as usize >= self.config.channels.len() {
+						return Err(ConnectionError::UnknownChannel(header.channel));
+					}
+					// Fragments are only limited one at a time by `recv_msg`.
+					let size = partial.get(&header.channel).map_or(0, |head| head.len()) + frame.len();
+					if size > self.config.max_message_size {
+						return Err(ConnectionError::MessageTooLarge {
+							size,
+							max: self.config.max_message_size,
+						});
+					}
+					if header.flags & messaging::FLAG_FRAGMENT != 0 {
+						match partial.get_mut(&header.channel) {
+							Some(head) => {
+								head.extend_from_slice(&frame);
+								self.config.buffer_pool.give(frame);
+							}
+							None => {
+								partial.insert(header.channel, frame);
+							}
+						}
+						continue;
+					}
+					if let Some(mut head) = partial.remove(&header.channel) {
+						head.extend_from_slice(&frame);
+						self.config.buffer_pool.give(frame);
+						frame = head;
+					}
+					self.stats.record_received(frame.len());
+					if header.flags & messaging::FLAG_COMPRESSED != 0 {
+						frame = self.decompress(frame)?;
+					}
+					let data = R::from_frame(&mut frame, &self.ctx, &self.config.codec);
+					self.config.buffer_pool.give(frame);
+					let data = match data {
+						Ok(data) => data,
+						Err(ConnectionError::ProtocolError(err)) => {
+							self.report(err).await?;
+							continue;
+						}
+						Err(err) => return Err(err),
+					};
+					if let Err(_err) = self.to_handle.send(Ok((data, header.tick))).await {
 						// If the channel returns an error and running is true, error.
 						if self.running.load(Ordering::Relaxed) {
 							return Err(ConnectionError::Disconnected);
@@ -248,14 +532,46 @@ where
 					}
 				}
 
-				//TODO: The connection should be shut down when an error is returned.
-				Err(error) => return Err(error.into()),
+				Err(error) => return Err(error),
 			}
 		}
 
 		Ok(())
 	}
 
+	fn decompress(&self, compressed: BytesMut) -> Result<BytesMut, ConnectionError> {
+		let (size, input) = lz4_flex::block::uncompressed_size(&compressed)?;
+		// The size is up to the peer, so check it before allocating.
+		if size > self.config.max_message_size {
+			return Err(ConnectionError::MessageTooLarge {
+				size,
+				max: self.config.max_message_size,
+			});
+		}
+		let mut frame = self.config.buffer_pool.take(size);
+		frame.resize(size, 0);
+		lz4_flex::decompress_into(input, &mut frame)?;
+		self.config.buffer_pool.give(compressed);
+		Ok(frame)
+	}
+
+	/// Passes a non-fatal error on to the handle.
+	async fn report(&self, err: ProtocolError) -> Result<(), ConnectionError> {
+		if self.to_handle.send(Err(err.into())).await.is_err() && self.running.load(Ordering::Relaxed) {
+			return Err(ConnectionError::Disconnected);
+		}
+		Ok(())
+	}
+
+	/// Passes a fatal error on to the handle, before its channel is closed by [Self::stop].
+	fn fail(&self, err: ConnectionError) -> Result<(), ConnectionError> {
+		// The handle reports a closed channel as disconnected already.
+		if !matches!(err, ConnectionError::Disconnected) {
+			let _ = self.to_handle.try_send(Err(err));
+		}
+		self.stop()
+	}
+
 	fn stop(&self) -> Result<(), ConnectionError> {
 		self.running.store(false, Ordering::Relaxed);
 		if !(self.to_handle.close() & self.from_handle.close()) {
@@ -272,9 +588,34 @@ pub enum ConnectionError {
 	#[error("Not connected, or unexpected disconnect.")]
 	Disconnected,
 	#[error("Unable to serialize message.")]
-	SerializationError(#[from] postcard::Error),
+	SerializationError(CodecError),
 	#[error("The underlying task returned an error on join.")]
 	JoinError(#[from] JoinError),
+	#[error("Protocol error: {0}")]
+	ProtocolError(#[from] ProtocolError),
+	#[error("Unable to decompress message.")]
+	DecompressionError(#[from] lz4_flex::block::DecompressError),
+	#[error("The peer sent a message of {size} bytes, more than the maximum of {max}.")]
+	MessageTooLarge { size: usize, max: usize },
+	#[error("Codec mismatch: using `{ours}`, but the peer uses `{theirs}`.")]
+	CodecMismatch { ours: String, theirs: String },
+	#[error("No channel {0} is configured.")]
+	UnknownChannel(ChannelId),
+}
+
+impl From<CodecError> for ConnectionError {
+	fn from(err: CodecError) -> Self {
+		match err {
+			CodecError::Protocol(err) => Self::ProtocolError(err),
+			err => Self::SerializationError(err),
+		}
+	}
+}
+
+impl From<postcard::Error> for ConnectionError {
+	fn from(err: postcard::Error) -> Self {
+		Self::SerializationError(CodecError::new(err))
+	}
 }
 
 impl From<RecvError> for ConnectionError {
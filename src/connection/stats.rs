@@ -0,0 +1,57 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Counters of a single connection, updated by its task and readable through [super::ConnectionHandle::stats].
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+	messages_sent: AtomicU64,
+	messages_received: AtomicU64,
+	bytes_sent: AtomicU64,
+	bytes_received: AtomicU64,
+	bytes_saved: AtomicU64,
+	flushes: AtomicU64,
+}
+
+impl ConnectionStats {
+	pub fn messages_sent(&self) -> u64 {
+		self.messages_sent.load(Ordering::Relaxed)
+	}
+
+	pub fn messages_received(&self) -> u64 {
+		self.messages_received.load(Ordering::Relaxed)
+	}
+
+	/// Payload bytes written to the stream, after compression.
+	pub fn bytes_sent(&self) -> u64 {
+		self.bytes_sent.load(Ordering::Relaxed)
+	}
+
+	/// Payload bytes read from the stream, before decompression.
+	pub fn bytes_received(&self) -> u64 {
+		self.bytes_received.load(Ordering::Relaxed)
+	}
+
+	/// Bytes that compression kept from being sent.
+	pub fn bytes_saved(&self) -> u64 {
+		self.bytes_saved.load(Ordering::Relaxed)
+	}
+
+	/// Times written messages were flushed to the stream, which is less than [Self::messages_sent] when batching.
+	pub fn flushes(&self) -> u64 {
+		self.flushes.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn record_sent(&self, bytes: usize, saved: usize) {
+		self.messages_sent.fetch_add(1, Ordering::Relaxed);
+		self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+		self.bytes_saved.fetch_add(saved as u64, Ordering::Relaxed);
+	}
+
+	pub(crate) fn record_flush(&self) {
+		self.flushes.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn record_received(&self, bytes: usize) {
+		self.messages_received.fetch_add(1, Ordering::Relaxed);
+		self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+}
@@ -1,66 +1,358 @@
-use bevy::{prelude::*, utils::Uuid};
+use std::{
+	collections::VecDeque,
+	ops::Range,
+	sync::{Mutex, MutexGuard},
+};
+
+use bevy::prelude::*;
 use dashmap::DashMap;
-use once_cell::sync::OnceCell;
-use rand::Rng;
+use client::Client;
+use messaging::{
+	protocol::{NetAppExt, NetMessageRegistry, Received},
+	NetEntityId, NetMsg,
+};
+use server::Server;
 use tokio::runtime::Runtime;
 
 pub mod client;
+pub mod clock;
 pub mod connection;
 pub mod messaging;
+pub mod replication;
+pub mod rpc;
 pub mod server;
+pub mod tick;
+pub mod transfer;
 
 pub struct MultiplayerPlugin;
 
 impl Plugin for MultiplayerPlugin {
 	fn build(&self, app: &mut App) {
-		app.add_startup_system(register_type_data)
-		.add_stage_before(
-			CoreStage::Update,
-			NetStage::Receive,
-			SystemStage::parallel(),
-		)
-		.add_stage_after(CoreStage::Update, NetStage::Send, SystemStage::parallel())
-		.insert_resource(NetEntityRegistry::default());
-	}
-}
-
-pub static TYPE_REGISTRY: OnceCell<AppTypeRegistry> = OnceCell::new();
-
-fn register_type_data(type_registry: Res<AppTypeRegistry>) {
-	if let Err(_err) = TYPE_REGISTRY.set(type_registry.clone()) {
-		panic!("This system should be the only system to set the type registry.");
+		let tick_config = tick::build(app);
+		let stage = || {
+			if tick_config.run_on_tick {
+				SystemStage::parallel().with_run_criteria(tick::on_net_tick)
+			} else {
+				SystemStage::parallel()
+			}
+		};
+		app.add_stage_before(CoreStage::Update, NetStage::Receive, stage())
+			.add_stage_after(CoreStage::Update, NetStage::Send, stage())
+			.add_stage_after(NetStage::Send, NetStage::Flush, stage())
+			.insert_resource(NetEntityRegistry::default())
+			.init_resource::<NetEntityConfig>()
+			.init_resource::<NetMessageRegistry>()
+			.register_type::<NetEntityId>()
+			.add_net_message::<NetEntityDespawned>()
+			.add_system_to_stage(NetStage::Receive, receive_despawns.after(NetSystem::Dispatch))
+			.add_system_to_stage(CoreStage::PostUpdate, register_net_entities.before(deregister_net_entities))
+			.add_system_to_stage(CoreStage::PostUpdate, deregister_net_entities);
 	}
 }
 
+/// Stages that run every frame, or once per network tick if [tick::NetTickConfig::run_on_tick] is set.
 #[derive(StageLabel)]
 pub enum NetStage {
 	Receive,
 	Send,
+	/// Flushes connections configured with [connection::FlushMode::Manual] after everything in [NetStage::Send].
+	Flush,
+}
+
+#[derive(SystemLabel)]
+pub enum NetSystem {
+	/// Systems that turn incoming connection data into events.
+	Receive,
+	/// Systems that route received [messaging::NetMsg]s into typed events.
+	Dispatch,
+	/// Advances the [tick::NetTick] in [CoreStage::PreUpdate].
+	Tick,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Slot {
+	Used(u32),
+	Free(u32),
+	/// Part of a range reserved for a client.
+	Reserved,
 }
 
-pub type EntityUuid = Uuid;
+/// Hands out [NetEntityId]s.
+///
+/// The server allocates the ids of the entities it replicates, and reserves ranges of indices for its clients.
+/// Clients allocate from their range for entities they spawn before the server does, like predicted projectiles.
+#[derive(Debug, Default)]
+pub struct NetEntityAllocator {
+	/// The state of every index allocated or reserved here.
+	slots: Vec<Slot>,
+	/// Freed indices, reused oldest first.
+	free: VecDeque<u32>,
+	/// What is left of the ranges the server reserved for us, used in order.
+	reserved: VecDeque<Range<u32>>,
+}
+
+impl NetEntityAllocator {
+	pub fn allocate(&mut self) -> NetEntityId {
+		if let Some(index) = self.free.pop_front() {
+			if let Slot::Free(generation) = self.slots[index as usize] {
+				self.slots[index as usize] = Slot::Used(generation);
+				return NetEntityId { index, generation };
+			}
+		}
+		let index = self.slots.len() as u32;
+		self.slots.push(Slot::Used(0));
+		NetEntityId { index, generation: 0 }
+	}
+
+	/// Frees an id returned by [NetEntityAllocator::allocate], so its index can be reused with the next generation.
+	///
+	/// Returns whether `id` was in use.
+	pub fn free(&mut self, id: NetEntityId) -> bool {
+		match self.slots.get(id.index as usize) {
+			Some(Slot::Used(generation)) if *generation == id.generation => {
+				self.slots[id.index as usize] = Slot::Free(id.generation.wrapping_add(1));
+				self.free.push_back(id.index);
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Reserves `count` indices for a client, which are never allocated here until they are released.
+	pub fn reserve(&mut self, count: u32) -> Range<u32> {
+		let start = self.slots.len() as u32;
+		self.slots.extend((0..count).map(|_| Slot::Reserved));
+		start..start + count
+	}
+
+	/// Takes over an id a client allocated from its reserved range, so it is freed like the ids allocated here.
+	///
+	/// Returns whether `id` was still reserved.
+	pub fn claim(&mut self, id: NetEntityId) -> bool {
+		match self.slots.get(id.index as usize) {
+			Some(Slot::Reserved) if id.generation == 0 => {
+				self.slots[id.index as usize] = Slot::Used(0);
+				true
+			}
+			_ => false,
+		}
+	}
+
+	/// Makes the indices in `range` that weren't claimed available for allocation again.
+	pub fn release(&mut self, range: Range<u32>) {
+		for index in range {
+			if let Some(slot @ Slot::Reserved) = self.slots.get_mut(index as usize) {
+				*slot = Slot::Free(0);
+				self.free.push_back(index);
+			}
+		}
+	}
+
+	/// Adds a range the server reserved for us, which is allocated from after the ranges added before it.
+	pub fn add_reserved(&mut self, range: Range<u32>) {
+		self.reserved.push_back(range);
+	}
 
+	/// Allocates an id from the ranges the server reserved for us, if there is any left.
+	pub fn allocate_reserved(&mut self) -> Option<NetEntityId> {
+		loop {
+			let range = self.reserved.front_mut()?;
+			match range.next() {
+				Some(index) => return Some(NetEntityId { index, generation: 0 }),
+				None => {
+					self.reserved.pop_front();
+				}
+			}
+		}
+	}
+}
+
+/// Maps the [NetEntityId]s of networked entities to the local entities, and back.
+///
+/// Entities that have their [NetEntityId] component removed, or are despawned, are removed from the registry
+/// in [CoreStage::PostUpdate], and entities that are given a [NetEntityId] component are added to it.
 #[derive(Resource, Default)]
 pub struct NetEntityRegistry {
-	map: DashMap<EntityUuid, Entity>,
+	map: DashMap<NetEntityId, Entity>,
+	entities: DashMap<Entity, NetEntityId>,
+	allocator: Mutex<NetEntityAllocator>,
 }
 
 impl NetEntityRegistry {
-	pub fn register(&self, entity: Entity) -> EntityUuid {
-		let rand_uuid = Uuid::from_bytes(rand::thread_rng().gen::<_>());
-		self.map.insert(rand_uuid, entity).expect("uuid clash");
-		rand_uuid
+	/// Allocates a new id for `entity`.
+	pub fn register(&self, entity: Entity) -> NetEntityId {
+		let id = self.allocator().allocate();
+		self.link(id, entity);
+		id
+	}
+
+	/// Allocates an id for `entity` from the range the server reserved for us, see [NetEntityAllocator].
+	pub fn register_reserved(&self, entity: Entity) -> Option<NetEntityId> {
+		let id = self.allocator().allocate_reserved()?;
+		self.link(id, entity);
+		Some(id)
+	}
+
+	/// Reserves `count` indices for a client to allocate from.
+	pub fn reserve(&self, count: u32) -> Range<u32> {
+		self.allocator().reserve(count)
+	}
+
+	/// Maps an id a client allocated from its reserved range to `entity`, see [NetEntityAllocator::claim].
+	///
+	/// Returns whether `id` was still reserved, `id` isn't mapped otherwise.
+	pub fn claim(&self, id: NetEntityId, entity: Entity) -> bool {
+		if !self.allocator().claim(id) {
+			return false;
+		}
+		self.link(id, entity);
+		true
 	}
 
-	pub fn deregister(&self, uuid: EntityUuid) -> Option<(EntityUuid, Entity)> {
-		self.map.remove(&uuid)
+	/// Makes the unclaimed indices of a range reserved for a client available again, once it left.
+	pub fn release(&self, range: Range<u32>) {
+		self.allocator().release(range);
 	}
 
-	pub fn get(&self, uuid: EntityUuid) -> Option<Entity> {
-		if let Some(val) = self.map.get(&uuid) {
-			Some(*val.value())
-		} else {
-			None
+	/// Adds a range the server reserved for us.
+	pub fn add_reserved(&self, range: Range<u32>) {
+		self.allocator().add_reserved(range);
+	}
+
+	/// Maps an id chosen by a peer to a local entity, returning the entity it was mapped to before.
+	pub fn insert(&self, id: NetEntityId, entity: Entity) -> Option<Entity> {
+		self.link(id, entity)
+	}
+
+	/// Removes the mapping of `id`, freeing it if it was allocated here.
+	pub fn deregister(&self, id: NetEntityId) -> Option<(NetEntityId, Entity)> {
+		let removed = self.map.remove(&id)?;
+		self.entities.remove_if(&removed.1, |_, linked| *linked == id);
+		self.allocator().free(id);
+		Some(removed)
+	}
+
+	/// Removes the mapping of `entity`, freeing its id if it was allocated here.
+	pub fn deregister_entity(&self, entity: Entity) -> Option<NetEntityId> {
+		let id = *self.entities.get(&entity)?;
+		self.deregister(id).map(|(id, _)| id)
+	}
+
+	pub fn get(&self, id: NetEntityId) -> Option<Entity> {
+		self.map.get(&id).map(|entity| *entity.value())
+	}
+
+	/// The id `entity` is registered with.
+	pub fn get_id(&self, entity: Entity) -> Option<NetEntityId> {
+		self.entities.get(&entity).map(|id| *id.value())
+	}
+
+	pub fn len(&self) -> usize {
+		self.map.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.map.is_empty()
+	}
+
+	/// Maps `id` and `entity` to each other, unlinking whatever either of them was mapped to before.
+	fn link(&self, id: NetEntityId, entity: Entity) -> Option<Entity> {
+		let old_entity = self.map.insert(id, entity);
+		if let Some(old_entity) = old_entity.filter(|old_entity| *old_entity != entity) {
+			self.entities.remove_if(&old_entity, |_, linked| *linked == id);
+		}
+		if let Some(old_id) = self.entities.insert(entity, id).filter(|old_id| *old_id != id) {
+			if self.map.remove_if(&old_id, |_, linked| *linked == entity).is_some() {
+				self.allocator().free(old_id);
+			}
+		}
+		old_entity
+	}
+
+	fn allocator(&self) -> MutexGuard<'_, NetEntityAllocator> {
+		self.allocator.lock().unwrap()
+	}
+}
+
+#[derive(Resource, Debug, Clone, Default)]
+pub struct NetEntityConfig {
+	/// Whether to send a [NetEntityDespawned] to our peers when a networked entity is despawned here.
+	///
+	/// Clients despawn their copy of an entity the server despawned. The server only reports them through
+	/// [messaging::protocol::Received<NetEntityDespawned>] events, as it decides what exists itself.
+	pub notify_despawns: bool,
+}
+
+/// Sent when a networked entity was despawned, see [NetEntityConfig::notify_despawns].
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct NetEntityDespawned {
+	pub net_id: NetEntityId,
+}
+
+/// Registers entities that were given a [NetEntityId] component outside of the registry.
+fn register_net_entities(
+	query: Query<(Entity, &NetEntityId), Changed<NetEntityId>>,
+	registry: Res<NetEntityRegistry>,
+) {
+	for (entity, net_id) in query.iter() {
+		if registry.get(*net_id) != Some(entity) {
+			registry.insert(*net_id, entity);
+		}
+	}
+}
+
+/// Deregisters entities that lost their [NetEntityId] component, or were despawned.
+fn deregister_net_entities(
+	removed: RemovedComponents<NetEntityId>,
+	query: Query<(), With<NetEntityId>>,
+	registry: Res<NetEntityRegistry>,
+	config: Res<NetEntityConfig>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	for entity in removed.iter() {
+		if query.contains(entity) {
+			// Given a new id in the same frame.
+			continue;
+		}
+		let Some(net_id) = registry.deregister_entity(entity) else {
+			continue;
+		};
+		if !config.notify_despawns {
+			continue;
+		}
+		if let Some(server) = server.as_deref() {
+			for conn in server.connections.iter() {
+				if let Err(err) = conn.send_blocking(NetMsg::new(NetEntityDespawned { net_id })) {
+					warn!("Unable to report the despawn of {} to {}: {}", net_id, conn.key(), err);
+				}
+			}
+		}
+		if let Some(conn) = client.as_deref().and_then(|client| client.as_ref()) {
+			if let Err(err) = conn.send_blocking(NetMsg::new(NetEntityDespawned { net_id })) {
+				warn!("Unable to report the despawn of {}: {}", net_id, err);
+			}
+		}
+	}
+}
+
+/// Despawns the entities the server reported despawned.
+fn receive_despawns(
+	mut commands: Commands,
+	mut despawned: EventReader<Received<NetEntityDespawned>>,
+	registry: Res<NetEntityRegistry>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	// Only the server a client is connected to decides what it despawns.
+	let Some(server) = client.as_deref().and_then(|client| client.as_ref()).map(|conn| conn.uuid) else {
+		despawned.clear();
+		return;
+	};
+	for Received { msg, .. } in despawned.iter().filter(|received| received.from == server) {
+		if let Some((_, entity)) = registry.deregister(msg.net_id) {
+			if let Some(mut entity) = commands.get_entity(entity) {
+				entity.despawn();
+			}
 		}
 	}
 }
@@ -0,0 +1,119 @@
+use std::{
+	marker::PhantomData,
+	sync::{Arc, Mutex},
+};
+
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+
+use crate::connection::ConnectionError;
+
+use super::{
+	codec::{Codec, CodecError},
+	NetContext, NetDeserialize,
+};
+
+/// Receive buffers that are reused across frames instead of allocating one per frame.
+///
+/// Cloning shares the pool, so all connections made with the same [crate::connection::ConnectionConfig] share it.
+#[derive(Clone, Debug)]
+pub struct BufferPool {
+	buffers: Arc<Mutex<Vec<BytesMut>>>,
+	max_buffers: usize,
+	max_capacity: usize,
+}
+
+impl BufferPool {
+	/// A pool keeping at most `max_buffers` buffers, of at most `max_capacity` bytes each.
+	pub fn new(max_buffers: usize, max_capacity: usize) -> Self {
+		Self {
+			buffers: Arc::new(Mutex::new(Vec::new())),
+			max_buffers,
+			max_capacity,
+		}
+	}
+
+	/// An empty buffer with room for at least `capacity` bytes.
+	pub fn take(&self, capacity: usize) -> BytesMut {
+		let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+		buf.reserve(capacity);
+		buf
+	}
+
+	/// Returns a buffer to the pool, unless it has no capacity left to reuse.
+	///
+	/// Buffers that grew above the pool's maximum capacity for one large frame are dropped instead of keeping that memory around.
+	pub fn give(&self, mut buf: BytesMut) {
+		if buf.capacity() == 0 || buf.capacity() > self.max_capacity {
+			return;
+		}
+		buf.clear();
+		let mut buffers = self.buffers.lock().unwrap();
+		if buffers.len() < self.max_buffers {
+			buffers.push(buf);
+		}
+	}
+}
+
+impl Default for BufferPool {
+	fn default() -> Self {
+		Self::new(16, 1024 * 1024)
+	}
+}
+
+/// A message type that deserializes by borrowing from the frame it was received in, see [Frame].
+pub trait BorrowedMsg: Send + Sync + 'static {
+	type Msg<'a>: Deserialize<'a>;
+}
+
+/// A received frame that is deserialized on demand, borrowing from the frame's buffer instead of copying out of it.
+///
+/// Use as the `R` of a connection to receive [BorrowedMsg]s.
+pub struct Frame<T: BorrowedMsg> {
+	bytes: Bytes,
+	codec: Arc<dyn Codec>,
+	_m: PhantomData<T>,
+}
+
+impl<T: BorrowedMsg> Frame<T> {
+	pub fn bytes(&self) -> &Bytes {
+		&self.bytes
+	}
+
+	pub fn get(&self) -> Result<T::Msg<'_>, ConnectionError> {
+		let mut value = None;
+		self.codec.decode(&self.bytes, &mut |de| {
+			value = Some(erased_serde::deserialize(de)?);
+			Ok(())
+		})?;
+		Ok(value.ok_or(CodecError::NotVisited(self.codec.name()))?)
+	}
+}
+
+impl<T: BorrowedMsg> std::fmt::Debug for Frame<T> {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("Frame").field("bytes", &self.bytes).finish()
+	}
+}
+
+impl<T: BorrowedMsg> NetDeserialize for Frame<T> {
+	/// A [Frame] has to be the whole message, it can't be part of one.
+	fn net_deserialize<'de, D>(_deserializer: D, _ctx: &NetContext) -> Result<Self, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		Err(serde::de::Error::custom("a `Frame` can only be received as a whole message"))
+	}
+
+	fn from_frame(
+		frame: &mut BytesMut,
+		_ctx: &NetContext,
+		codec: &Arc<dyn Codec>,
+	) -> Result<Self, ConnectionError> {
+		Ok(Self {
+			bytes: frame.split().freeze(),
+			codec: codec.clone(),
+			_m: PhantomData,
+		})
+	}
+}
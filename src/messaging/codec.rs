@@ -0,0 +1,124 @@
+use std::fmt::Debug;
+
+use thiserror::Error;
+
+use super::protocol::ProtocolError;
+
+/// Called by [Codec::decode] with a deserializer over the frame's bytes.
+pub type DecodeFn<'a, 'de> =
+	&'a mut dyn FnMut(&mut dyn erased_serde::Deserializer<'de>) -> Result<(), erased_serde::Error>;
+
+/// The serialization format of the frames on a connection.
+///
+/// Both sides have to use the same codec, which is checked during the [crate::connection::handshake].
+pub trait Codec: Debug + Send + Sync + 'static {
+	/// Identifies the format to the peer.
+	fn name(&self) -> &'static str;
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError>;
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError>;
+}
+
+#[derive(Error, Debug)]
+pub enum CodecError {
+	/// The message itself is fine, but doesn't match the registered net messages.
+	#[error(transparent)]
+	Protocol(#[from] ProtocolError),
+	/// The format's own error.
+	#[error(transparent)]
+	Format(Box<dyn std::error::Error + Send + Sync>),
+	/// [Codec::decode] returned without passing a deserializer to `visit`.
+	#[error("The `{0}` codec never visited the frame it decoded.")]
+	NotVisited(&'static str),
+}
+
+impl CodecError {
+	pub fn new<E: std::error::Error + Send + Sync + 'static>(err: E) -> Self {
+		Self::Format(Box::new(err))
+	}
+}
+
+/// Compact binary format, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PostcardCodec;
+
+impl Codec for PostcardCodec {
+	fn name(&self) -> &'static str {
+		"postcard"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		postcard::to_stdvec(value).map_err(CodecError::new)
+	}
+
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		let mut de = postcard::Deserializer::from_bytes(bytes);
+		visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(CodecError::new)
+	}
+}
+
+#[cfg(feature = "bincode")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+#[cfg(feature = "bincode")]
+impl Codec for BincodeCodec {
+	fn name(&self) -> &'static str {
+		"bincode"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		bincode::serialize(value).map_err(CodecError::new)
+	}
+
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		use bincode::Options;
+		// Matches the options of `bincode::serialize`.
+		let options = bincode::options()
+			.with_fixint_encoding()
+			.allow_trailing_bytes();
+		let mut de = bincode::Deserializer::from_slice(bytes, options);
+		visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(CodecError::new)
+	}
+}
+
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+	fn name(&self) -> &'static str {
+		"msgpack"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		rmp_serde::to_vec(value).map_err(CodecError::new)
+	}
+
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		let mut de = rmp_serde::Deserializer::from_read_ref(bytes);
+		visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(CodecError::new)
+	}
+}
+
+/// Human-readable format to inspect traffic with during development.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "json")]
+impl Codec for JsonCodec {
+	fn name(&self) -> &'static str {
+		"json"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		serde_json::to_vec(value).map_err(CodecError::new)
+	}
+
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		let mut de = serde_json::Deserializer::from_slice(bytes);
+		visit(&mut <dyn erased_serde::Deserializer>::erase(&mut de)).map_err(CodecError::new)?;
+		de.end().map_err(CodecError::new)
+	}
+}
@@ -2,12 +2,12 @@ use std::marker::PhantomData;
 
 use bevy::prelude::*;
 
-use super::{CastNetMsg, NetUuid, NetMsg};
+use super::{CastNetMsg, NetEntityId};
 
 #[derive(Reflect)]
 pub struct SynchronizeEvent<T: Component + CastNetMsg> {
 	msg: T::Target,
-	uuid: NetUuid,
+	net_id: NetEntityId,
 }
 
 // impl<T: Component + CastNetMsg> NetMsg for SynchronizeEvent<T> {}
@@ -45,7 +45,7 @@ impl<T: Component + CastNetMsg> Synchronize<T> {
 // 	mut event_reader: EventReader<SynchronizeEvent<T>>,
 // ) {
 // 	for event in event_reader.iter() {
-// 		let entity = local_entities.get(event.uuid.into()).expect("Entity should be registered before synchronizing.");
+// 		let entity = local_entities.get(event.net_id).expect("Entity should be registered before synchronizing.");
 // 		let mut t = query.get_mut(entity).unwrap();
 // 		t.set_with_net_msg(event.msg.clone());
 // 		// sync.changed_only_by_recv = true;
@@ -0,0 +1,181 @@
+//! Bevy events forwarded between peers.
+//!
+//! Register an event with [NetEventAppExt::add_network_event] on both sides. Events written with an
+//! [EventWriter] on the sending side are sent in [NetStage::Send], and come out of [EventReader]s on the
+//! receiving side after [NetSystem::Dispatch]. Which connection sent them is available through
+//! [Received] events of the same type.
+//!
+//! The direction is decided per connection, so an app that is a server and a client of another server at
+//! the same time forwards events both ways.
+
+use std::marker::PhantomData;
+
+use bevy::{
+	ecs::event::Event,
+	prelude::*,
+	reflect::{FromReflect, GetTypeRegistration},
+	utils::HashSet,
+};
+
+use crate::{
+	client::Client,
+	connection::ConnectionHandle,
+	server::Server,
+	NetStage, NetSystem,
+};
+
+use super::{
+	protocol::{NetAppExt, Received},
+	NetMsg,
+};
+
+/// Which way an event is forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetEventDirection {
+	/// From the server to all clients.
+	ServerToClient,
+	/// From a client to the server.
+	ClientToServer,
+	Both,
+}
+
+impl NetEventDirection {
+	fn to_clients(self) -> bool {
+		matches!(self, Self::ServerToClient | Self::Both)
+	}
+
+	fn to_server(self) -> bool {
+		matches!(self, Self::ClientToServer | Self::Both)
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+	/// Every event is sent, in order.
+	Reliable,
+	/// Events are dropped instead of queued while more than [NetEventConfig::max_pending] messages wait to be
+	/// written to the connection. The events that are sent still arrive in order.
+	Unreliable,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct NetEventConfig {
+	/// Backlog of a connection above which [Reliability::Unreliable] events are dropped.
+	pub max_pending: usize,
+}
+
+impl Default for NetEventConfig {
+	fn default() -> Self {
+		Self { max_pending: 64 }
+	}
+}
+
+#[derive(Resource)]
+struct NetEventState<E> {
+	direction: NetEventDirection,
+	reliability: Reliability,
+	/// Ids of the events that were received this frame, which aren't sent back.
+	received: HashSet<usize>,
+	_e: PhantomData<E>,
+}
+
+pub trait NetEventAppExt {
+	/// Forwards event `E` to peers in `direction`.
+	///
+	/// Has to be called on both sides with the same arguments.
+	fn add_network_event<E>(&mut self, direction: NetEventDirection, reliability: Reliability) -> &mut Self
+	where
+		E: Event + Clone + Reflect + FromReflect + GetTypeRegistration;
+}
+
+impl NetEventAppExt for App {
+	fn add_network_event<E>(&mut self, direction: NetEventDirection, reliability: Reliability) -> &mut Self
+	where
+		E: Event + Clone + Reflect + FromReflect + GetTypeRegistration,
+	{
+		self.init_resource::<NetEventConfig>()
+			.insert_resource(NetEventState::<E> {
+				direction,
+				reliability,
+				received: HashSet::default(),
+				_e: PhantomData,
+			})
+			.add_event::<E>()
+			.add_net_message::<E>()
+			.add_system_to_stage(NetStage::Receive, receive_events::<E>.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Send, send_events::<E>)
+	}
+}
+
+fn receive_events<E: Event + Clone>(
+	mut received: EventReader<Received<E>>,
+	mut events: ResMut<Events<E>>,
+	mut state: ResMut<NetEventState<E>>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let from_server = client.as_deref().and_then(|client| client.as_ref()).map(|conn| conn.uuid);
+	let mut reader = events.get_reader_current();
+	for Received { msg, from, .. } in received.iter() {
+		let accepted = if from_server == Some(*from) {
+			state.direction.to_clients()
+		} else if server.as_ref().is_some_and(|server| server.connections.contains_key(from)) {
+			state.direction.to_server()
+		} else {
+			false
+		};
+		if !accepted {
+			warn!("Dropped a `{}` from {}, which isn't sent this way.", std::any::type_name::<E>(), from);
+			continue;
+		}
+		events.send(msg.clone());
+	}
+	state.received.extend(reader.iter_with_id(&events).map(|(_, id)| id.id));
+}
+
+fn send_events<E: Event + Clone + Reflect>(
+	mut events: EventReader<E>,
+	mut state: ResMut<NetEventState<E>>,
+	config: Res<NetEventConfig>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let local: Vec<_> = events
+		.iter_with_id()
+		.filter(|(_, id)| !state.received.contains(&id.id))
+		.map(|(event, _)| event.clone())
+		.collect();
+	state.received.clear();
+	if local.is_empty() {
+		return;
+	}
+	if let Some(server) = server {
+		if state.direction.to_clients() {
+			for conn in server.connections.iter() {
+				send_to(&conn, &local, state.reliability, &config);
+			}
+		}
+	}
+	if let Some(conn) = client.as_deref().and_then(|client| client.as_ref()) {
+		if state.direction.to_server() {
+			send_to(conn, &local, state.reliability, &config);
+		}
+	}
+}
+
+fn send_to<E: Clone + Reflect>(
+	conn: &ConnectionHandle<NetMsg, NetMsg>,
+	events: &[E],
+	reliability: Reliability,
+	config: &NetEventConfig,
+) {
+	for event in events {
+		if reliability == Reliability::Unreliable && conn.pending() > config.max_pending {
+			return;
+		}
+		if let Err(err) = conn.send_blocking(NetMsg::new(event.clone())) {
+			warn!("Unable to send a `{}` to {}: {}", std::any::type_name::<E>(), conn.uuid, err);
+			return;
+		}
+	}
+}
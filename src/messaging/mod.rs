@@ -1,32 +1,100 @@
+pub mod buffer;
 pub mod bundle;
+pub mod codec;
 pub mod commands;
 pub mod components;
+pub mod events;
+pub mod protocol;
 
-use std::ops::Deref;
+use std::{marker::PhantomData, ops::Deref, sync::Arc};
 
+use bytes::BytesMut;
 use bevy::{
 	prelude::*,
-	reflect::serde::{ReflectSerializer, UntypedReflectDeserializer},
-	utils::Uuid,
+	reflect::serde::{TypedReflectDeserializer, TypedReflectSerializer},
+};
+use serde::{
+	de::{self, DeserializeOwned, DeserializeSeed, SeqAccess, Visitor},
+	ser::{self, SerializeTuple},
+	Deserialize, Deserializer, Serialize, Serializer,
 };
-use serde::{de::DeserializeSeed, Deserialize, Serialize};
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::TYPE_REGISTRY;
+use crate::{
+	connection::{channel::ChannelId, ConnectionError},
+	tick::{NetTick, SharedNetTick},
+};
+
+use self::{
+	buffer::BufferPool,
+	codec::{Codec, CodecError},
+	protocol::{ErrorSlot, NetMessageId, NetMessageRegistry, ProtocolError},
+};
+
+/// Identifies a networked entity on all peers, allocated by [crate::NetEntityRegistry].
+///
+/// `index`es are handed out sequentially and reused after the entity is deregistered, with the
+/// `generation` bumped so old ids don't match the new entity.
+#[derive(
+	Reflect, FromReflect, Serialize, Deserialize, Debug, Default, Clone, Copy, Component, PartialEq, Eq, Hash,
+)]
+pub struct NetEntityId {
+	pub index: u32,
+	pub generation: u32,
+}
+
+impl std::fmt::Display for NetEntityId {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "{}v{}", self.index, self.generation)
+	}
+}
 
-/// Only use for sending over the network
-#[derive(Reflect, Clone, Copy, Component, PartialEq, Eq, Hash)]
-pub struct NetUuid([u8; 16]);
+/// Bytes serialized as one blob, where a reflected `Vec<u8>` is serialized byte by byte.
+///
+/// Register it with [App::register_type] to use it in net messages.
+#[derive(Reflect, FromReflect, Debug, Default, Clone, PartialEq, Eq, Deref, DerefMut)]
+#[reflect_value(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RawBytes(pub Vec<u8>);
 
-impl From<Uuid> for NetUuid {
-	fn from(uuid: Uuid) -> Self {
-		Self(uuid.into_bytes())
+impl Serialize for RawBytes {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_bytes(&self.0)
 	}
 }
 
-impl From<NetUuid> for Uuid {
-	fn from(net: NetUuid) -> Self {
-		Self::from_bytes(net.0)
+impl<'de> Deserialize<'de> for RawBytes {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_byte_buf(RawBytesVisitor)
+	}
+}
+
+struct RawBytesVisitor;
+
+impl<'de> Visitor<'de> for RawBytesVisitor {
+	type Value = RawBytes;
+
+	fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+		formatter.write_str("bytes")
+	}
+
+	fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+		Ok(RawBytes(v.to_vec()))
+	}
+
+	fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+		Ok(RawBytes(v))
+	}
+
+	/// Formats without a bytes type, like JSON, write them as a sequence.
+	fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+	where
+		A: SeqAccess<'de>,
+	{
+		let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+		while let Some(byte) = seq.next_element()? {
+			bytes.push(byte);
+		}
+		Ok(RawBytes(bytes))
 	}
 }
 
@@ -41,35 +109,216 @@ impl From<Box<dyn Reflect>> for NetMsg {
 	}
 }
 
-impl Serialize for NetMsg {
-	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+impl NetMsg {
+	pub fn new<T: Reflect>(msg: T) -> Self {
+		Self {
+			inner: Box::new(msg),
+		}
+	}
+}
+
+/// Serialized as `(id, payload)`, where `id` is the [NetMessageId] of the payload's type.
+impl NetSerialize for NetMsg {
+	fn net_serialize<S>(&self, serializer: S, ctx: &NetContext) -> Result<S::Ok, S::Error>
 	where
-		S: serde::Serializer,
+		S: Serializer,
 	{
-		let binding = TYPE_REGISTRY
-			.get()
-			.expect("The global type registry should be available before this can run.")
-			.0
-			.read();
+		let type_name = self.inner.type_name();
+		let Some(id) = ctx.messages.id(type_name) else {
+			ctx.errors.report(ProtocolError::Unregistered(type_name.to_owned()));
+			return Err(ser::Error::custom(format_args!("`{type_name}` is not registered as a net message")));
+		};
+		let binding = ctx.type_registry.read();
 		let type_registry = binding.deref();
-		let ser = ReflectSerializer::new(self.inner.as_reflect(), type_registry);
-		Ok(ser.serialize(serializer)?)
+		let mut tuple = serializer.serialize_tuple(2)?;
+		tuple.serialize_element(&id)?;
+		tuple.serialize_element(&TypedReflectSerializer::new(self.inner.as_reflect(), type_registry))?;
+		tuple.end()
 	}
 }
 
-impl<'de> Deserialize<'de> for NetMsg {
-	fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+impl NetDeserialize for NetMsg {
+	fn net_deserialize<'de, D>(deserializer: D, ctx: &NetContext) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		deserializer.deserialize_tuple(2, NetMsgVisitor(ctx))
+	}
+}
+
+struct NetMsgVisitor<'a>(&'a NetContext);
+
+impl<'a, 'de> Visitor<'de> for NetMsgVisitor<'a> {
+	type Value = NetMsg;
+
+	fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+		formatter.write_str("a net message id followed by its payload")
+	}
+
+	fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
 	where
-		D: serde::Deserializer<'de>,
+		A: SeqAccess<'de>,
 	{
-		let binding = TYPE_REGISTRY
-			.get()
-			.expect("The global type registry should be available before this can run.")
-			.0
-			.read();
+		let ctx = self.0;
+		let id: NetMessageId = seq
+			.next_element()?
+			.ok_or_else(|| de::Error::invalid_length(0, &self))?;
+		let Some(type_name) = ctx.peer_type_name(id) else {
+			ctx.errors.report(ProtocolError::UnknownId(id));
+			return Err(de::Error::custom(format_args!("unknown net message id {id}")));
+		};
+		if ctx.messages.id(&type_name).is_none() {
+			ctx.errors.report(ProtocolError::Unregistered(type_name.clone()));
+			return Err(de::Error::custom(format_args!("`{type_name}` is not registered as a net message")));
+		}
+		let binding = ctx.type_registry.read();
 		let type_registry = binding.deref();
-		let de = UntypedReflectDeserializer::new(type_registry);
-		Ok(de.deserialize(deserializer)?.into())
+		let Some(registration) = type_registry.get_with_name(&type_name) else {
+			return Err(de::Error::custom(format_args!("`{type_name}` is not in the type registry")));
+		};
+		let inner = seq
+			.next_element_seed(TypedReflectDeserializer::new(registration, type_registry))?
+			.ok_or_else(|| de::Error::invalid_length(1, &self))?;
+		Ok(inner.into())
+	}
+}
+
+/// State a connection makes available to messages while (de)serializing them,
+/// so that every [App] can use its own [AppTypeRegistry].
+#[derive(Clone, Default)]
+pub struct NetContext {
+	pub type_registry: AppTypeRegistry,
+	pub messages: NetMessageRegistry,
+	/// The message table the peer announced during the handshake, indexed by its [NetMessageId]s.
+	pub peer_messages: Option<Arc<Vec<String>>>,
+	/// Stamped on outgoing frames.
+	pub tick: SharedNetTick,
+	pub(crate) errors: ErrorSlot,
+}
+
+impl NetContext {
+	pub fn new(type_registry: AppTypeRegistry, messages: NetMessageRegistry) -> Self {
+		Self {
+			type_registry,
+			messages,
+			peer_messages: None,
+			tick: SharedNetTick::default(),
+			errors: ErrorSlot::default(),
+		}
+	}
+
+	/// A copy with its own [ErrorSlot], as the reader and writer of a connection share the context.
+	pub(crate) fn for_message(&self) -> Self {
+		Self {
+			errors: ErrorSlot::default(),
+			..self.clone()
+		}
+	}
+
+	/// The type path the peer uses `id` for, falling back to the local registry before the handshake.
+	pub fn peer_type_name(&self, id: NetMessageId) -> Option<String> {
+		match &self.peer_messages {
+			Some(names) => names.get(id as usize).cloned(),
+			None => self.messages.type_name(id),
+		}
+	}
+
+	/// The context of `app`, sharing its type and message registries and its [NetTick](crate::tick::NetTick).
+	pub fn from_app(app: &mut App) -> Self {
+		let messages = app
+			.world
+			.get_resource_or_insert_with(NetMessageRegistry::default)
+			.clone();
+		let tick = app.world.get_resource_or_insert_with(SharedNetTick::default).clone();
+		Self {
+			tick,
+			..Self::new(app.world.resource::<AppTypeRegistry>().clone(), messages)
+		}
+	}
+}
+
+impl std::fmt::Debug for NetContext {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("NetContext").finish_non_exhaustive()
+	}
+}
+
+/// Serialize a message with access to the [NetContext] of its connection.
+///
+/// Implemented for every [Serialize] type, which ignores the context.
+pub trait NetSerialize {
+	fn net_serialize<S>(&self, serializer: S, ctx: &NetContext) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer;
+}
+
+impl<T: Serialize> NetSerialize for T {
+	fn net_serialize<S>(&self, serializer: S, _ctx: &NetContext) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		self.serialize(serializer)
+	}
+}
+
+/// Deserialize a message with access to the [NetContext] of its connection.
+///
+/// Implemented for every [DeserializeOwned] type, which ignores the context.
+pub trait NetDeserialize: Sized {
+	fn net_deserialize<'de, D>(deserializer: D, ctx: &NetContext) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>;
+
+	/// Builds a received message from its frame, which is returned to the [buffer::BufferPool] afterwards.
+	///
+	/// Types that keep the frame around, like [buffer::Frame], can take its contents without copying.
+	fn from_frame(
+		frame: &mut BytesMut,
+		ctx: &NetContext,
+		codec: &Arc<dyn Codec>,
+	) -> Result<Self, ConnectionError> {
+		decode(frame, ctx, &**codec)
+	}
+}
+
+impl<T: DeserializeOwned> NetDeserialize for T {
+	fn net_deserialize<'de, D>(deserializer: D, _ctx: &NetContext) -> Result<Self, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		T::deserialize(deserializer)
+	}
+}
+
+/// Adapts a [NetSerialize] type to [Serialize] by carrying its context along.
+pub struct WithContext<'a, T: NetSerialize + ?Sized>(pub &'a T, pub &'a NetContext);
+
+impl<'a, T: NetSerialize + ?Sized> Serialize for WithContext<'a, T> {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: Serializer,
+	{
+		self.0.net_serialize(serializer, self.1)
+	}
+}
+
+/// Adapts a [NetDeserialize] type to [DeserializeSeed] by carrying its context along.
+pub struct ContextSeed<'a, T: NetDeserialize>(pub &'a NetContext, pub PhantomData<T>);
+
+impl<'a, T: NetDeserialize> ContextSeed<'a, T> {
+	pub fn new(ctx: &'a NetContext) -> Self {
+		Self(ctx, PhantomData)
+	}
+}
+
+impl<'a, 'de, T: NetDeserialize> DeserializeSeed<'de> for ContextSeed<'a, T> {
+	type Value = T;
+
+	fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		T::net_deserialize(deserializer, self.0)
 	}
 }
 
@@ -94,22 +343,92 @@ pub trait CastNetMsg: Sized {
 	}
 }
 
+pub(crate) fn encode<T: NetSerialize>(
+	msg: &T,
+	ctx: &NetContext,
+	codec: &dyn Codec,
+) -> Result<Vec<u8>, ConnectionError> {
+	let ctx = ctx.for_message();
+	Ok(codec.encode(&WithContext(msg, &ctx)).map_err(|err| ctx.errors.attach(err))?)
+}
+
+pub(crate) fn decode<T: NetDeserialize>(
+	bytes: &[u8],
+	ctx: &NetContext,
+	codec: &dyn Codec,
+) -> Result<T, ConnectionError> {
+	let ctx = ctx.for_message();
+	let mut value = None;
+	codec
+		.decode(bytes, &mut |de| {
+			value = Some(ContextSeed::<T>::new(&ctx).deserialize(de)?);
+			Ok(())
+		})
+		.map_err(|err| ctx.errors.attach(err))?;
+	Ok(value.ok_or(CodecError::NotVisited(codec.name()))?)
+}
+
+/// Set in a frame's flags when its payload is lz4 compressed.
+pub(crate) const FLAG_COMPRESSED: u8 = 1;
+/// Set on every fragment of a message but the last, see [crate::connection::channel].
+pub(crate) const FLAG_FRAGMENT: u8 = 2;
+
+/// The header written in front of every frame's payload.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+	pub flags: u8,
+	/// The channel the message was sent on.
+	pub channel: ChannelId,
+	/// The sender's tick when the message was sent.
+	pub tick: NetTick,
+}
+
 pub(crate) async fn send_msg<W>(writer: &mut W, data: Vec<u8>) -> Result<(), io::Error>
 where
 	W: AsyncWrite + Unpin,
 {
-	writer.write_u64_le(data.len() as u64).await?;
-	writer.write_all(&*data).await?;
+	write_msg(writer, &data, FrameHeader::default()).await?;
 	writer.flush().await?;
 	Ok(())
 }
 
-pub(crate) async fn recv_msg<R>(reader: &mut R) -> Result<Vec<u8>, io::Error>
+/// Writes a length-prefixed frame without flushing the writer.
+pub(crate) async fn write_msg<W>(writer: &mut W, data: &[u8], header: FrameHeader) -> Result<(), io::Error>
+where
+	W: AsyncWrite + Unpin,
+{
+	writer.write_u64_le(data.len() as u64).await?;
+	writer.write_u8(header.flags).await?;
+	writer.write_u8(header.channel).await?;
+	writer.write_u32_le(header.tick.0).await?;
+	writer.write_all(data).await?;
+	Ok(())
+}
+
+/// Reads a frame written by [write_msg] into a buffer from `pool`, returning its header and payload.
+///
+/// Frames with a payload above `max_size` bytes are rejected before anything is allocated for them.
+pub(crate) async fn recv_msg<R>(
+	reader: &mut R,
+	pool: &BufferPool,
+	max_size: usize,
+) -> Result<(FrameHeader, BytesMut), ConnectionError>
 where
 	R: AsyncRead + Unpin,
 {
 	let num_bytes = reader.read_u64_le().await?;
-	let mut buf = vec![0u8; num_bytes as usize];
-	reader.read_exact(&mut *buf).await?;
-	Ok(buf)
+	if num_bytes > max_size as u64 {
+		return Err(ConnectionError::MessageTooLarge {
+			size: num_bytes as usize,
+			max: max_size,
+		});
+	}
+	let num_bytes = num_bytes as usize;
+	let flags = reader.read_u8().await?;
+	let channel = reader.read_u8().await?;
+	let tick = NetTick(reader.read_u32_le().await?);
+	let mut buf = pool.take(num_bytes);
+	buf.resize(num_bytes, 0);
+	reader.read_exact(&mut buf).await?;
+	Ok((FrameHeader { flags, channel, tick }, buf))
 }
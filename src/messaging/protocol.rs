@@ -0,0 +1,180 @@
+use std::sync::{Arc, Mutex, RwLock};
+
+use bevy::{
+	ecs::event::ManualEventReader,
+	prelude::*,
+	reflect::{FromReflect, GetTypeRegistration},
+	utils::HashMap,
+};
+use thiserror::Error;
+
+use crate::{
+	client::FromServer,
+	connection::{ext::Event, ConnectionId},
+	server::FromClient,
+	tick::NetTick,
+	NetStage, NetSystem,
+};
+
+use super::{codec::CodecError, NetMsg};
+
+/// Compact identifier of a message type, written in place of its full type path.
+pub type NetMessageId = u32;
+
+#[derive(Default)]
+struct NetMessageTable {
+	ids: HashMap<String, NetMessageId>,
+	names: Vec<String>,
+}
+
+/// Maps the type paths of messages registered with [NetAppExt::add_net_message] to [NetMessageId]s.
+///
+/// Ids are handed out in registration order and only have to be unique locally,
+/// as both sides announce their table during the [crate::connection::handshake].
+#[derive(Resource, Clone, Default)]
+pub struct NetMessageRegistry {
+	table: Arc<RwLock<NetMessageTable>>,
+}
+
+impl NetMessageRegistry {
+	pub fn register(&self, type_name: &str) -> NetMessageId {
+		let mut table = self.table.write().unwrap();
+		if let Some(id) = table.ids.get(type_name) {
+			return *id;
+		}
+		let id = table.names.len() as NetMessageId;
+		table.ids.insert(type_name.to_owned(), id);
+		table.names.push(type_name.to_owned());
+		id
+	}
+
+	pub fn id(&self, type_name: &str) -> Option<NetMessageId> {
+		self.table.read().unwrap().ids.get(type_name).copied()
+	}
+
+	pub fn type_name(&self, id: NetMessageId) -> Option<String> {
+		self.table.read().unwrap().names.get(id as usize).cloned()
+	}
+
+	/// All registered type paths, indexed by their [NetMessageId].
+	pub fn names(&self) -> Vec<String> {
+		self.table.read().unwrap().names.clone()
+	}
+}
+
+impl std::fmt::Debug for NetMessageRegistry {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_list().entries(self.table.read().unwrap().names.iter()).finish()
+	}
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+	#[error("The peer sent a message with unknown id {0}.")]
+	UnknownId(NetMessageId),
+	#[error("`{0}` is not registered as a net message.")]
+	Unregistered(String),
+}
+
+/// Where a (de)serializer records the [ProtocolError] behind a failure, as serde errors only carry a message.
+///
+/// Every encoded or decoded message gets its own slot, see [super::NetContext::for_message].
+#[derive(Clone, Default)]
+pub(crate) struct ErrorSlot(Arc<Mutex<Option<ProtocolError>>>);
+
+impl ErrorSlot {
+	pub fn report(&self, err: ProtocolError) {
+		*self.0.lock().unwrap() = Some(err);
+	}
+
+	/// Replaces `err` with the [ProtocolError] that caused it, if one was reported.
+	pub fn attach(&self, err: CodecError) -> CodecError {
+		match self.0.lock().unwrap().take() {
+			Some(err) => err.into(),
+			None => err,
+		}
+	}
+}
+
+/// A message of type `T` received from the connection `from`.
+#[derive(Debug)]
+pub struct Received<T> {
+	pub msg: T,
+	pub from: ConnectionId,
+	/// The sender's [NetTick] when it sent the message.
+	pub tick: NetTick,
+}
+
+pub trait NetAppExt {
+	/// Registers `T` as a [NetMsg] payload and dispatches incoming messages of that type to [Received<T>] events.
+	///
+	/// The types of `T`'s fields have to be in the type registry as well. Registering the same type again does
+	/// nothing, so plugins can add the messages they need without coordinating.
+	///
+	/// Only the default instance, `Server<NetMsg, NetMsg>` and `Client<NetMsg, NetMsg>`, is dispatched: messages
+	/// received by a labelled instance like `ServerPlugin::<NetMsg, NetMsg, ChatRelay>` stay in its [FromClient]
+	/// or [FromServer] events.
+	fn add_net_message<T>(&mut self) -> &mut Self
+	where
+		T: Reflect + FromReflect + GetTypeRegistration;
+}
+
+impl NetAppExt for App {
+	fn add_net_message<T>(&mut self) -> &mut Self
+	where
+		T: Reflect + FromReflect + GetTypeRegistration,
+	{
+		let messages = self.world.get_resource_or_insert_with(NetMessageRegistry::default);
+		if messages.id(std::any::type_name::<T>()).is_some() {
+			return self;
+		}
+		messages.register(std::any::type_name::<T>());
+		self.register_type::<T>();
+		self.add_event::<Received<T>>().add_system_to_stage(
+			NetStage::Receive,
+			dispatch_net_message::<T>
+				.label(NetSystem::Dispatch)
+				.after(NetSystem::Receive),
+		)
+	}
+}
+
+fn dispatch_net_message<T: Reflect + FromReflect>(
+	from_clients: Option<Res<Events<FromClient<NetMsg>>>>,
+	from_servers: Option<Res<Events<FromServer<NetMsg>>>>,
+	mut client_reader: Local<ManualEventReader<FromClient<NetMsg>>>,
+	mut server_reader: Local<ManualEventReader<FromServer<NetMsg>>>,
+	mut eventwriter: EventWriter<Received<T>>,
+) {
+	if let Some(events) = from_clients {
+		for event in client_reader.iter(&events) {
+			dispatch(event, event.tick(), &mut eventwriter);
+		}
+	}
+	if let Some(events) = from_servers {
+		for event in server_reader.iter(&events) {
+			dispatch(event, event.tick(), &mut eventwriter);
+		}
+	}
+}
+
+fn dispatch<T: Reflect + FromReflect>(
+	event: &Event<NetMsg>,
+	tick: Option<NetTick>,
+	eventwriter: &mut EventWriter<Received<T>>,
+) {
+	let Event::Message(msg, from) = event else {
+		return;
+	};
+	if !msg.is::<T>() && msg.type_name() != std::any::type_name::<T>() {
+		return;
+	}
+	match T::from_reflect(msg.as_reflect()) {
+		Some(msg) => eventwriter.send(Received {
+			msg,
+			from: *from,
+			tick: tick.unwrap_or_default(),
+		}),
+		None => warn!("Unable to convert a `{}` received from {}.", msg.type_name(), from),
+	}
+}
@@ -0,0 +1,339 @@
+//! Client-authoritative entities.
+//!
+//! By default the server owns every replicated entity. An [Authority] component on the server hands the
+//! right to write an entity's replicated components to one of its clients instead. That client has
+//! [LocalAuthority] on its copy of the entity, sends the server its changes as [AuthoritativeUpdate]s,
+//! and stops applying snapshots to it. The server applies updates from the owner, rejects all others with
+//! an [UpdateRejected] event, and replicates the entity to everyone else as usual.
+//!
+//! Authority is handed over through [AuthorityTransfers]: the previous owner is told it lost authority,
+//! and the new owner has to acknowledge it before the server accepts its updates. In between, updates
+//! from either are rejected. Authority falls back to the server when the owner disconnects.
+
+use bevy::{prelude::*, utils::HashMap};
+
+use crate::{
+	client::Client,
+	connection::ConnectionId,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg, NetEntityId,
+	},
+	server::Server,
+	NetEntityRegistry, NetStage, NetSystem,
+};
+
+use super::{
+	serialize_entity, ComponentData, EntityState, ReplicationConfig, ReplicationRules, ReplicationSystem,
+};
+
+/// Who may write the replicated components of an entity, on the server.
+///
+/// Entities without it are owned by the server. Change it through [AuthorityTransfers].
+#[derive(Component, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Authority {
+	#[default]
+	Server,
+	Client(ConnectionId),
+}
+
+/// Marks an entity on the client whose replicated components this client writes.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct LocalAuthority;
+
+/// The changed replicated components of an entity, sent by the client that has authority over it.
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct AuthoritativeUpdate {
+	pub net_id: NetEntityId,
+	pub changed: Vec<ComponentData>,
+}
+
+/// Tells a client it was granted or lost authority over an entity.
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct AuthorityTransfer {
+	pub net_id: NetEntityId,
+	pub granted: bool,
+}
+
+/// Acknowledges an [AuthorityTransfer].
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct AuthorityAck {
+	pub net_id: NetEntityId,
+	pub granted: bool,
+}
+
+/// Sent on the server when the [Authority] over an entity changed.
+#[derive(Debug, Clone)]
+pub struct AuthorityChanged {
+	pub entity: Entity,
+	pub authority: Authority,
+}
+
+/// Sent on the server when an [AuthoritativeUpdate] came from a connection without authority.
+#[derive(Debug, Clone)]
+pub struct UpdateRejected {
+	pub connection: ConnectionId,
+	pub net_id: NetEntityId,
+}
+
+/// Hands authority over entities between the server and its clients.
+#[derive(Resource, Debug, Default)]
+pub struct AuthorityTransfers {
+	requested: Vec<(Entity, Authority)>,
+	/// Transfers waiting for the new owner's [AuthorityAck].
+	pending: HashMap<NetEntityId, (Entity, ConnectionId)>,
+}
+
+impl AuthorityTransfers {
+	/// Hands authority over the replicated `entity` to `to`, in [NetStage::Send].
+	pub fn transfer(&mut self, entity: Entity, to: Authority) {
+		self.requested.push((entity, to));
+	}
+
+	/// Whether authority over `net_id` is being handed over, waiting for the new owner to acknowledge it.
+	pub fn is_pending(&self, net_id: NetEntityId) -> bool {
+		self.pending.contains_key(&net_id)
+	}
+}
+
+/// Client side state of the entities this client has authority over.
+#[derive(Resource)]
+struct AuthorityClient {
+	timer: Timer,
+	/// The state last sent of each owned entity.
+	sent: HashMap<NetEntityId, EntityState>,
+	/// Transfers for entities that weren't spawned yet.
+	parked: Vec<AuthorityTransfer>,
+}
+
+fn send_to_client(server: &Server<NetMsg, NetMsg>, to: ConnectionId, msg: NetMsg) {
+	let Some(conn) = server.connections.get(&to) else {
+		return;
+	};
+	if let Err(err) = conn.send_blocking(msg) {
+		warn!("Unable to send an authority transfer to {}: {}", to, err);
+	}
+}
+
+/// Starts requested transfers, and returns authority of disconnected clients to the server.
+fn start_transfers(world: &mut World) {
+	if !world.contains_resource::<Server<NetMsg, NetMsg>>() {
+		return;
+	}
+	let mut owned = world.query::<(Entity, &Authority)>();
+	let server = world.resource::<Server<NetMsg, NetMsg>>();
+	let mut requested: Vec<_> = owned
+		.iter(world)
+		.filter(|(_, authority)| matches!(authority, Authority::Client(id) if !server.connections.contains_key(id)))
+		.map(|(entity, _)| (entity, Authority::Server))
+		.collect();
+	let mut transfers = world.resource_mut::<AuthorityTransfers>();
+	requested.append(&mut transfers.requested);
+	// Transfers to clients that left will never be acknowledged.
+	let server = world.resource::<Server<NetMsg, NetMsg>>();
+	let abandoned: Vec<_> = world
+		.resource::<AuthorityTransfers>()
+		.pending
+		.iter()
+		.filter(|(_, (_, to))| !server.connections.contains_key(to))
+		.map(|(net_id, (entity, _))| (*net_id, *entity))
+		.collect();
+	for (net_id, entity) in abandoned {
+		world.resource_mut::<AuthorityTransfers>().pending.remove(&net_id);
+		requested.push((entity, Authority::Server));
+	}
+
+	let mut changed = Vec::new();
+	for (entity, to) in requested {
+		let Some(net_id) = world.get::<NetEntityId>(entity).copied() else {
+			warn!("Unable to transfer authority over {:?}, it isn't replicated yet.", entity);
+			continue;
+		};
+		let previous = world.get::<Authority>(entity).copied().unwrap_or_default();
+		let server = world.resource::<Server<NetMsg, NetMsg>>();
+		if let Authority::Client(previous) = previous {
+			send_to_client(server, previous, NetMsg::new(AuthorityTransfer { net_id, granted: false }));
+		}
+		let pending = &mut world.resource_mut::<AuthorityTransfers>().pending;
+		if let Some((_, to)) = pending.remove(&net_id) {
+			let server = world.resource::<Server<NetMsg, NetMsg>>();
+			send_to_client(server, to, NetMsg::new(AuthorityTransfer { net_id, granted: false }));
+		}
+		match to {
+			Authority::Server => {
+				world.entity_mut(entity).insert(Authority::Server);
+				changed.push(AuthorityChanged { entity, authority: to });
+			}
+			Authority::Client(id) => {
+				// Nobody may write the entity until the new owner acknowledged it.
+				world.entity_mut(entity).insert(Authority::Server);
+				world.resource_mut::<AuthorityTransfers>().pending.insert(net_id, (entity, id));
+				let server = world.resource::<Server<NetMsg, NetMsg>>();
+				send_to_client(server, id, NetMsg::new(AuthorityTransfer { net_id, granted: true }));
+			}
+		}
+	}
+	world.resource_mut::<Events<AuthorityChanged>>().extend(changed);
+}
+
+fn receive_acks(
+	mut commands: Commands,
+	mut acks: EventReader<Received<AuthorityAck>>,
+	mut transfers: ResMut<AuthorityTransfers>,
+	mut changed: EventWriter<AuthorityChanged>,
+) {
+	for Received { msg, from, .. } in acks.iter() {
+		if !msg.granted || !matches!(transfers.pending.get(&msg.net_id), Some((_, to)) if to == from) {
+			continue;
+		}
+		let (entity, _) = transfers.pending.remove(&msg.net_id).unwrap();
+		let authority = Authority::Client(*from);
+		commands.entity(entity).insert(authority);
+		changed.send(AuthorityChanged { entity, authority });
+	}
+}
+
+/// Applies updates from owners, and rejects the others.
+fn receive_updates(world: &mut World) {
+	let updates: Vec<_> = world
+		.resource_mut::<Events<Received<AuthoritativeUpdate>>>()
+		.drain()
+		.collect();
+	if updates.is_empty() {
+		return;
+	}
+	let mut rejected = Vec::new();
+	world.resource_scope(|world, rules: Mut<ReplicationRules>| {
+		for Received { msg, from, tick } in updates {
+			let entity = world.resource::<NetEntityRegistry>().get(msg.net_id);
+			let authorized = entity.filter(|entity| {
+				matches!(world.get::<Authority>(*entity), Some(Authority::Client(owner)) if *owner == from)
+			});
+			let Some(entity) = authorized else {
+				rejected.push(UpdateRejected {
+					connection: from,
+					net_id: msg.net_id,
+				});
+				continue;
+			};
+			for ComponentData { id, data } in msg.changed.iter() {
+				match rules.rules.get(id) {
+					Some(rule) => (rule.apply)(world, entity, data, tick),
+					None => warn!("Received unknown replicated component {:#x}.", id),
+				}
+			}
+		}
+	});
+	for rejection in rejected.iter() {
+		warn!("Rejected an update of {:?} from {} without authority.", rejection.net_id, rejection.connection);
+	}
+	world.resource_mut::<Events<UpdateRejected>>().extend(rejected);
+}
+
+/// Grants or revokes [LocalAuthority] on the client, and acknowledges it.
+fn receive_transfers(world: &mut World) {
+	let mut transfers: Vec<_> = world
+		.resource_mut::<Events<Received<AuthorityTransfer>>>()
+		.drain()
+		.map(|received| received.msg)
+		.collect();
+	let mut parked = std::mem::take(&mut world.resource_mut::<AuthorityClient>().parked);
+	parked.append(&mut transfers);
+	if parked.is_empty() {
+		return;
+	}
+	let Some(conn) = world
+		.get_resource::<Client<NetMsg, NetMsg>>()
+		.and_then(|client| client.as_ref())
+	else {
+		// Keep them until they can be acknowledged.
+		world.resource_mut::<AuthorityClient>().parked = parked;
+		return;
+	};
+	let mut acks = Vec::new();
+	let mut unresolved = Vec::new();
+	for transfer in parked {
+		match world.resource::<NetEntityRegistry>().get(transfer.net_id) {
+			Some(entity) if world.get_entity(entity).is_some() => acks.push((entity, transfer)),
+			_ => unresolved.push(transfer),
+		}
+	}
+	for (_, transfer) in acks.iter() {
+		let ack = AuthorityAck {
+			net_id: transfer.net_id,
+			granted: transfer.granted,
+		};
+		if let Err(err) = conn.send_blocking(NetMsg::new(ack)) {
+			warn!("Unable to acknowledge authority over {:?}: {}", transfer.net_id, err);
+		}
+	}
+	for (entity, transfer) in acks {
+		if transfer.granted {
+			world.entity_mut(entity).insert(LocalAuthority);
+		} else {
+			world.entity_mut(entity).remove::<LocalAuthority>();
+			world.resource_mut::<AuthorityClient>().sent.remove(&transfer.net_id);
+		}
+	}
+	world.resource_mut::<AuthorityClient>().parked = unresolved;
+}
+
+/// Sends the server the changes to entities this client has authority over.
+fn send_updates(world: &mut World) {
+	let delta = world.resource::<Time>().delta();
+	if !world.resource_mut::<AuthorityClient>().timer.tick(delta).just_finished() {
+		return;
+	}
+	let mut owned = world.query_filtered::<(Entity, &NetEntityId), With<LocalAuthority>>();
+	let owned: Vec<_> = owned.iter(world).map(|(entity, net_id)| (entity, *net_id)).collect();
+	let Some(conn) = world
+		.get_resource::<Client<NetMsg, NetMsg>>()
+		.and_then(|client| client.as_ref())
+	else {
+		return;
+	};
+	let mut sent = Vec::new();
+	for (entity, net_id) in owned {
+		let state = serialize_entity(world, entity);
+		let previous = world.resource::<AuthorityClient>().sent.get(&net_id);
+		let changed: Vec<_> = state
+			.iter()
+			.filter(|(id, data)| previous.and_then(|previous| previous.get(*id)) != Some(*data))
+			.map(|(id, data)| ComponentData {
+				id: *id,
+				data: data.clone(),
+			})
+			.collect();
+		if changed.is_empty() {
+			continue;
+		}
+		if let Err(err) = conn.send_blocking(NetMsg::new(AuthoritativeUpdate { net_id, changed })) {
+			warn!("Unable to send an update of {:?}: {}", net_id, err);
+			continue;
+		}
+		sent.push((net_id, state));
+	}
+	world.resource_mut::<AuthorityClient>().sent.extend(sent);
+}
+
+pub(crate) fn build(app: &mut App, config: &ReplicationConfig) {
+	app.register_type::<AuthoritativeUpdate>()
+		.register_type::<AuthorityTransfer>()
+		.register_type::<AuthorityAck>()
+		.add_net_message::<AuthoritativeUpdate>()
+		.add_net_message::<AuthorityTransfer>()
+		.add_net_message::<AuthorityAck>()
+		.init_resource::<AuthorityTransfers>()
+		.insert_resource(AuthorityClient {
+			timer: Timer::new(config.step(), TimerMode::Repeating),
+			sent: HashMap::default(),
+			parked: Vec::new(),
+		})
+		.add_event::<AuthorityChanged>()
+		.add_event::<UpdateRejected>()
+		.add_system_to_stage(NetStage::Receive, receive_acks.after(NetSystem::Dispatch))
+		.add_system_to_stage(NetStage::Receive, receive_updates.after(NetSystem::Dispatch))
+		.add_system_to_stage(NetStage::Receive, receive_transfers.after(ReplicationSystem::Apply))
+		.add_system_to_stage(NetStage::Send, start_transfers)
+		.add_system_to_stage(NetStage::Send, send_updates);
+}
@@ -0,0 +1,109 @@
+//! Replication of [Parent]/[Children] links between replicated entities.
+//!
+//! The [Parent] of a replicated entity is replicated like a component, as the [NetEntityId] of the parent.
+//! Only links to parents that are replicated themselves are kept. [Children] follow from the parents.
+//!
+//! When a child arrives before its parent, it is attached as soon as the parent is spawned.
+//! Until then the link is kept in [PendingParents], and the child has no parent.
+
+use bevy::{prelude::*, reflect::TypeRegistryInternal, utils::HashMap};
+
+use crate::{
+	messaging::NetEntityId,
+	tick::NetTick,
+	NetEntityRegistry, NetStage,
+};
+
+use super::{replication_id, ComponentRule, ReplicationRules, ReplicationSystem};
+
+/// Children whose parent hasn't been spawned yet.
+#[derive(Resource, Debug, Default)]
+pub struct PendingParents(HashMap<Entity, NetEntityId>);
+
+impl PendingParents {
+	/// The parent `child` is waiting for.
+	pub fn get(&self, child: Entity) -> Option<NetEntityId> {
+		self.0.get(&child).copied()
+	}
+
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
+fn serialize_parent(world: &World, entity: Entity, _: &TypeRegistryInternal) -> Option<Vec<u8>> {
+	let parent = world.get::<Parent>(entity)?;
+	let net_id = world.get::<NetEntityId>(parent.get())?;
+	postcard::to_stdvec(net_id).ok()
+}
+
+fn apply_parent(world: &mut World, entity: Entity, data: &[u8], _: NetTick) {
+	let Ok(net_id) = postcard::from_bytes::<NetEntityId>(data) else {
+		warn!("Received an invalid parent for {:?}.", entity);
+		return;
+	};
+	match world.resource::<NetEntityRegistry>().get(net_id) {
+		Some(parent) if world.get_entity(parent).is_some() => {
+			world.resource_mut::<PendingParents>().0.remove(&entity);
+			if world.get::<Parent>(entity).map(|current| current.get()) != Some(parent) {
+				world.entity_mut(parent).push_children(&[entity]);
+			}
+		}
+		_ => {
+			// Detach from the previous parent, which isn't the parent anymore while waiting for the new one.
+			if let Some(previous) = world.get::<Parent>(entity).map(|parent| parent.get()) {
+				world.entity_mut(previous).remove_children(&[entity]);
+			}
+			world.resource_mut::<PendingParents>().0.insert(entity, net_id);
+		}
+	}
+}
+
+fn remove_parent(world: &mut World, entity: Entity) {
+	world.resource_mut::<PendingParents>().0.remove(&entity);
+	if let Some(parent) = world.get::<Parent>(entity).map(|parent| parent.get()) {
+		world.entity_mut(parent).remove_children(&[entity]);
+	}
+}
+
+/// Attaches children whose parent was spawned since.
+fn attach_pending(world: &mut World) {
+	if world.resource::<PendingParents>().is_empty() {
+		return;
+	}
+	let pending = std::mem::take(&mut world.resource_mut::<PendingParents>().0);
+	let mut unresolved = HashMap::default();
+	for (child, net_id) in pending {
+		if world.get_entity(child).is_none() {
+			continue;
+		}
+		match world.resource::<NetEntityRegistry>().get(net_id) {
+			Some(parent) if world.get_entity(parent).is_some() => {
+				world.entity_mut(parent).push_children(&[child]);
+			}
+			_ => {
+				unresolved.insert(child, net_id);
+			}
+		}
+	}
+	world.resource_mut::<PendingParents>().0.extend(unresolved);
+}
+
+pub(crate) fn build(app: &mut App) {
+	let type_name = std::any::type_name::<Parent>();
+	app.init_resource::<PendingParents>()
+		.add_system_to_stage(NetStage::Receive, attach_pending.after(ReplicationSystem::Apply));
+	app.world.resource_mut::<ReplicationRules>().rules.insert(
+		replication_id(type_name),
+		ComponentRule {
+			type_name,
+			serialize: serialize_parent,
+			apply: apply_parent,
+			remove: remove_parent,
+		},
+	);
+}
@@ -0,0 +1,178 @@
+//! Filters which replicated entities each client receives.
+//!
+//! An entity is relevant to a client when, in order of precedence:
+//! - its [NetVisibility] shows or hides it for that client,
+//! - the first [InterestManagement] rule with an opinion says so,
+//! - it is within the [Relevancy] of one of the client's [InterestViewer]s.
+//!
+//! Entities without a [Transform] are always spatially relevant. Entities entering or leaving a client's
+//! interest set are spawned or despawned on that client by the next snapshot.
+
+use bevy::{
+	prelude::*,
+	utils::{HashMap, HashSet},
+};
+
+use crate::{connection::ConnectionId, messaging::NetEntityId};
+
+use super::Replicated;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relevancy {
+	/// Every replicated entity is relevant to every client.
+	Global,
+	/// Entities within `radius` of one of the client's viewers are relevant.
+	Radius(f32),
+	/// Entities in the grid cells within `range` cells of a viewer's cell are relevant.
+	Grid { cell_size: f32, range: i32 },
+}
+
+/// Decides whether an entity is relevant to a connection, or returns `None` to leave it to the next rule.
+pub type InterestRule = Box<dyn Fn(&World, ConnectionId, Entity) -> Option<bool> + Send + Sync>;
+
+#[derive(Resource)]
+pub struct InterestManagement {
+	pub relevancy: Relevancy,
+	rules: Vec<InterestRule>,
+}
+
+impl Default for InterestManagement {
+	fn default() -> Self {
+		Self::new(Relevancy::Global)
+	}
+}
+
+impl InterestManagement {
+	pub fn new(relevancy: Relevancy) -> Self {
+		Self {
+			relevancy,
+			rules: Vec::new(),
+		}
+	}
+
+	/// Adds a rule that takes precedence over [InterestManagement::relevancy]. Rules are checked in order.
+	pub fn add_rule<F>(&mut self, rule: F) -> &mut Self
+	where
+		F: Fn(&World, ConnectionId, Entity) -> Option<bool> + Send + Sync + 'static,
+	{
+		self.rules.push(Box::new(rule));
+		self
+	}
+}
+
+/// Marks the entity whose [Transform] determines what `connection` is interested in, e.g. its player.
+///
+/// A connection can have multiple viewers.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct InterestViewer {
+	pub connection: ConnectionId,
+}
+
+/// Per-client overrides of an entity's relevancy.
+#[derive(Component, Debug, Default, Clone)]
+pub struct NetVisibility {
+	shown: HashSet<ConnectionId>,
+	hidden: HashSet<ConnectionId>,
+}
+
+impl NetVisibility {
+	/// Always sends the entity to `connection`.
+	pub fn show(&mut self, connection: ConnectionId) {
+		self.hidden.remove(&connection);
+		self.shown.insert(connection);
+	}
+
+	/// Never sends the entity to `connection`.
+	pub fn hide(&mut self, connection: ConnectionId) {
+		self.shown.remove(&connection);
+		self.hidden.insert(connection);
+	}
+
+	/// Leaves the entity's relevancy for `connection` to the rules again.
+	pub fn reset(&mut self, connection: ConnectionId) {
+		self.shown.remove(&connection);
+		self.hidden.remove(&connection);
+	}
+
+	fn get(&self, connection: ConnectionId) -> Option<bool> {
+		if self.shown.contains(&connection) {
+			Some(true)
+		} else if self.hidden.contains(&connection) {
+			Some(false)
+		} else {
+			None
+		}
+	}
+}
+
+/// Sent on the server when an entity enters or leaves a client's interest set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterestChanged {
+	pub connection: ConnectionId,
+	pub net_id: NetEntityId,
+	pub relevant: bool,
+}
+
+fn cell(position: Vec3, cell_size: f32) -> IVec3 {
+	(position / cell_size).floor().as_ivec3()
+}
+
+fn spatially_relevant(relevancy: Relevancy, viewers: &[Vec3], position: Vec3) -> bool {
+	match relevancy {
+		Relevancy::Global => true,
+		Relevancy::Radius(radius) => viewers
+			.iter()
+			.any(|viewer| viewer.distance_squared(position) <= radius * radius),
+		Relevancy::Grid { cell_size, range } => {
+			let cell = cell(position, cell_size);
+			viewers.iter().any(|viewer| {
+				let offset = (cell - self::cell(*viewer, cell_size)).abs();
+				offset.max_element() <= range
+			})
+		}
+	}
+}
+
+/// The replicated entities relevant to each of `connections`.
+pub(crate) fn relevant_entities(
+	world: &mut World,
+	connections: &[ConnectionId],
+) -> HashMap<ConnectionId, HashSet<NetEntityId>> {
+	let mut viewers = world.query::<(&InterestViewer, &Transform)>();
+	let mut positions: HashMap<ConnectionId, Vec<Vec3>> = HashMap::default();
+	for (viewer, transform) in viewers.iter(world) {
+		positions.entry(viewer.connection).or_default().push(transform.translation);
+	}
+	let mut entities =
+		world.query_filtered::<(Entity, &NetEntityId, Option<&Transform>, Option<&NetVisibility>), With<Replicated>>();
+	let world = &*world;
+	let interest = world.get_resource::<InterestManagement>();
+	let relevancy = interest.map_or(Relevancy::Global, |interest| interest.relevancy);
+
+	let mut relevant = HashMap::default();
+	for connection in connections {
+		let viewers = positions.get(connection).map(Vec::as_slice).unwrap_or_default();
+		let set = entities
+			.iter(world)
+			.filter(|(entity, _, transform, visibility)| {
+				if let Some(relevant) = visibility.and_then(|visibility| visibility.get(*connection)) {
+					return relevant;
+				}
+				let rule = interest
+					.into_iter()
+					.flat_map(|interest| interest.rules.iter())
+					.find_map(|rule| rule(world, *connection, *entity));
+				if let Some(relevant) = rule {
+					return relevant;
+				}
+				match transform {
+					Some(transform) => spatially_relevant(relevancy, viewers, transform.translation),
+					None => true,
+				}
+			})
+			.map(|(_, net_id, _, _)| *net_id)
+			.collect();
+		relevant.insert(*connection, set);
+	}
+	relevant
+}
@@ -0,0 +1,177 @@
+//! Smooths remote entities by rendering their replicated components in between received server states.
+//!
+//! Register a component with [InterpolationAppExt::interpolate] and insert [Interpolated<T>] on the entities
+//! that should be smoothed. Snapshots then fill its buffer instead of overwriting `T`, and every frame `T` is
+//! set to the state [InterpolationConfig::delay] in the past. When no newer state arrived in time, the last
+//! two states are extrapolated for at most [InterpolationConfig::max_extrapolation].
+//!
+//! The server's current tick is taken from the [ServerClock], which the [super::ReplicationPlugin] keeps in sync.
+//! Server ticks are converted to local time assuming both sides use the same [crate::tick::NetTickConfig::tick_rate].
+
+use std::{
+	collections::VecDeque,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+
+use crate::{
+	clock::ServerClock,
+	tick::{NetTick, NetTickTimer},
+	NetSystem,
+};
+
+/// States kept per [Interpolated] component.
+const MAX_STATES: usize = 32;
+
+/// Blends between two states of a component.
+pub trait Interpolate: Clone {
+	/// `t` is 0 at `self` and 1 at `other`, and exceeds 1 when extrapolating.
+	fn interpolate(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		self + (other - self) * t
+	}
+}
+
+impl Interpolate for Vec2 {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		self.lerp(*other, t)
+	}
+}
+
+impl Interpolate for Vec3 {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		self.lerp(*other, t)
+	}
+}
+
+impl Interpolate for Quat {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		self.slerp(*other, t)
+	}
+}
+
+impl Interpolate for Transform {
+	fn interpolate(&self, other: &Self, t: f32) -> Self {
+		Transform {
+			translation: self.translation.interpolate(&other.translation, t),
+			rotation: self.rotation.interpolate(&other.rotation, t),
+			scale: self.scale.interpolate(&other.scale, t),
+		}
+	}
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct InterpolationConfig {
+	/// How far in the past interpolated entities are rendered.
+	pub delay: Duration,
+	/// How far past the newest state entities are extrapolated before they stop.
+	pub max_extrapolation: Duration,
+}
+
+impl Default for InterpolationConfig {
+	fn default() -> Self {
+		Self {
+			delay: Duration::from_millis(100),
+			max_extrapolation: Duration::from_millis(250),
+		}
+	}
+}
+
+/// Buffers the server states of `T`, keyed by the server's tick.
+#[derive(Component, Debug)]
+pub struct Interpolated<T: Send + Sync + 'static> {
+	states: VecDeque<(NetTick, T)>,
+}
+
+impl<T: Send + Sync + 'static> Default for Interpolated<T> {
+	fn default() -> Self {
+		Self {
+			states: VecDeque::new(),
+		}
+	}
+}
+
+impl<T: Send + Sync + 'static> Interpolated<T> {
+	/// Adds the server's state at `tick`, replacing the state for the same tick.
+	pub fn push(&mut self, tick: NetTick, value: T) {
+		match self.states.iter().position(|(buffered, _)| *buffered >= tick) {
+			Some(i) if self.states[i].0 == tick => self.states[i].1 = value,
+			Some(i) => self.states.insert(i, (tick, value)),
+			None => self.states.push_back((tick, value)),
+		}
+		if self.states.len() > MAX_STATES {
+			self.states.pop_front();
+		}
+	}
+
+	pub fn states(&self) -> impl Iterator<Item = &(NetTick, T)> {
+		self.states.iter()
+	}
+
+	/// Drops the states that are no longer needed to sample at `tick` or later.
+	pub fn prune(&mut self, tick: f64) {
+		while self.states.len() > 2 && self.states[1].0 .0 as f64 <= tick {
+			self.states.pop_front();
+		}
+	}
+}
+
+impl<T: Interpolate + Send + Sync + 'static> Interpolated<T> {
+	/// The state at `tick`, which may lie in between ticks.
+	///
+	/// Past the newest state, the last two states are extrapolated for at most `max_extrapolation` ticks.
+	pub fn sample(&self, tick: f64, max_extrapolation: f64) -> Option<T> {
+		let blend = |(t0, a): &(NetTick, T), (t1, b): &(NetTick, T), tick: f64| {
+			let t = (tick - t0.0 as f64) / (t1.0 as f64 - t0.0 as f64);
+			a.interpolate(b, t as f32)
+		};
+		let len = self.states.len();
+		match self.states.iter().position(|(state, _)| state.0 as f64 > tick) {
+			Some(0) => self.states.front().map(|(_, value)| value.clone()),
+			Some(i) => Some(blend(&self.states[i - 1], &self.states[i], tick)),
+			None if len >= 2 => {
+				let newest = self.states[len - 1].0 .0 as f64;
+				Some(blend(&self.states[len - 2], &self.states[len - 1], tick.min(newest + max_extrapolation)))
+			}
+			None => self.states.back().map(|(_, value)| value.clone()),
+		}
+	}
+}
+
+fn interpolate<T: Component + Interpolate>(
+	config: Res<InterpolationConfig>,
+	clock: Res<ServerClock>,
+	timer: Res<NetTickTimer>,
+	mut query: Query<(&mut T, &mut Interpolated<T>)>,
+) {
+	let step = timer.step().as_secs_f64();
+	let Some(now) = clock.server_tick(Instant::now()) else {
+		return;
+	};
+	let render = now - config.delay.as_secs_f64() / step;
+	let max_extrapolation = config.max_extrapolation.as_secs_f64() / step;
+	for (mut value, mut interpolated) in query.iter_mut() {
+		interpolated.prune(render);
+		if let Some(sampled) = interpolated.sample(render, max_extrapolation) {
+			*value = sampled;
+		}
+	}
+}
+
+pub trait InterpolationAppExt {
+	/// Renders [Interpolated<T>] entities in between their server states on the client.
+	///
+	/// Requires the [super::ReplicationPlugin], and `T` to be replicated.
+	fn interpolate<T: Component + Interpolate>(&mut self) -> &mut Self;
+}
+
+impl InterpolationAppExt for App {
+	fn interpolate<T: Component + Interpolate>(&mut self) -> &mut Self {
+		self.world.get_resource_or_insert_with(InterpolationConfig::default);
+		self.add_system_to_stage(CoreStage::PreUpdate, interpolate::<T>.after(NetSystem::Tick))
+	}
+}
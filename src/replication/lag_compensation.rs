@@ -0,0 +1,152 @@
+//! Server-side lag compensation, for checking hits against what a client saw when it acted.
+//!
+//! The [LagCompensationPlugin] records the [Transform] of every [Replicated] entity each tick in its
+//! [TransformHistory]. [rewind] temporarily restores those transforms to an earlier tick, and
+//! [rewind_tick] works out which tick a client was looking at from its round trip time and the
+//! [InterpolationConfig::delay] its remote entities are rendered with.
+//!
+//! Only [Transform]s are rewound; [GlobalTransform]s are not propagated while rewound.
+
+use std::{collections::VecDeque, time::Duration};
+
+use bevy::prelude::*;
+
+use crate::{
+	connection::ConnectionId,
+	tick::{NetTick, NetTickTimer},
+	NetStage,
+};
+
+use super::{interpolation::InterpolationConfig, Replicated, ReplicationServer};
+
+#[derive(Resource, Debug, Clone)]
+pub struct LagCompensationConfig {
+	/// How far back transforms are kept, and so how far entities can be rewound.
+	pub max_rewind: Duration,
+}
+
+impl Default for LagCompensationConfig {
+	fn default() -> Self {
+		Self {
+			max_rewind: Duration::from_secs(1),
+		}
+	}
+}
+
+/// The [Transform]s of a replicated entity at past ticks, added by the [LagCompensationPlugin].
+#[derive(Component, Debug, Default)]
+pub struct TransformHistory {
+	states: VecDeque<(NetTick, Transform)>,
+}
+
+impl TransformHistory {
+	/// The transform at `tick`, or at the last recorded tick before it.
+	///
+	/// Returns [None] if the entity has no transform recorded that far back.
+	pub fn at(&self, tick: NetTick) -> Option<Transform> {
+		self.states
+			.iter()
+			.rev()
+			.find(|(recorded, _)| *recorded <= tick)
+			.map(|(_, transform)| *transform)
+	}
+
+	pub fn states(&self) -> impl Iterator<Item = &(NetTick, Transform)> {
+		self.states.iter()
+	}
+
+	fn record(&mut self, tick: NetTick, transform: Transform, max_ticks: u32) {
+		match self.states.back_mut() {
+			Some((last, state)) if *last == tick => *state = transform,
+			_ => self.states.push_back((tick, transform)),
+		}
+		while matches!(self.states.front(), Some((oldest, _)) if tick.since(*oldest) > max_ticks as i32) {
+			self.states.pop_front();
+		}
+	}
+}
+
+/// The tick whose state `connection` saw when the input it sent just now was made.
+///
+/// That is the current tick, minus the round trip time and the interpolation delay, limited to
+/// [LagCompensationConfig::max_rewind]. Without a measured round trip time only the delay is subtracted.
+pub fn rewind_tick(world: &World, connection: ConnectionId) -> NetTick {
+	let tick = *world.resource::<NetTick>();
+	let step = world.resource::<NetTickTimer>().step();
+	let rtt = world
+		.get_resource::<ReplicationServer>()
+		.and_then(|replication| replication.rtt(connection))
+		.unwrap_or_default();
+	let delay = world
+		.get_resource::<InterpolationConfig>()
+		.map(|config| config.delay)
+		.unwrap_or_default();
+	let max_rewind = world
+		.get_resource::<LagCompensationConfig>()
+		.map(|config| config.max_rewind)
+		.unwrap_or_default();
+	let ticks = (rtt + delay).min(max_rewind).as_secs_f64() / step.as_secs_f64();
+	tick.offset(-(ticks.round() as i32))
+}
+
+/// Runs `f` with the [Transform] of every entity with a [TransformHistory] restored to `tick`,
+/// and puts the current transforms back afterwards.
+///
+/// Entities without a transform recorded at `tick` keep their current one.
+pub fn rewind<R>(world: &mut World, tick: NetTick, f: impl FnOnce(&mut World) -> R) -> R {
+	let mut query = world.query::<(Entity, &TransformHistory, &mut Transform)>();
+	let mut current = Vec::new();
+	for (entity, history, mut transform) in query.iter_mut(world) {
+		if let Some(past) = history.at(tick) {
+			current.push((entity, *transform));
+			*transform = past;
+		}
+	}
+	let result = f(world);
+	for (entity, transform) in current {
+		if let Some(mut rewound) = world.get_mut::<Transform>(entity) {
+			*rewound = transform;
+		}
+	}
+	result
+}
+
+/// [rewind]s to what `connection` saw, see [rewind_tick].
+pub fn rewind_for<R>(world: &mut World, connection: ConnectionId, f: impl FnOnce(&mut World) -> R) -> R {
+	let tick = rewind_tick(world, connection);
+	rewind(world, tick, f)
+}
+
+fn record_transforms(
+	mut commands: Commands,
+	config: Res<LagCompensationConfig>,
+	tick: Res<NetTick>,
+	timer: Res<NetTickTimer>,
+	mut query: Query<(Entity, &Transform, Option<&mut TransformHistory>), With<Replicated>>,
+) {
+	let max_ticks = (config.max_rewind.as_secs_f64() / timer.step().as_secs_f64()).ceil() as u32;
+	for (entity, transform, history) in query.iter_mut() {
+		match history {
+			Some(mut history) => history.record(*tick, *transform, max_ticks),
+			None => {
+				let mut history = TransformHistory::default();
+				history.record(*tick, *transform, max_ticks);
+				commands.entity(entity).insert(history);
+			}
+		}
+	}
+}
+
+/// Records the [TransformHistory] of replicated entities on the server.
+///
+/// Requires the [super::ReplicationPlugin]. Insert a [LagCompensationConfig] first to change how far back it goes.
+#[derive(Debug, Default)]
+pub struct LagCompensationPlugin;
+
+impl Plugin for LagCompensationPlugin {
+	fn build(&self, app: &mut App) {
+		app.world.get_resource_or_insert_with(LagCompensationConfig::default);
+		app.world.get_resource_or_insert_with(InterpolationConfig::default);
+		app.add_system_to_stage(NetStage::Send, record_transforms);
+	}
+}
@@ -0,0 +1,187 @@
+//! Replication of components that refer to other entities.
+//!
+//! An [Entity] only means something in the world it was spawned in. Components registered with
+//! [super::ReplicationAppExt::replicate_mapped] are sent along with the [NetEntityId] of every entity they refer to,
+//! as listed by [MapNetEntities], and those references are mapped back to local entities through the
+//! [NetEntityRegistry] when received.
+//!
+//! A component that refers to an entity that hasn't arrived yet is kept in [UnresolvedEntities], and
+//! applied as soon as all the entities it refers to exist. References to entities that aren't replicated
+//! can't be mapped, and are received as [dangling].
+
+use bevy::{
+	prelude::*,
+	reflect::{FromReflect, TypeRegistryInternal},
+	utils::HashMap,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{messaging::NetEntityId, tick::NetTick, NetEntityRegistry, NetStage};
+
+use super::{
+	deserialize_component, insert_component, replication_id, serialize_component, ReplicationId, ReplicationRules,
+	ReplicationSystem,
+};
+
+pub use multiplayer_test_macros::MapNetEntities;
+
+/// Lists and maps the [Entity] references in a component.
+///
+/// Derive it and mark the fields holding entities with `#[net_entity]`. Those fields have to implement
+/// [MapNetEntities] as well, which [Entity], [Option] and [Vec] do.
+pub trait MapNetEntities {
+	/// Calls `f` with every referenced entity, in the same order as [MapNetEntities::map_net_entities].
+	fn net_entities(&self, f: &mut dyn FnMut(Entity));
+
+	/// Lets `f` replace every referenced entity.
+	fn map_net_entities(&mut self, f: &mut dyn FnMut(&mut Entity));
+}
+
+impl MapNetEntities for Entity {
+	fn net_entities(&self, f: &mut dyn FnMut(Entity)) {
+		f(*self)
+	}
+
+	fn map_net_entities(&mut self, f: &mut dyn FnMut(&mut Entity)) {
+		f(self)
+	}
+}
+
+impl<T: MapNetEntities> MapNetEntities for Option<T> {
+	fn net_entities(&self, f: &mut dyn FnMut(Entity)) {
+		if let Some(inner) = self {
+			inner.net_entities(f);
+		}
+	}
+
+	fn map_net_entities(&mut self, f: &mut dyn FnMut(&mut Entity)) {
+		if let Some(inner) = self {
+			inner.map_net_entities(f);
+		}
+	}
+}
+
+impl<T: MapNetEntities> MapNetEntities for Vec<T> {
+	fn net_entities(&self, f: &mut dyn FnMut(Entity)) {
+		for inner in self.iter() {
+			inner.net_entities(f);
+		}
+	}
+
+	fn map_net_entities(&mut self, f: &mut dyn FnMut(&mut Entity)) {
+		for inner in self.iter_mut() {
+			inner.map_net_entities(f);
+		}
+	}
+}
+
+/// What references to entities that aren't replicated are mapped to.
+pub fn dangling() -> Entity {
+	Entity::from_raw(u32::MAX)
+}
+
+/// A serialized component, and the [NetEntityId]s of the entities it refers to in order.
+#[derive(Serialize, Deserialize)]
+struct MappedData {
+	component: Vec<u8>,
+	/// [None] for entities that aren't replicated.
+	entities: Vec<Option<NetEntityId>>,
+}
+
+/// Received components that refer to entities that haven't arrived yet.
+#[derive(Resource, Debug, Default)]
+pub struct UnresolvedEntities(HashMap<(Entity, ReplicationId), (Vec<u8>, NetTick)>);
+
+impl UnresolvedEntities {
+	pub fn len(&self) -> usize {
+		self.0.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.0.is_empty()
+	}
+}
+
+pub(super) fn serialize_mapped<T>(world: &World, entity: Entity, registry: &TypeRegistryInternal) -> Option<Vec<u8>>
+where
+	T: Component + Reflect + MapNetEntities,
+{
+	let component = serialize_component::<T>(world, entity, registry)?;
+	let mut entities = Vec::new();
+	world.get::<T>(entity)?.net_entities(&mut |target| {
+		entities.push(world.get::<NetEntityId>(target).copied());
+	});
+	match postcard::to_stdvec(&MappedData { component, entities }) {
+		Ok(data) => Some(data),
+		Err(err) => {
+			warn!("Unable to serialize `{}`: {}", std::any::type_name::<T>(), err);
+			None
+		}
+	}
+}
+
+pub(super) fn apply_mapped<T>(world: &mut World, entity: Entity, data: &[u8], tick: NetTick)
+where
+	T: Component + FromReflect + MapNetEntities,
+{
+	let Ok(mapped) = postcard::from_bytes::<MappedData>(data) else {
+		warn!("Unable to deserialize a replicated `{}`.", std::any::type_name::<T>());
+		return;
+	};
+	let Some(mut component) = deserialize_component::<T>(world, &mapped.component) else {
+		return;
+	};
+	let key = (entity, replication_id(std::any::type_name::<T>()));
+	let registry = world.resource::<NetEntityRegistry>();
+	let resolved: Option<Vec<_>> = mapped
+		.entities
+		.iter()
+		.map(|net_id| match net_id {
+			Some(net_id) => registry
+				.get(*net_id)
+				.filter(|target| world.get_entity(*target).is_some()),
+			None => Some(dangling()),
+		})
+		.collect();
+	let Some(resolved) = resolved else {
+		world.resource_mut::<UnresolvedEntities>().0.insert(key, (data.to_vec(), tick));
+		return;
+	};
+	world.resource_mut::<UnresolvedEntities>().0.remove(&key);
+	let mut resolved = resolved.into_iter();
+	component.map_net_entities(&mut |target| {
+		if let Some(local) = resolved.next() {
+			*target = local;
+		}
+	});
+	insert_component(world, entity, component, tick);
+}
+
+pub(super) fn remove_mapped<T: Component>(world: &mut World, entity: Entity) {
+	let key = (entity, replication_id(std::any::type_name::<T>()));
+	world.resource_mut::<UnresolvedEntities>().0.remove(&key);
+	world.entity_mut(entity).remove::<T>();
+}
+
+/// Applies the parked components again, which parks them once more if they still can't be resolved.
+fn resolve_parked(world: &mut World) {
+	if world.resource::<UnresolvedEntities>().is_empty() {
+		return;
+	}
+	let parked = std::mem::take(&mut world.resource_mut::<UnresolvedEntities>().0);
+	world.resource_scope(|world, rules: Mut<ReplicationRules>| {
+		for ((entity, id), (data, tick)) in parked {
+			if world.get_entity(entity).is_none() {
+				continue;
+			}
+			if let Some(rule) = rules.rules.get(&id) {
+				(rule.apply)(world, entity, &data, tick);
+			}
+		}
+	});
+}
+
+pub(super) fn build(app: &mut App) {
+	app.init_resource::<UnresolvedEntities>()
+		.add_system_to_stage(NetStage::Receive, resolve_parked.after(ReplicationSystem::Apply));
+}
@@ -0,0 +1,822 @@
+//! Server-authoritative replication of entities through world snapshots.
+//!
+//! The server marks entities with [Replicated] and registers their components with
+//! [ReplicationAppExt::replicate]. At [ReplicationConfig::tick_rate] it builds a snapshot of all replicated
+//! entities, and sends every client the difference to the last snapshot that client acknowledged.
+//! Clients rebuild the full snapshot from that baseline and apply it to the entities mapped through
+//! the [NetEntityRegistry], spawning and despawning entities as needed. Links between replicated parents
+//! and children are replicated as well, see [hierarchy].
+//!
+//! Which entities each client receives is decided by [interest], and clients can be given [authority] over entities.
+//! The round trip time to each client is measured from snapshot acknowledgements, see [ReplicationServer::rtt].
+//! Global state kept in resources is replicated separately, see [resource]. Clients can spawn replicated entities
+//! ahead of the server from ids the server reserved for them, see [spawn].
+
+use std::{
+	any::TypeId,
+	collections::VecDeque,
+	sync::Arc,
+	ops::Range,
+	time::{Duration, Instant},
+};
+
+use bevy::{
+	prelude::*,
+	reflect::{
+		serde::{TypedReflectDeserializer, TypedReflectSerializer},
+		FromReflect, GetTypeRegistration, TypeRegistryInternal,
+	},
+	utils::{HashMap, HashSet},
+};
+use serde::de::DeserializeSeed;
+
+use crate::{
+	client::Client,
+	clock::ClockSyncPlugin,
+	connection::ConnectionId,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg, NetEntityId,
+	},
+	server::Server,
+	tick::NetTick,
+	NetEntityRegistry, NetStage, NetSystem,
+};
+
+use self::{
+	authority::LocalAuthority,
+	map_entities::MapNetEntities,
+	interest::{InterestChanged, InterestManagement},
+	interpolation::Interpolated,
+	prediction::Predicted,
+};
+
+pub mod authority;
+pub mod hierarchy;
+pub mod interest;
+pub mod interpolation;
+pub mod lag_compensation;
+pub mod map_entities;
+pub mod prediction;
+pub mod resource;
+pub mod spawn;
+
+/// Identifies a replicated component type on both sides, see [replication_id].
+pub type ReplicationId = u64;
+
+pub type SnapshotTick = u32;
+
+/// Stable identifier of a component type, so peers don't have to register components in the same order.
+pub fn replication_id(type_name: &str) -> ReplicationId {
+	// 64 bit FNV-1a.
+	type_name
+		.bytes()
+		.fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// Marks an entity on the server to be included in snapshots.
+///
+/// A [NetEntityId] is assigned to the entity when it is first replicated.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Replicated;
+
+#[derive(Resource, Debug, Clone)]
+pub struct ReplicationConfig {
+	/// Snapshots sent per second.
+	pub tick_rate: f64,
+	/// Snapshots kept per client as possible baselines. Clients that fall further behind get a full snapshot.
+	pub max_history: usize,
+	/// Number of [NetEntityId]s reserved for a client to spawn entities with at a time, see [ReservedIds].
+	/// More are reserved once the client used half of them.
+	pub reserved_ids: u32,
+}
+
+impl ReplicationConfig {
+	/// The time between snapshots.
+	///
+	/// Panics if [ReplicationConfig::tick_rate] isn't a positive number of snapshots per second.
+	pub fn step(&self) -> Duration {
+		assert!(
+			self.tick_rate > 0.0 && self.tick_rate.is_finite(),
+			"`ReplicationConfig::tick_rate` has to be positive, but is {}.",
+			self.tick_rate
+		);
+		Duration::from_secs_f64(1.0 / self.tick_rate)
+	}
+}
+
+impl Default for ReplicationConfig {
+	fn default() -> Self {
+		Self {
+			tick_rate: 20.0,
+			max_history: 32,
+			reserved_ids: 256,
+		}
+	}
+}
+
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct ComponentData {
+	pub id: ReplicationId,
+	pub data: Vec<u8>,
+}
+
+/// The components of an entity that differ from the baseline.
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct EntityDelta {
+	pub net_id: NetEntityId,
+	pub changed: Vec<ComponentData>,
+	pub removed: Vec<ReplicationId>,
+}
+
+/// The last input of one input type the server processed, see [prediction].
+#[derive(Reflect, FromReflect, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputAck {
+	/// The [replication_id] of the input type.
+	pub input: ReplicationId,
+	pub tick: NetTick,
+}
+
+/// The replicated world at `tick`, encoded as the difference to the snapshot at `baseline`.
+///
+/// Without a baseline the snapshot contains every replicated entity.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct Snapshot {
+	pub tick: SnapshotTick,
+	pub baseline: Option<SnapshotTick>,
+	pub entities: Vec<EntityDelta>,
+	pub despawned: Vec<NetEntityId>,
+	/// The last input of each input type of the client the server processed, see [prediction].
+	pub input_acks: Vec<InputAck>,
+}
+
+/// Sent by clients once they have applied a [Snapshot], so it can be used as baseline.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct SnapshotAck {
+	pub tick: SnapshotTick,
+}
+
+/// A range of [NetEntityId] indices the server reserved for a client, sent before its first [Snapshot],
+/// and again whenever the client is running out of them.
+///
+/// The client allocates from it with [NetEntityRegistry::register_reserved], see [spawn].
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct ReservedIds {
+	pub start: u32,
+	pub end: u32,
+}
+
+pub type EntityState = HashMap<ReplicationId, Vec<u8>>;
+
+/// The serialized components of every replicated entity at one tick.
+#[derive(Debug, Clone, Default)]
+pub struct WorldState(HashMap<NetEntityId, EntityState>);
+
+impl WorldState {
+	/// The changes that turn `baseline` into `self`.
+	pub fn delta(&self, baseline: &WorldState) -> (Vec<EntityDelta>, Vec<NetEntityId>) {
+		let mut entities = Vec::new();
+		for (net_id, components) in self.0.iter() {
+			let old = baseline.0.get(net_id);
+			let changed: Vec<_> = components
+				.iter()
+				.filter(|(id, data)| old.and_then(|old| old.get(*id)) != Some(*data))
+				.map(|(id, data)| ComponentData {
+					id: *id,
+					data: data.clone(),
+				})
+				.collect();
+			let removed: Vec<_> = old
+				.into_iter()
+				.flat_map(|old| old.keys())
+				.filter(|id| !components.contains_key(*id))
+				.copied()
+				.collect();
+			if !changed.is_empty() || !removed.is_empty() || old.is_none() {
+				entities.push(EntityDelta {
+					net_id: *net_id,
+					changed,
+					removed,
+				});
+			}
+		}
+		let despawned = baseline.0.keys().filter(|net_id| !self.0.contains_key(*net_id)).copied().collect();
+		(entities, despawned)
+	}
+
+	/// Applies a delta created by [WorldState::delta].
+	pub fn apply(&mut self, entities: Vec<EntityDelta>, despawned: &[NetEntityId]) {
+		for delta in entities {
+			let components = self.0.entry(delta.net_id).or_default();
+			for id in delta.removed {
+				components.remove(&id);
+			}
+			for ComponentData { id, data } in delta.changed {
+				components.insert(id, data);
+			}
+		}
+		for net_id in despawned {
+			self.0.remove(net_id);
+		}
+	}
+
+	/// Only the entities in `keep`.
+	pub fn filter(&self, keep: &HashSet<NetEntityId>) -> WorldState {
+		Self(
+			self.0
+				.iter()
+				.filter(|(net_id, _)| keep.contains(*net_id))
+				.map(|(net_id, components)| (*net_id, components.clone()))
+				.collect(),
+		)
+	}
+
+	pub fn get(&self, net_id: &NetEntityId) -> Option<&EntityState> {
+		self.0.get(net_id)
+	}
+}
+
+#[derive(Clone, Copy)]
+struct ComponentRule {
+	type_name: &'static str,
+	serialize: fn(&World, Entity, &TypeRegistryInternal) -> Option<Vec<u8>>,
+	/// Also gets the server's tick the data is from.
+	apply: fn(&mut World, Entity, &[u8], NetTick),
+	remove: fn(&mut World, Entity),
+}
+
+/// The components registered with [ReplicationAppExt::replicate].
+#[derive(Resource, Default)]
+pub struct ReplicationRules {
+	rules: HashMap<ReplicationId, ComponentRule>,
+}
+
+impl ReplicationRules {
+	pub fn type_name(&self, id: ReplicationId) -> Option<&'static str> {
+		self.rules.get(&id).map(|rule| rule.type_name)
+	}
+}
+
+fn serialize_component<T: Component + Reflect>(world: &World, entity: Entity, registry: &TypeRegistryInternal) -> Option<Vec<u8>> {
+	serialize_reflect::<T>(world.get::<T>(entity)?, registry)
+}
+
+fn deserialize_component<T: FromReflect>(world: &World, data: &[u8]) -> Option<T> {
+	deserialize_reflect(&world.resource::<AppTypeRegistry>().read(), data)
+}
+
+/// Serializes a replicated value of type `T` with postcard.
+pub(crate) fn serialize_reflect<T: Reflect>(value: &T, registry: &TypeRegistryInternal) -> Option<Vec<u8>> {
+	match postcard::to_stdvec(&TypedReflectSerializer::new(value, registry)) {
+		Ok(data) => Some(data),
+		Err(err) => {
+			warn!("Unable to serialize `{}`: {}", std::any::type_name::<T>(), err);
+			None
+		}
+	}
+}
+
+/// Deserializes a value serialized by [serialize_reflect].
+pub(crate) fn deserialize_reflect<T: FromReflect>(type_registry: &TypeRegistryInternal, data: &[u8]) -> Option<T> {
+	let Some(registration) = type_registry.get(TypeId::of::<T>()) else {
+		warn!("`{}` is replicated but not registered.", std::any::type_name::<T>());
+		return None;
+	};
+	let reflected = TypedReflectDeserializer::new(registration, type_registry)
+		.deserialize(&mut postcard::Deserializer::from_bytes(data));
+	let value = reflected.ok().and_then(|reflected| T::from_reflect(reflected.as_ref()));
+	if value.is_none() {
+		warn!("Unable to deserialize a replicated `{}`.", std::any::type_name::<T>());
+	}
+	value
+}
+
+/// Inserts a received component, or buffers it if the entity is [Interpolated].
+fn insert_component<T: Component>(world: &mut World, entity: Entity, component: T, tick: NetTick) {
+	match world.get_mut::<Interpolated<T>>(entity) {
+		Some(mut interpolated) => interpolated.push(tick, component),
+		None => {
+			world.entity_mut(entity).insert(component);
+		}
+	}
+}
+
+fn apply_component<T: Component + FromReflect>(world: &mut World, entity: Entity, data: &[u8], tick: NetTick) {
+	if let Some(component) = deserialize_component::<T>(world, data) {
+		insert_component(world, entity, component, tick);
+	}
+}
+
+fn remove_component<T: Component>(world: &mut World, entity: Entity) {
+	world.entity_mut(entity).remove::<T>();
+}
+
+/// Server side bookkeeping of the snapshots sent to each client.
+#[derive(Default)]
+struct ClientSnapshots {
+	acked: Option<SnapshotTick>,
+	history: VecDeque<(SnapshotTick, Arc<WorldState>)>,
+	/// When each snapshot in `history` was sent.
+	sent: VecDeque<(SnapshotTick, Instant)>,
+	/// Smoothed round trip time, from sending a snapshot to receiving its acknowledgement.
+	rtt: Option<Duration>,
+	/// The entities in the client's interest set.
+	relevant: HashSet<NetEntityId>,
+	input_acks: Vec<InputAck>,
+	sent_input_acks: Vec<InputAck>,
+	/// The [NetEntityId] indices reserved for the client.
+	reserved: Vec<Range<u32>>,
+	/// The part of `reserved` the client hasn't spawned entities with yet.
+	unused: VecDeque<Range<u32>>,
+}
+
+impl ClientSnapshots {
+	fn unused_ids(&self) -> u32 {
+		self.unused.iter().map(|range| range.end - range.start).sum()
+	}
+}
+
+#[derive(Resource)]
+pub struct ReplicationServer {
+	timer: Timer,
+	tick: SnapshotTick,
+	clients: HashMap<ConnectionId, ClientSnapshots>,
+}
+
+impl ReplicationServer {
+	pub fn new(config: &ReplicationConfig) -> Self {
+		Self {
+			timer: Timer::new(config.step(), TimerMode::Repeating),
+			tick: 0,
+			clients: HashMap::default(),
+		}
+	}
+
+	/// The tick of the last snapshot that was built.
+	pub fn tick(&self) -> SnapshotTick {
+		self.tick
+	}
+
+	/// The last snapshot `client` acknowledged.
+	pub fn acked(&self, client: ConnectionId) -> Option<SnapshotTick> {
+		self.clients.get(&client)?.acked
+	}
+
+	/// The smoothed round trip time to `client`, once it acknowledged a snapshot.
+	pub fn rtt(&self, client: ConnectionId) -> Option<Duration> {
+		self.clients.get(&client)?.rtt
+	}
+
+	/// Whether `id` lies in a range reserved for `client`, so `client` may have spawned an entity with it.
+	pub fn is_reserved_for(&self, client: ConnectionId, id: NetEntityId) -> bool {
+		let Some(client) = self.clients.get(&client) else {
+			return false;
+		};
+		client.reserved.iter().any(|range| range.contains(&id.index))
+	}
+
+	/// Uses up `id` from the ids reserved for `client`, along with the ids before it, as clients allocate them in
+	/// order.
+	///
+	/// Returns whether `client` could have spawned an entity with `id`, and didn't use it before.
+	pub(crate) fn use_reserved(&mut self, client: ConnectionId, id: NetEntityId) -> bool {
+		let Some(client) = self.clients.get_mut(&client) else {
+			return false;
+		};
+		let Some(i) = client.unused.iter().position(|range| range.contains(&id.index)) else {
+			return false;
+		};
+		if id.generation != 0 {
+			return false;
+		}
+		client.unused.drain(..i);
+		client.unused[0].start = id.index + 1;
+		true
+	}
+
+	/// The clients snapshots are sent to.
+	pub fn clients(&self) -> impl Iterator<Item = ConnectionId> + '_ {
+		self.clients.keys().copied()
+	}
+
+	/// Marks the input of type `I` `client` sent at `tick` as processed, which is reported back in the next snapshot.
+	pub fn ack_input<I: 'static>(&mut self, client: ConnectionId, tick: NetTick) {
+		let input = replication_id(std::any::type_name::<I>());
+		let client = self.clients.entry(client).or_default();
+		match client.input_acks.iter_mut().find(|ack| ack.input == input) {
+			Some(ack) => ack.tick = ack.tick.max(tick),
+			None => client.input_acks.push(InputAck { input, tick }),
+		}
+	}
+}
+
+/// Client side state of the snapshots received from the server.
+#[derive(Resource, Default)]
+pub struct ReplicationClient {
+	applied: WorldState,
+	history: VecDeque<(SnapshotTick, WorldState)>,
+	input_acks: Vec<InputAck>,
+	server_tick: Option<NetTick>,
+}
+
+impl ReplicationClient {
+	/// The tick of the last applied snapshot.
+	pub fn tick(&self) -> Option<SnapshotTick> {
+		self.history.back().map(|(tick, _)| *tick)
+	}
+
+	/// The state of the replicated world as last applied.
+	pub fn applied(&self) -> &WorldState {
+		&self.applied
+	}
+
+	/// The server's [NetTick] when it sent the applied snapshot.
+	pub fn server_tick(&self) -> Option<NetTick> {
+		self.server_tick
+	}
+
+	/// The last of our inputs of type `I` the server had processed in the applied snapshot.
+	pub fn input_ack<I: 'static>(&self) -> Option<NetTick> {
+		let input = replication_id(std::any::type_name::<I>());
+		self.input_acks.iter().find(|ack| ack.input == input).map(|ack| ack.tick)
+	}
+}
+
+#[derive(SystemLabel)]
+pub enum ReplicationSystem {
+	/// Applies received snapshots on the client.
+	Apply,
+	/// Builds and sends snapshots on the server.
+	Send,
+}
+
+/// Replicates entities from a [Server] to its [Client]s, both using [NetMsg]s.
+///
+/// Add it after [crate::MultiplayerPlugin] on both sides, and insert a [ReplicationConfig] first to change the tick rate.
+#[derive(Debug, Default)]
+pub struct ReplicationPlugin;
+
+impl Plugin for ReplicationPlugin {
+	fn build(&self, app: &mut App) {
+		let config = app.world.get_resource_or_insert_with(ReplicationConfig::default).clone();
+		// Field types have to be registered as well for the messages to be deserialized.
+		app.register_type::<NetEntityId>()
+			.register_type::<Vec<u8>>()
+			.register_type::<Vec<NetEntityId>>()
+			.register_type::<Vec<ReplicationId>>()
+			.register_type::<ComponentData>()
+			.register_type::<Vec<ComponentData>>()
+			.register_type::<EntityDelta>()
+			.register_type::<Vec<EntityDelta>>()
+			.register_type::<Option<SnapshotTick>>()
+			.register_type::<InputAck>()
+			.register_type::<Vec<InputAck>>()
+			.add_net_message::<Snapshot>()
+			.add_net_message::<SnapshotAck>()
+			.add_net_message::<ReservedIds>()
+			.init_resource::<ReplicationRules>()
+			.init_resource::<ReplicationClient>()
+			.init_resource::<InterestManagement>()
+			.add_event::<InterestChanged>()
+			.insert_resource(ReplicationServer::new(&config))
+			.add_system_to_stage(NetStage::Receive, receive_acks.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Receive, receive_reserved_ids.after(NetSystem::Dispatch))
+			.add_system_to_stage(
+				NetStage::Receive,
+				apply_snapshots.label(ReplicationSystem::Apply).after(NetSystem::Dispatch),
+			)
+			.add_system_to_stage(NetStage::Send, send_snapshots.label(ReplicationSystem::Send));
+		// Interpolation renders at the server's estimated tick.
+		app.add_plugin(ClockSyncPlugin);
+		authority::build(app, &config);
+		hierarchy::build(app);
+		map_entities::build(app);
+		resource::build(app);
+		spawn::build(app);
+	}
+}
+
+pub trait ReplicationAppExt {
+	/// Includes component `T` in snapshots.
+	///
+	/// Requires the [ReplicationPlugin], and has to be called on both sides.
+	fn replicate<T>(&mut self) -> &mut Self
+	where
+		T: Component + Reflect + FromReflect + GetTypeRegistration;
+
+	/// Includes component `T` in snapshots, mapping the entities it refers to, see [map_entities].
+	///
+	/// Requires the [ReplicationPlugin], and has to be called on both sides.
+	fn replicate_mapped<T>(&mut self) -> &mut Self
+	where
+		T: Component + Reflect + FromReflect + GetTypeRegistration + MapNetEntities;
+}
+
+impl ReplicationAppExt for App {
+	fn replicate<T>(&mut self) -> &mut Self
+	where
+		T: Component + Reflect + FromReflect + GetTypeRegistration,
+	{
+		let type_name = std::any::type_name::<T>();
+		self.register_type::<T>();
+		self.world.resource_mut::<ReplicationRules>().rules.insert(
+			replication_id(type_name),
+			ComponentRule {
+				type_name,
+				serialize: serialize_component::<T>,
+				apply: apply_component::<T>,
+				remove: remove_component::<T>,
+			},
+		);
+		self
+	}
+
+	fn replicate_mapped<T>(&mut self) -> &mut Self
+	where
+		T: Component + Reflect + FromReflect + GetTypeRegistration + MapNetEntities,
+	{
+		let type_name = std::any::type_name::<T>();
+		self.register_type::<T>().register_type::<Entity>();
+		self.world.resource_mut::<ReplicationRules>().rules.insert(
+			replication_id(type_name),
+			ComponentRule {
+				type_name,
+				serialize: map_entities::serialize_mapped::<T>,
+				apply: map_entities::apply_mapped::<T>,
+				remove: map_entities::remove_mapped::<T>,
+			},
+		);
+		self
+	}
+}
+
+/// Serializes the replicated components of `entity`.
+pub(crate) fn serialize_entity(world: &World, entity: Entity) -> EntityState {
+	let rules = world.resource::<ReplicationRules>();
+	let type_registry = world.resource::<AppTypeRegistry>().read();
+	rules
+		.rules
+		.iter()
+		.filter_map(|(id, rule)| Some((*id, (rule.serialize)(world, entity, &type_registry)?)))
+		.collect()
+}
+
+/// Overwrites the replicated components of `entity` with `state`, removing those it doesn't contain.
+pub(crate) fn apply_entity(world: &mut World, entity: Entity, state: &EntityState, tick: NetTick) {
+	world.resource_scope(|world, rules: Mut<ReplicationRules>| {
+		for (id, rule) in rules.rules.iter() {
+			match state.get(id) {
+				Some(data) => (rule.apply)(world, entity, data, tick),
+				None => (rule.remove)(world, entity),
+			}
+		}
+	});
+}
+
+/// Builds the current [WorldState], assigning a [NetEntityId] to newly replicated entities.
+fn build_state(world: &mut World) -> WorldState {
+	let mut unassigned = world.query_filtered::<Entity, (With<Replicated>, Without<NetEntityId>)>();
+	let unassigned: Vec<_> = unassigned.iter(world).collect();
+	for entity in unassigned {
+		let net_id = world.resource::<NetEntityRegistry>().register(entity);
+		world.entity_mut(entity).insert(net_id);
+	}
+
+	let mut query = world.query_filtered::<(Entity, &NetEntityId), With<Replicated>>();
+	let world = &*world;
+	let mut state = WorldState::default();
+	for (entity, net_id) in query.iter(world) {
+		state.0.insert(*net_id, serialize_entity(world, entity));
+	}
+	state
+}
+
+fn send_snapshots(world: &mut World) {
+	if !world.contains_resource::<Server<NetMsg, NetMsg>>() {
+		return;
+	}
+	let delta = world.resource::<Time>().delta();
+	if !world.resource_mut::<ReplicationServer>().timer.tick(delta).just_finished() {
+		return;
+	}
+	let state = build_state(world);
+	let config = world.resource::<ReplicationConfig>();
+	let (max_history, reserved_ids) = (config.max_history, config.reserved_ids);
+	let connections: Vec<_> = world
+		.resource::<Server<NetMsg, NetMsg>>()
+		.connections
+		.iter()
+		.map(|conn| conn.uuid)
+		.collect();
+	let mut relevant = interest::relevant_entities(world, &connections);
+
+	world.resource_scope(|world, mut replication: Mut<ReplicationServer>| {
+		replication.tick += 1;
+		let tick = replication.tick;
+		let registry = world.resource::<NetEntityRegistry>();
+		replication.clients.retain(|id, client| {
+			if connections.contains(id) {
+				return true;
+			}
+			for range in client.reserved.drain(..) {
+				registry.release(range);
+			}
+			false
+		});
+		let mut changes = Vec::new();
+		let server = world.resource::<Server<NetMsg, NetMsg>>();
+		for conn in server.connections.iter() {
+			let client = replication.clients.entry(conn.uuid).or_default();
+			// Reserve more before the client runs out, so it never has to wait for them.
+			if reserved_ids > 0 && client.unused_ids() <= reserved_ids / 2 {
+				let range = world.resource::<NetEntityRegistry>().reserve(reserved_ids);
+				let msg = ReservedIds {
+					start: range.start,
+					end: range.end,
+				};
+				if let Err(err) = conn.send_blocking(NetMsg::new(msg)) {
+					warn!("Unable to send the reserved ids to {}: {}", conn.uuid, err);
+					world.resource::<NetEntityRegistry>().release(range);
+					continue;
+				}
+				client.reserved.push(range.clone());
+				client.unused.push_back(range);
+			}
+			let relevant = relevant.remove(&conn.uuid).unwrap_or_default();
+			changes.extend(client.relevant.symmetric_difference(&relevant).map(|net_id| InterestChanged {
+				connection: conn.uuid,
+				net_id: *net_id,
+				relevant: relevant.contains(net_id),
+			}));
+			let state = Arc::new(state.filter(&relevant));
+			client.relevant = relevant;
+
+			let baseline = client
+				.acked
+				.and_then(|acked| client.history.iter().find(|(tick, _)| *tick == acked));
+			let (entities, despawned) = match baseline {
+				Some((_, baseline)) => state.delta(baseline),
+				None => state.delta(&WorldState::default()),
+			};
+			let unchanged = entities.is_empty() && despawned.is_empty() && client.input_acks == client.sent_input_acks;
+			if baseline.is_some() && unchanged {
+				// The acknowledged snapshot is still up to date.
+				continue;
+			}
+			let snapshot = Snapshot {
+				tick,
+				baseline: baseline.map(|(tick, _)| *tick),
+				entities,
+				despawned,
+				input_acks: client.input_acks.clone(),
+			};
+			if let Err(err) = conn.send_blocking(NetMsg::new(snapshot)) {
+				warn!("Unable to send a snapshot to {}: {}", conn.uuid, err);
+				continue;
+			}
+			client.sent_input_acks = client.input_acks.clone();
+			client.history.push_back((tick, state));
+			client.sent.push_back((tick, Instant::now()));
+			if client.history.len() > max_history {
+				client.history.pop_front();
+				client.sent.pop_front();
+			}
+		}
+		world.resource_mut::<Events<InterestChanged>>().extend(changes);
+	});
+}
+
+fn receive_acks(mut acks: EventReader<Received<SnapshotAck>>, mut replication: ResMut<ReplicationServer>) {
+	for Received { msg, from, .. } in acks.iter() {
+		let Some(client) = replication.clients.get_mut(from) else {
+			continue;
+		};
+		if let Some((_, sent)) = client.sent.iter().find(|(tick, _)| *tick == msg.tick) {
+			let sample = sent.elapsed();
+			client.rtt = Some(match client.rtt {
+				Some(rtt) => rtt.mul_f64(0.875) + sample.mul_f64(0.125),
+				None => sample,
+			});
+		}
+		if client.acked < Some(msg.tick) {
+			client.acked = Some(msg.tick);
+			client.history.retain(|(tick, _)| *tick >= msg.tick);
+			client.sent.retain(|(tick, _)| *tick >= msg.tick);
+		}
+	}
+}
+
+fn receive_reserved_ids(
+	mut reserved: EventReader<Received<ReservedIds>>,
+	registry: Res<NetEntityRegistry>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let Some(server) = client.as_deref().and_then(|client| client.as_ref()).map(|conn| conn.uuid) else {
+		reserved.clear();
+		return;
+	};
+	for Received { msg, .. } in reserved.iter().filter(|received| received.from == server) {
+		registry.add_reserved(msg.start..msg.end);
+	}
+}
+
+fn apply_snapshots(world: &mut World) {
+	let server = world
+		.get_resource::<Client<NetMsg, NetMsg>>()
+		.and_then(|client| client.as_ref())
+		.map(|conn| conn.uuid);
+	let snapshots: Vec<_> = world
+		.resource_mut::<Events<Received<Snapshot>>>()
+		.drain()
+		.filter(|received| Some(received.from) == server)
+		.map(|received| (received.msg, received.tick))
+		.collect();
+	if snapshots.is_empty() {
+		return;
+	}
+	let max_history = world.resource::<ReplicationConfig>().max_history;
+
+	world.resource_scope(|world, mut replication: Mut<ReplicationClient>| {
+		for (snapshot, server_tick) in snapshots {
+			let mut state = match snapshot.baseline {
+				Some(baseline) => match replication.history.iter().find(|(tick, _)| *tick == baseline) {
+					Some((_, state)) => state.clone(),
+					None => {
+						warn!("Snapshot {} is based on unknown snapshot {}.", snapshot.tick, baseline);
+						continue;
+					}
+				},
+				None => WorldState::default(),
+			};
+			state.apply(snapshot.entities, &snapshot.despawned);
+			apply_state(world, &replication.applied, &state, server_tick);
+			replication.applied = state.clone();
+			replication.server_tick = Some(server_tick);
+			replication.input_acks = snapshot.input_acks;
+
+			if let Some(baseline) = snapshot.baseline {
+				replication.history.retain(|(tick, _)| *tick >= baseline);
+			}
+			replication.history.push_back((snapshot.tick, state));
+			if replication.history.len() > max_history {
+				replication.history.pop_front();
+			}
+
+			let client = world.get_resource::<Client<NetMsg, NetMsg>>();
+			if let Some(conn) = client.and_then(|client| client.as_ref()) {
+				if let Err(err) = conn.send_blocking(NetMsg::new(SnapshotAck { tick: snapshot.tick })) {
+					warn!("Unable to acknowledge snapshot {}: {}", snapshot.tick, err);
+				}
+			}
+		}
+	});
+}
+
+/// Updates the world from the `old` state to the `new` one.
+fn apply_state(world: &mut World, old: &WorldState, new: &WorldState, tick: NetTick) {
+	world.resource_scope(|world, rules: Mut<ReplicationRules>| {
+		for (net_id, components) in new.0.iter() {
+			let entity = match world.resource::<NetEntityRegistry>().get(*net_id) {
+				Some(entity) if world.get_entity(entity).is_some() => entity,
+				_ => {
+					let entity = world.spawn(*net_id).id();
+					world.resource::<NetEntityRegistry>().insert(*net_id, entity);
+					entity
+				}
+			};
+			if world.get::<Predicted>(entity).is_some() {
+				// Corrected by prediction::reconcile instead.
+				continue;
+			}
+			if world.get::<LocalAuthority>(entity).is_some() {
+				// Written by us, the server only forwards our updates.
+				continue;
+			}
+			let old = old.0.get(net_id);
+			for (id, data) in components.iter() {
+				if old.and_then(|old| old.get(id)) == Some(data) {
+					continue;
+				}
+				match rules.rules.get(id) {
+					Some(rule) => (rule.apply)(world, entity, data, tick),
+					None => warn!("Received unknown replicated component {:#x}.", id),
+				}
+			}
+			for id in old.into_iter().flat_map(|old| old.keys()) {
+				if components.contains_key(id) {
+					continue;
+				}
+				if let Some(rule) = rules.rules.get(id) {
+					(rule.remove)(world, entity);
+				}
+			}
+		}
+		for net_id in old.0.keys().filter(|net_id| !new.0.contains_key(*net_id)) {
+			if let Some((_, entity)) = world.resource::<NetEntityRegistry>().deregister(*net_id) {
+				if let Some(entity) = world.get_entity_mut(entity) {
+					entity.despawn();
+				}
+			}
+		}
+	});
+}
@@ -0,0 +1,277 @@
+//! Client-side prediction of [Predicted] entities, with reconciliation against the server.
+//!
+//! Inputs recorded in the client's [InputBuffer] are sent to the server and applied to every predicted
+//! entity right away. Snapshots report the last input of each type the server processed; if the server's
+//! state of a predicted entity disagrees with what the client predicted at that input, the entity is rolled
+//! back to the server's state and the inputs the server hasn't processed yet are replayed on top of it.
+//!
+//! The server reads the inputs as [Received]`<`[ClientInput]`>` events and applies them in [CoreStage::Update].
+//! They are acknowledged automatically afterwards, in [NetStage::Send] before the next snapshot is built.
+//!
+//! Every input type has its own [InputBuffer] and [PredictionHistory].
+
+use std::{collections::VecDeque, marker::PhantomData};
+
+use bevy::{
+	ecs::event::ManualEventReader,
+	prelude::*,
+	reflect::{FromReflect, GetTypeRegistration},
+	utils::HashMap,
+};
+
+use crate::{
+	client::Client,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg, NetEntityId,
+	},
+	tick::NetTick,
+	NetStage,
+};
+
+use super::{
+	apply_entity, serialize_entity, EntityState, ReplicationClient, ReplicationServer, ReplicationSystem, SnapshotTick,
+};
+
+/// Marks an entity on the client whose replicated components are predicted from local inputs.
+///
+/// Snapshots don't overwrite predicted entities, they are corrected through reconciliation instead.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct Predicted;
+
+/// An input of type `I` the client applied at `tick`.
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct ClientInput<I: Reflect + FromReflect + GetTypeRegistration> {
+	pub tick: NetTick,
+	pub input: I,
+}
+
+/// The client's inputs that the server hasn't processed yet.
+#[derive(Resource, Debug)]
+pub struct InputBuffer<I> {
+	inputs: VecDeque<(NetTick, I)>,
+	/// The last input that was sent and predicted.
+	sent: Option<NetTick>,
+}
+
+impl<I> Default for InputBuffer<I> {
+	fn default() -> Self {
+		Self {
+			inputs: VecDeque::new(),
+			sent: None,
+		}
+	}
+}
+
+impl<I> InputBuffer<I> {
+	/// Records the input for `tick`, which is sent and predicted in [NetStage::Send].
+	///
+	/// Inputs for ticks that were already sent are ignored.
+	pub fn record(&mut self, tick: NetTick, input: I) {
+		if self.sent >= Some(tick) || matches!(self.inputs.back(), Some((last, _)) if *last >= tick) {
+			return;
+		}
+		self.inputs.push_back((tick, input));
+	}
+
+	/// The inputs that haven't been acknowledged by the server, in order.
+	pub fn unacknowledged(&self) -> impl Iterator<Item = &(NetTick, I)> {
+		self.inputs.iter()
+	}
+}
+
+/// The predicted states of [Predicted] entities after each input of type `I`.
+#[derive(Resource, Debug)]
+pub struct PredictionHistory<I> {
+	states: VecDeque<(NetTick, HashMap<NetEntityId, EntityState>)>,
+	reconciled: Option<SnapshotTick>,
+	rollbacks: u32,
+	_m: PhantomData<I>,
+}
+
+impl<I> Default for PredictionHistory<I> {
+	fn default() -> Self {
+		Self {
+			states: VecDeque::new(),
+			reconciled: None,
+			rollbacks: 0,
+			_m: PhantomData,
+		}
+	}
+}
+
+impl<I> PredictionHistory<I> {
+	/// The latest predicted state at or before `tick`.
+	fn at(&self, tick: NetTick) -> Option<&HashMap<NetEntityId, EntityState>> {
+		self.states
+			.iter()
+			.rev()
+			.find(|(predicted, _)| *predicted <= tick)
+			.map(|(_, states)| states)
+	}
+
+	fn record(&mut self, tick: NetTick, net_id: NetEntityId, state: EntityState) {
+		match self.states.iter().position(|(recorded, _)| *recorded >= tick) {
+			Some(i) if self.states[i].0 == tick => {
+				self.states[i].1.insert(net_id, state);
+			}
+			Some(i) => self.states.insert(i, (tick, HashMap::from_iter([(net_id, state)]))),
+			None => self.states.push_back((tick, HashMap::from_iter([(net_id, state)]))),
+		}
+	}
+
+	/// Number of times a predicted entity was rolled back because the server disagreed.
+	pub fn rollbacks(&self) -> u32 {
+		self.rollbacks
+	}
+}
+
+/// Applies an input to a predicted entity.
+pub type PredictFn<I> = Box<dyn Fn(&mut World, Entity, &I) + Send + Sync>;
+
+#[derive(Resource)]
+struct Prediction<I>(PredictFn<I>);
+
+pub trait PredictionAppExt {
+	/// Predicts [Predicted] entities on the client by applying inputs of type `I` with `apply`.
+	///
+	/// Has to be called on both sides, and requires the [super::ReplicationPlugin].
+	fn add_prediction<I, F>(&mut self, apply: F) -> &mut Self
+	where
+		I: Reflect + FromReflect + GetTypeRegistration + Clone,
+		F: Fn(&mut World, Entity, &I) + Send + Sync + 'static;
+}
+
+impl PredictionAppExt for App {
+	fn add_prediction<I, F>(&mut self, apply: F) -> &mut Self
+	where
+		I: Reflect + FromReflect + GetTypeRegistration + Clone,
+		F: Fn(&mut World, Entity, &I) + Send + Sync + 'static,
+	{
+		self.register_type::<I>()
+			.add_net_message::<ClientInput<I>>()
+			.init_resource::<InputBuffer<I>>()
+			.init_resource::<PredictionHistory<I>>()
+			.insert_resource(Prediction::<I>(Box::new(apply)))
+			.add_system_to_stage(NetStage::Send, acknowledge_inputs::<I>.before(ReplicationSystem::Send))
+			.add_system_to_stage(NetStage::Receive, reconcile::<I>.after(ReplicationSystem::Apply))
+			.add_system_to_stage(NetStage::Send, predict::<I>)
+	}
+}
+
+/// Acknowledges the inputs received this frame, once they have been applied.
+///
+/// Exclusive like `send_snapshots`, so it can be ordered before it.
+fn acknowledge_inputs<I: Reflect + FromReflect + GetTypeRegistration>(
+	world: &mut World,
+	mut reader: Local<ManualEventReader<Received<ClientInput<I>>>>,
+) {
+	let inputs = world.resource::<Events<Received<ClientInput<I>>>>();
+	let acks: Vec<_> = reader.iter(inputs).map(|Received { msg, from, .. }| (*from, msg.tick)).collect();
+	let mut replication = world.resource_mut::<ReplicationServer>();
+	for (from, tick) in acks {
+		replication.ack_input::<I>(from, tick);
+	}
+}
+
+fn predicted_entities(world: &mut World) -> Vec<(Entity, NetEntityId)> {
+	let mut query = world.query_filtered::<(Entity, &NetEntityId), With<Predicted>>();
+	query.iter(world).map(|(entity, net_id)| (entity, *net_id)).collect()
+}
+
+/// Sends and applies the newly recorded inputs.
+fn predict<I>(world: &mut World)
+where
+	I: Reflect + FromReflect + GetTypeRegistration + Clone,
+{
+	let Some(conn) = world.get_resource::<Client<NetMsg, NetMsg>>() else {
+		return;
+	};
+	let Some(conn) = conn.as_ref() else {
+		return;
+	};
+	let buffer = world.resource::<InputBuffer<I>>();
+	let new: Vec<_> = buffer
+		.inputs
+		.iter()
+		.filter(|(tick, _)| buffer.sent < Some(*tick))
+		.cloned()
+		.collect();
+	for (tick, input) in new.iter() {
+		let msg = ClientInput {
+			tick: *tick,
+			input: input.clone(),
+		};
+		if let Err(err) = conn.send_blocking(NetMsg::new(msg)) {
+			warn!("Unable to send the input for {}: {}", tick, err);
+		}
+	}
+	let Some((last, _)) = new.last() else {
+		return;
+	};
+	world.resource_mut::<InputBuffer<I>>().sent = Some(*last);
+
+	let entities = predicted_entities(world);
+	world.resource_scope(|world, prediction: Mut<Prediction<I>>| {
+		for (tick, input) in new.iter() {
+			for (entity, net_id) in entities.iter() {
+				(prediction.0)(world, *entity, input);
+				let state = serialize_entity(world, *entity);
+				world.resource_mut::<PredictionHistory<I>>().record(*tick, *net_id, state);
+			}
+		}
+	});
+}
+
+/// Rolls back predicted entities the server disagrees with, and replays the unacknowledged inputs.
+fn reconcile<I>(world: &mut World)
+where
+	I: Reflect + FromReflect + GetTypeRegistration + Clone,
+{
+	let replication = world.resource::<ReplicationClient>();
+	let (snapshot, Some(ack)) = (replication.tick(), replication.input_ack::<I>()) else {
+		return;
+	};
+	let server_tick = replication.server_tick().unwrap_or_default();
+	let mut history = world.resource_mut::<PredictionHistory<I>>();
+	if history.reconciled == snapshot {
+		return;
+	}
+	history.reconciled = snapshot;
+
+	let entities = predicted_entities(world);
+	let replication = world.resource::<ReplicationClient>();
+	let history = world.resource::<PredictionHistory<I>>();
+	let mispredicted: Vec<_> = entities
+		.into_iter()
+		.filter_map(|(entity, net_id)| {
+			let server = replication.applied().get(&net_id)?;
+			let predicted = history.at(ack).and_then(|states| states.get(&net_id));
+			(predicted != Some(server)).then(|| (entity, net_id, server.clone()))
+		})
+		.collect();
+
+	let mut buffer = world.resource_mut::<InputBuffer<I>>();
+	buffer.inputs.retain(|(tick, _)| *tick > ack);
+	let replay: Vec<_> = buffer.inputs.iter().filter(|(tick, _)| buffer.sent >= Some(*tick)).cloned().collect();
+	let mut history = world.resource_mut::<PredictionHistory<I>>();
+	// Keep the state at `ack` around, it is the baseline for the next comparison.
+	let keep = history.states.iter().rposition(|(tick, _)| *tick <= ack).unwrap_or(0);
+	history.states.drain(..keep);
+	if mispredicted.is_empty() {
+		return;
+	}
+	history.rollbacks += 1;
+
+	world.resource_scope(|world, prediction: Mut<Prediction<I>>| {
+		for (entity, net_id, server) in mispredicted.iter() {
+			apply_entity(world, *entity, server, server_tick);
+			world.resource_mut::<PredictionHistory<I>>().record(ack, *net_id, server.clone());
+			for (tick, input) in replay.iter() {
+				(prediction.0)(world, *entity, input);
+				let state = serialize_entity(world, *entity);
+				world.resource_mut::<PredictionHistory<I>>().record(*tick, *net_id, state);
+			}
+		}
+	});
+}
@@ -0,0 +1,153 @@
+//! Replication of resources, for global state like a match timer or score table.
+//!
+//! Add a [ReplicateResource] plugin for every replicated resource on both sides. The server sends the full
+//! resource to clients when they connect, and again whenever it changes. Clients insert the resource
+//! when it first arrives and overwrite it afterwards. Removing the resource on the server removes it on
+//! the clients as well. Clients only accept updates from the server they are connected to.
+
+use std::marker::PhantomData;
+
+use bevy::{
+	prelude::*,
+	reflect::{FromReflect, GetTypeRegistration},
+	utils::HashSet,
+};
+
+use crate::{
+	client::Client,
+	connection::ConnectionId,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg,
+	},
+	server::Server,
+	NetStage, NetSystem,
+};
+
+use super::{deserialize_reflect, replication_id, serialize_reflect, ReplicationId};
+
+/// The full value of a replicated resource, or [None] if the server removed it.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct ResourceUpdate {
+	pub id: ReplicationId,
+	pub data: Option<Vec<u8>>,
+}
+
+/// Replicates resource `T` from the server to its clients.
+///
+/// Requires the [super::ReplicationPlugin], and has to be added on both sides.
+pub struct ReplicateResource<T> {
+	_t: PhantomData<T>,
+}
+
+impl<T> Default for ReplicateResource<T> {
+	fn default() -> Self {
+		Self { _t: PhantomData }
+	}
+}
+
+impl<T> Plugin for ReplicateResource<T>
+where
+	T: Resource + Reflect + FromReflect + GetTypeRegistration,
+{
+	fn build(&self, app: &mut App) {
+		app.register_type::<T>()
+			.add_system_to_stage(NetStage::Receive, receive_resource::<T>.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Send, send_resource::<T>);
+	}
+}
+
+/// Sends `T` to the clients that haven't received it yet, or to all of them if it changed.
+///
+/// Once `T` is removed, the clients that received it are told to remove it as well.
+fn send_resource<T: Resource + Reflect>(
+	resource: Option<Res<T>>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	type_registry: Res<AppTypeRegistry>,
+	mut synced: Local<HashSet<ConnectionId>>,
+) {
+	let Some(server) = server else {
+		return;
+	};
+	synced.retain(|id| server.connections.contains_key(id));
+	let id = replication_id(std::any::type_name::<T>());
+	let Some(resource) = resource else {
+		for uuid in synced.drain() {
+			let Some(conn) = server.connections.get(&uuid) else {
+				continue;
+			};
+			if let Err(err) = conn.send_blocking(NetMsg::new(ResourceUpdate { id, data: None })) {
+				warn!("Unable to send the removal of `{}` to {}: {}", std::any::type_name::<T>(), uuid, err);
+			}
+		}
+		return;
+	};
+	let changed = resource.is_changed();
+	let outdated: Vec<_> = server
+		.connections
+		.iter()
+		.filter(|conn| changed || !synced.contains(&conn.uuid))
+		.map(|conn| conn.uuid)
+		.collect();
+	if outdated.is_empty() {
+		return;
+	}
+	let Some(data) = serialize_reflect::<T>(&resource, &type_registry.read()) else {
+		return;
+	};
+	for uuid in outdated {
+		let Some(conn) = server.connections.get(&uuid) else {
+			continue;
+		};
+		let msg = ResourceUpdate {
+			id,
+			data: Some(data.clone()),
+		};
+		match conn.send_blocking(NetMsg::new(msg)) {
+			Ok(()) => {
+				synced.insert(uuid);
+			}
+			Err(err) => warn!("Unable to send `{}` to {}: {}", std::any::type_name::<T>(), uuid, err),
+		}
+	}
+}
+
+/// Inserts, overwrites or removes `T` as the server last sent.
+fn receive_resource<T: Resource + FromReflect>(
+	mut commands: Commands,
+	mut updates: EventReader<Received<ResourceUpdate>>,
+	resource: Option<ResMut<T>>,
+	type_registry: Res<AppTypeRegistry>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let Some(server) = client.as_deref().and_then(|client| client.as_ref()).map(|conn| conn.uuid) else {
+		updates.clear();
+		return;
+	};
+	let id = replication_id(std::any::type_name::<T>());
+	let Some(update) = updates
+		.iter()
+		.filter(|update| update.from == server)
+		.rev()
+		.find(|update| update.msg.id == id)
+	else {
+		return;
+	};
+	let Some(data) = &update.msg.data else {
+		if resource.is_some() {
+			commands.remove_resource::<T>();
+		}
+		return;
+	};
+	let Some(value) = deserialize_reflect::<T>(&type_registry.read(), data) else {
+		return;
+	};
+	match resource {
+		Some(mut resource) => *resource = value,
+		None => commands.insert_resource(value),
+	}
+}
+
+pub(crate) fn build(app: &mut App) {
+	app.register_type::<Option<Vec<u8>>>().add_net_message::<ResourceUpdate>();
+}
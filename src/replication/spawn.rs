@@ -0,0 +1,149 @@
+//! Entities spawned by clients ahead of the server, like predicted projectiles.
+//!
+//! A client inserts [SpawnPredicted] on an entity with replicated components. In [NetStage::Send] the entity
+//! is given a [NetEntityId] from the range the server reserved for the client, see [super::ReservedIds], and
+//! sent to the server as a [PredictedSpawn]. The server spawns a [Replicated] entity with that id if it was
+//! reserved for the client and wasn't used before, and reports it with a [ClientSpawned] event. Other spawns
+//! are rejected with a [SpawnRejected] event.
+//!
+//! Snapshots of the entity are applied to the client's own copy, unless it is [super::prediction::Predicted].
+
+use bevy::prelude::*;
+
+use crate::{
+	client::Client,
+	connection::ConnectionId,
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetEntityId, NetMsg,
+	},
+	NetEntityRegistry, NetStage, NetSystem,
+};
+
+use super::{serialize_entity, ComponentData, ReplicationRules, ReplicationServer, Replicated};
+
+/// Marks an entity on the client to be spawned on the server as well.
+///
+/// Removed once the entity has been sent. Entities stay marked while the client has no reserved ids left.
+#[derive(Component, Debug, Default, Clone, Copy)]
+pub struct SpawnPredicted;
+
+/// An entity spawned by a client, with its replicated components.
+#[derive(Reflect, FromReflect, Debug, Clone)]
+pub struct PredictedSpawn {
+	pub net_id: NetEntityId,
+	pub components: Vec<ComponentData>,
+}
+
+/// Sent on the server when it spawned an entity for a [PredictedSpawn].
+#[derive(Debug, Clone)]
+pub struct ClientSpawned {
+	pub connection: ConnectionId,
+	pub entity: Entity,
+	pub net_id: NetEntityId,
+}
+
+/// Sent on the server when a [PredictedSpawn] used an id that wasn't reserved for its client, or was used before.
+#[derive(Debug, Clone)]
+pub struct SpawnRejected {
+	pub connection: ConnectionId,
+	pub net_id: NetEntityId,
+}
+
+/// Gives [SpawnPredicted] entities a reserved id, and sends them to the server.
+fn send_spawns(world: &mut World) {
+	let connected = world
+		.get_resource::<Client<NetMsg, NetMsg>>()
+		.is_some_and(|client| client.is_some());
+	if !connected {
+		return;
+	}
+	let mut query = world.query_filtered::<Entity, (With<SpawnPredicted>, Without<NetEntityId>)>();
+	let entities: Vec<_> = query.iter(world).collect();
+	let mut spawns = Vec::new();
+	for entity in entities {
+		let Some(net_id) = world.resource::<NetEntityRegistry>().register_reserved(entity) else {
+			// Sent once the server reserved more ids.
+			break;
+		};
+		let mut entity_mut = world.entity_mut(entity);
+		entity_mut.remove::<SpawnPredicted>();
+		entity_mut.insert(net_id);
+		let components = serialize_entity(world, entity)
+			.into_iter()
+			.map(|(id, data)| ComponentData { id, data })
+			.collect();
+		spawns.push(PredictedSpawn { net_id, components });
+	}
+	let Some(conn) = world
+		.get_resource::<Client<NetMsg, NetMsg>>()
+		.and_then(|client| client.as_ref())
+	else {
+		return;
+	};
+	for spawn in spawns {
+		let net_id = spawn.net_id;
+		if let Err(err) = conn.send_blocking(NetMsg::new(spawn)) {
+			warn!("Unable to send the spawn of {:?}: {}", net_id, err);
+		}
+	}
+}
+
+/// Spawns the entities clients spawned with their reserved ids, and rejects the others.
+fn receive_spawns(world: &mut World) {
+	let spawns: Vec<_> = world
+		.resource_mut::<Events<Received<PredictedSpawn>>>()
+		.drain()
+		.collect();
+	if spawns.is_empty() {
+		return;
+	}
+	let mut spawned = Vec::new();
+	let mut rejected = Vec::new();
+	world.resource_scope(|world, rules: Mut<ReplicationRules>| {
+		for Received { msg, from, tick } in spawns {
+			let net_id = msg.net_id;
+			if !world.resource_mut::<ReplicationServer>().use_reserved(from, net_id) {
+				rejected.push(SpawnRejected {
+					connection: from,
+					net_id,
+				});
+				continue;
+			}
+			let entity = world.spawn((Replicated, net_id)).id();
+			if !world.resource::<NetEntityRegistry>().claim(net_id, entity) {
+				world.despawn(entity);
+				rejected.push(SpawnRejected {
+					connection: from,
+					net_id,
+				});
+				continue;
+			}
+			for ComponentData { id, data } in msg.components.iter() {
+				match rules.rules.get(id) {
+					Some(rule) => (rule.apply)(world, entity, data, tick),
+					None => warn!("Received unknown replicated component {:#x}.", id),
+				}
+			}
+			spawned.push(ClientSpawned {
+				connection: from,
+				entity,
+				net_id,
+			});
+		}
+	});
+	for rejection in rejected.iter() {
+		warn!("Rejected a spawn of {:?} from {} with an id it didn't reserve.", rejection.net_id, rejection.connection);
+	}
+	world.resource_mut::<Events<ClientSpawned>>().extend(spawned);
+	world.resource_mut::<Events<SpawnRejected>>().extend(rejected);
+}
+
+pub(crate) fn build(app: &mut App) {
+	app.register_type::<PredictedSpawn>()
+		.add_net_message::<PredictedSpawn>()
+		.add_event::<ClientSpawned>()
+		.add_event::<SpawnRejected>()
+		.add_system_to_stage(NetStage::Receive, receive_spawns.after(NetSystem::Dispatch))
+		.add_system_to_stage(NetStage::Send, send_spawns);
+}
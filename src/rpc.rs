@@ -0,0 +1,320 @@
+//! Typed request/response calls on top of [NetMsg]s.
+//!
+//! Register a request type with [RpcAppExt::add_rpc] on the calling side, and with
+//! [RpcAppExt::add_rpc_handler] on the side that answers it. Calls are made through [RpcClient].
+//! A peer can both call and answer the same request type.
+//!
+//! Like [NetAppExt::add_net_message], this only works over the default instance, `Server<NetMsg, NetMsg>` and
+//! `Client<NetMsg, NetMsg>`: requests and responses are dispatched from its events, and responses are sent over
+//! its connections.
+
+use std::{
+	any::Any,
+	marker::PhantomData,
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
+};
+
+use async_channel::{bounded, Receiver, Sender};
+use bevy::{
+	ecs::system::SystemParam,
+	prelude::*,
+	reflect::{FromReflect, GetTypeRegistration},
+};
+use dashmap::DashMap;
+
+use crate::{
+	client::Client,
+	connection::{ConnectionError, ConnectionHandle, ConnectionId},
+	messaging::{
+		protocol::{NetAppExt, NetMessageRegistry, Received},
+		NetMsg,
+	},
+	server::Server,
+	NetStage, NetSystem,
+};
+
+pub type RequestId = u64;
+
+/// A request that is answered with a [NetRequest::Response].
+pub trait NetRequest: Reflect + FromReflect + GetTypeRegistration {
+	type Response: Reflect + FromReflect + GetTypeRegistration;
+}
+
+#[derive(Reflect, FromReflect, Debug, Clone, PartialEq, Eq)]
+pub enum RpcError {
+	/// No response arrived within [RpcCalls::timeout].
+	Timeout,
+	/// The connection closed before a response arrived.
+	Disconnected,
+	/// The handler answered with an error.
+	Failed(String),
+}
+
+#[derive(Reflect, FromReflect, Debug)]
+pub struct RpcRequest<T: Reflect + FromReflect + GetTypeRegistration> {
+	pub id: RequestId,
+	pub body: T,
+}
+
+#[derive(Reflect, FromReflect, Debug)]
+pub struct RpcResponse<T: Reflect + FromReflect + GetTypeRegistration> {
+	pub id: RequestId,
+	/// Set if the call succeeded.
+	pub body: Option<T>,
+	/// Set if the call failed.
+	pub error: Option<RpcError>,
+}
+
+/// A pending call, which can be polled from a system or awaited.
+#[derive(Debug)]
+pub struct RpcCall<T> {
+	pub id: RequestId,
+	receiver: Receiver<Result<T, RpcError>>,
+}
+
+impl<T> RpcCall<T> {
+	/// Returns the response once it has arrived.
+	pub fn try_recv(&self) -> Option<Result<T, RpcError>> {
+		self.receiver.try_recv().ok()
+	}
+
+	pub async fn response(self) -> Result<T, RpcError> {
+		self.receiver.recv().await.unwrap_or(Err(RpcError::Disconnected))
+	}
+}
+
+struct PendingCall {
+	/// The connection the request was sent to, which has to answer it.
+	to: ConnectionId,
+	deadline: Instant,
+	/// A `Sender<Result<T, RpcError>>` for the call's response type.
+	sender: Box<dyn Any + Send + Sync>,
+	fail: Box<dyn Fn(RpcError) + Send + Sync>,
+}
+
+/// Keeps track of the calls that are waiting for a response.
+#[derive(Resource)]
+pub struct RpcCalls {
+	pub timeout: Duration,
+	next_id: AtomicU64,
+	pending: DashMap<RequestId, PendingCall>,
+}
+
+impl Default for RpcCalls {
+	fn default() -> Self {
+		Self {
+			timeout: Duration::from_secs(10),
+			next_id: AtomicU64::new(0),
+			pending: DashMap::new(),
+		}
+	}
+}
+
+impl RpcCalls {
+	/// Sends `req` over `conn`.
+	pub fn call<Req: NetRequest>(
+		&self,
+		conn: &ConnectionHandle<NetMsg, NetMsg>,
+		req: Req,
+	) -> Result<RpcCall<Req::Response>, ConnectionError> {
+		let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+		let (sender, receiver) = bounded(1);
+		let fail = {
+			let sender = sender.clone();
+			Box::new(move |err| {
+				let _ = sender.try_send(Err(err));
+			})
+		};
+		self.pending.insert(
+			id,
+			PendingCall {
+				to: conn.uuid,
+				deadline: Instant::now() + self.timeout,
+				sender: Box::new(sender),
+				fail,
+			},
+		);
+		if let Err(err) = conn.send_blocking(NetMsg::new(RpcRequest { id, body: req })) {
+			self.pending.remove(&id);
+			return Err(err);
+		}
+		Ok(RpcCall { id, receiver })
+	}
+
+	fn respond<T: Send + 'static>(&self, from: ConnectionId, id: RequestId, result: Result<T, RpcError>) {
+		let Some((_, call)) = self.pending.remove_if(&id, |_, call| call.to == from) else {
+			// Already timed out, or not a call to `from`.
+			return;
+		};
+		match call.sender.downcast::<Sender<Result<T, RpcError>>>() {
+			Ok(sender) => {
+				let _ = sender.try_send(result);
+			}
+			Err(_) => (call.fail)(RpcError::Failed("response of the wrong type".to_owned())),
+		}
+	}
+
+	/// Fails the calls past their deadline, and those whose connection is no longer `open`.
+	fn expire(&self, now: Instant, open: impl Fn(ConnectionId) -> bool) {
+		self.pending.retain(|_, call| {
+			if !open(call.to) {
+				(call.fail)(RpcError::Disconnected);
+				return false;
+			}
+			if call.deadline > now {
+				return true;
+			}
+			(call.fail)(RpcError::Timeout);
+			false
+		});
+	}
+}
+
+/// Makes calls to the server the [Client] is connected to.
+#[derive(SystemParam)]
+pub struct RpcClient<'w, 's> {
+	client: Res<'w, Client<NetMsg, NetMsg>>,
+	calls: Res<'w, RpcCalls>,
+	#[system_param(ignore)]
+	_s: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> RpcClient<'w, 's> {
+	pub fn call<Req: NetRequest>(&self, req: Req) -> Result<RpcCall<Req::Response>, ConnectionError> {
+		let conn = (**self.client).as_ref().ok_or(ConnectionError::Disconnected)?;
+		self.calls.call(conn, req)
+	}
+}
+
+/// Answers a request from the given connection.
+pub type RpcHandlerFn<Req> = Box<
+	dyn Fn(&mut World, Req, ConnectionId) -> Result<<Req as NetRequest>::Response, RpcError> + Send + Sync,
+>;
+
+#[derive(Resource)]
+struct RpcHandler<Req: NetRequest>(RpcHandlerFn<Req>);
+
+pub trait RpcAppExt {
+	/// Registers the messages of `Req`, so it can be called through [RpcClient] or [RpcCalls].
+	///
+	/// Registering the same type again does nothing.
+	fn add_rpc<Req: NetRequest>(&mut self) -> &mut Self;
+
+	/// Registers `Req` and answers incoming requests with `handler`, replacing the previous handler of `Req`.
+	fn add_rpc_handler<Req, F>(&mut self, handler: F) -> &mut Self
+	where
+		Req: NetRequest,
+		F: Fn(&mut World, Req, ConnectionId) -> Result<Req::Response, RpcError> + Send + Sync + 'static;
+}
+
+impl RpcAppExt for App {
+	fn add_rpc<Req: NetRequest>(&mut self) -> &mut Self {
+		if !self.world.contains_resource::<RpcCalls>() {
+			self.init_resource::<RpcCalls>().add_system_to_stage(
+				NetStage::Receive,
+				expire_calls.after(NetSystem::Dispatch),
+			);
+		}
+		let registered = self
+			.world
+			.get_resource::<NetMessageRegistry>()
+			.and_then(|messages| messages.id(std::any::type_name::<RpcRequest<Req>>()))
+			.is_some();
+		if registered {
+			return self;
+		}
+		// Field types have to be registered as well for the messages to be deserialized.
+		self.register_type::<Req>()
+			.register_type::<Req::Response>()
+			.register_type::<RpcError>()
+			.register_type::<Option<RpcError>>()
+			.register_type::<Option<Req::Response>>()
+			.add_net_message::<RpcRequest<Req>>()
+			.add_net_message::<RpcResponse<Req::Response>>()
+			.add_system_to_stage(
+				NetStage::Receive,
+				receive_responses::<Req>.after(NetSystem::Dispatch),
+			)
+	}
+
+	fn add_rpc_handler<Req, F>(&mut self, handler: F) -> &mut Self
+	where
+		Req: NetRequest,
+		F: Fn(&mut World, Req, ConnectionId) -> Result<Req::Response, RpcError> + Send + Sync + 'static,
+	{
+		self.add_rpc::<Req>();
+		let handled = self.world.contains_resource::<RpcHandler<Req>>();
+		self.insert_resource(RpcHandler::<Req>(Box::new(handler)));
+		if handled {
+			return self;
+		}
+		self.add_system_to_stage(NetStage::Receive, handle_requests::<Req>.after(NetSystem::Dispatch))
+	}
+}
+
+fn receive_responses<Req: NetRequest>(
+	mut responses: EventReader<Received<RpcResponse<Req::Response>>>,
+	calls: Res<RpcCalls>,
+) {
+	for Received { msg, from, .. } in responses.iter() {
+		let result = match (&msg.body, &msg.error) {
+			(Some(body), _) => match <Req::Response as FromReflect>::from_reflect(body) {
+				Some(body) => Ok(body),
+				None => Err(RpcError::Failed("unable to convert the response".to_owned())),
+			},
+			(None, Some(err)) => Err(err.clone()),
+			(None, None) => Err(RpcError::Failed("empty response".to_owned())),
+		};
+		calls.respond(*from, msg.id, result);
+	}
+}
+
+fn expire_calls(
+	calls: Res<RpcCalls>,
+	server: Option<Res<Server<NetMsg, NetMsg>>>,
+	client: Option<Res<Client<NetMsg, NetMsg>>>,
+) {
+	let client = client.as_deref().and_then(|client| client.as_ref());
+	calls.expire(Instant::now(), |to| {
+		server.as_ref().and_then(|server| server.connections.get(&to)).is_some_and(|conn| conn.is_running())
+			|| client.is_some_and(|conn| conn.uuid == to && conn.is_running())
+	});
+}
+
+fn handle_requests<Req: NetRequest>(world: &mut World) {
+	let requests: Vec<_> = world
+		.resource_mut::<Events<Received<RpcRequest<Req>>>>()
+		.drain()
+		.collect();
+	if requests.is_empty() {
+		return;
+	}
+	world.resource_scope(|world, handler: Mut<RpcHandler<Req>>| {
+		for Received { msg, from, .. } in requests {
+			let (body, error) = match (handler.0)(world, msg.body, from) {
+				Ok(body) => (Some(body), None),
+				Err(err) => (None, Some(err)),
+			};
+			let response = NetMsg::new(RpcResponse { id: msg.id, body, error });
+			if let Err(err) = send_to(world, from, response) {
+				warn!("Unable to respond to {}: {}", from, err);
+			}
+		}
+	});
+}
+
+/// Sends `msg` to the connection `to`, whether it is a client of our [Server] or our [Client]'s server.
+fn send_to(world: &World, to: ConnectionId, msg: NetMsg) -> Result<(), ConnectionError> {
+	if let Some(server) = world.get_resource::<Server<NetMsg, NetMsg>>() {
+		if let Some(conn) = server.connections.get(&to) {
+			return conn.send_blocking(msg);
+		}
+	}
+	if let Some(client) = world.get_resource::<Client<NetMsg, NetMsg>>() {
+		if let Some(conn) = client.as_ref().filter(|conn| conn.uuid == to) {
+			return conn.send_blocking(msg);
+		}
+	}
+	Err(ConnectionError::Disconnected)
+}
@@ -8,7 +8,6 @@ use std::{
 
 use async_channel::{unbounded, Receiver, RecvError, SendError, Sender};
 use dashmap::DashMap;
-use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tokio::{
 	io,
@@ -17,7 +16,10 @@ use tokio::{
 	task::{JoinError, JoinHandle},
 };
 
-use crate::connection::{ConnectionHandle, ConnectionId};
+use crate::{
+	connection::{ConnectionConfig, ConnectionHandle, ConnectionId},
+	messaging::{codec::CodecError, NetContext, NetDeserialize, NetSerialize},
+};
 
 mod plugin;
 
@@ -26,24 +28,26 @@ pub use plugin::*;
 #[derive(Debug)]
 pub struct ServerHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
 {
 	pub connections: Arc<DashMap<ConnectionId, ConnectionHandle<S, R>>>,
 	running: Arc<AtomicBool>,
 	task: Option<JoinHandle<Result<(), ServerError>>>,
 	from_task: Option<Receiver<(SocketAddr, ConnectionId)>>,
 	rt: Option<Handle>,
+	ctx: NetContext,
+	config: ConnectionConfig,
 }
 
 use ServerError::Disconnected;
 
 impl<S, R> ServerHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
 {
-	pub fn new() -> Self {
+	pub fn new(ctx: NetContext, config: ConnectionConfig) -> Self {
 		let connections = Arc::new(DashMap::new());
 		let running = Arc::new(AtomicBool::new(false));
 
@@ -57,10 +61,30 @@ where
 			task,
 			from_task,
 			rt,
+			ctx,
+			config,
 		}
 	}
 
 	pub fn bind<A: ToSocketAddrs + Sync + Send + 'static>(&mut self, addr: A, rt: Handle) {
+		let server = self.start(&rt);
+		self.task = Some(rt.spawn(server.listen(addr)));
+	}
+
+	/// Starts accepting connections on `addr`, returning the address the server listens on.
+	///
+	/// Unlike [Self::bind], which binds in the background, the listener is bound before this returns, so clients
+	/// can connect right away and binding errors are returned. Bind to port 0 to let the OS pick a free port.
+	pub fn listen<A: std::net::ToSocketAddrs>(&mut self, addr: A, rt: Handle) -> Result<SocketAddr, ServerError> {
+		let listener = std::net::TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+		let local_addr = listener.local_addr()?;
+		let server = self.start(&rt);
+		self.task = Some(rt.spawn(server.listen_on(listener)));
+		Ok(local_addr)
+	}
+
+	fn start(&mut self, rt: &Handle) -> InternalServer<S, R> {
 		let (to_handle, from_task) = unbounded::<(SocketAddr, ConnectionId)>();
 
 		let server = InternalServer {
@@ -68,15 +92,15 @@ where
 			running: self.running.clone(),
 			rt: rt.clone(),
 			to_handle,
+			ctx: self.ctx.clone(),
+			config: self.config.clone(),
 		};
 
 		self.running.store(true, Ordering::Relaxed);
 
-		let task = Some(rt.spawn(server.listen(addr)));
-
-		self.task = task;
 		self.from_task = Some(from_task);
-		self.rt = Some(rt);
+		self.rt = Some(rt.clone());
+		server
 	}
 
 	fn internal_disconnect_blocking(&mut self) -> Result<(), ServerError> {
@@ -114,8 +138,8 @@ where
 
 impl<S, R> Drop for ServerHandle<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
 {
 	fn drop(&mut self) {
 		if !self.running.load(Ordering::Relaxed) {
@@ -126,28 +150,43 @@ where
 
 struct InternalServer<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
 {
 	connections: Arc<DashMap<ConnectionId, ConnectionHandle<S, R>>>,
 	running: Arc<AtomicBool>,
 	rt: Handle,
 	to_handle: Sender<(SocketAddr, ConnectionId)>,
+	ctx: NetContext,
+	config: ConnectionConfig,
 }
 
 impl<S, R> InternalServer<S, R>
 where
-	S: Serialize + Send + 'static,
-	for<'de> R: Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
 {
 	async fn listen<A: ToSocketAddrs + Sync + Sync + 'static>(
 		self,
 		addr: A,
 	) -> Result<(), ServerError> {
 		let listener = TcpListener::bind(addr).await.unwrap();
+		self.accept(listener).await
+	}
+
+	async fn listen_on(self, listener: std::net::TcpListener) -> Result<(), ServerError> {
+		let listener = TcpListener::from_std(listener)?;
+		self.accept(listener).await
+	}
 
+	async fn accept(self, listener: TcpListener) -> Result<(), ServerError> {
 		while let Ok((stream, addr)) = listener.accept().await {
-			let conn = ConnectionHandle::with_stream(stream, self.rt.clone());
+			let conn = ConnectionHandle::with_stream(
+				stream,
+				self.rt.clone(),
+				self.ctx.clone(),
+				self.config.clone(),
+			);
 			if let Err(_err) = self.to_handle.send((addr, conn.uuid)).await {
 				// If the channel returns an error and running is true, unexpected disconnect.
 				if self.running.load(Ordering::Relaxed) {
@@ -170,7 +209,7 @@ pub enum ServerError {
 	#[error("Not connected, or unexpected disconnect.")]
 	Disconnected,
 	#[error("Unable to serialize message.")]
-	SerializationError(#[from] postcard::Error),
+	SerializationError(#[from] CodecError),
 	#[error("The underlying task returned an error on join.")]
 	JoinError(#[from] JoinError),
 }
@@ -1,55 +1,150 @@
 use std::marker::PhantomData;
 
-use bevy::prelude::{Deref, DerefMut, EventWriter, Plugin, Res, Resource};
-use serde::{Deserialize, Serialize};
+use bevy::prelude::{EventWriter, IntoSystemDescriptor, Plugin, Res, Resource};
 
-use crate::{connection::ext::Event, NetStage};
+use crate::{
+	connection::{ext::Event, ConnectionConfig, FlushMode},
+	messaging::{NetContext, NetDeserialize, NetSerialize},
+	tick::NetTick,
+	NetStage, NetSystem,
+};
 
 use super::ServerHandle;
 
-#[derive(Default, Debug)]
-pub struct ServerPlugin<S, R>
+/// Adds a [Server] resource and its [FromClient] events to the app.
+///
+/// The instance label `I` distinguishes multiple servers with the same message types,
+/// e.g. `ServerPlugin::<Msg, Msg, GameServer>` and `ServerPlugin::<Msg, Msg, ChatRelay>`.
+#[derive(Debug)]
+pub struct ServerPlugin<S, R, I = ()>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
 {
 	_s: PhantomData<S>,
 	_r: PhantomData<R>,
+	_i: PhantomData<I>,
 }
 
-impl<S, R> Plugin for ServerPlugin<S, R>
+impl<S, R, I> Default for ServerPlugin<S, R, I>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	fn default() -> Self {
+		Self {
+			_s: PhantomData,
+			_r: PhantomData,
+			_i: PhantomData,
+		}
+	}
+}
+
+impl<S, R, I> Plugin for ServerPlugin<S, R, I>
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
 {
 	fn build(&self, app: &mut bevy::prelude::App) {
-		app.add_system_to_stage(NetStage::Receive, Server::<S, R>::event_system)
-			.insert_resource(Server::<S, R>(ServerHandle::new()))
-			.add_event::<FromClient<R>>();
+		let ctx = NetContext::from_app(app);
+		let config = app.world.get_resource::<ConnectionConfig>().cloned().unwrap_or_default();
+		app.add_system_to_stage(NetStage::Receive, Server::<S, R, I>::event_system.label(NetSystem::Receive))
+			.add_system_to_stage(NetStage::Flush, Server::<S, R, I>::flush_system)
+			.insert_resource(Server::<S, R, I>::new(ServerHandle::new(ctx, config)))
+			.add_event::<FromClient<R, I>>();
+	}
+}
+
+#[derive(Resource, Debug)]
+pub struct Server<S, R, I = ()>(ServerHandle<S, R>, PhantomData<I>)
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static;
+
+impl<S, R, I> std::ops::Deref for Server<S, R, I>
+where
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	type Target = ServerHandle<S, R>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
 	}
 }
 
-#[derive(Resource, Deref, DerefMut, Debug)]
-pub struct Server<S, R>(ServerHandle<S, R>)
+impl<S, R, I> std::ops::DerefMut for Server<S, R, I>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static;
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
+{
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.0
+	}
+}
+
+pub struct FromClient<R, I = ()>(Event<R>, Option<NetTick>, PhantomData<I>);
 
-#[derive(Deref, DerefMut)]
-pub struct FromClient<R>(Event<R>);
+impl<R, I> FromClient<R, I> {
+	/// An event for a message the client sent at `tick`.
+	pub fn stamped(event: Event<R>, tick: NetTick) -> Self {
+		Self(event, Some(tick), PhantomData)
+	}
+
+	/// The client's [NetTick] when it sent the message, if this is a message.
+	pub fn tick(&self) -> Option<NetTick> {
+		self.1
+	}
+}
+
+impl<R, I> std::ops::Deref for FromClient<R, I> {
+	type Target = Event<R>;
+
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+
+impl<R, I> std::ops::DerefMut for FromClient<R, I> {
+	fn deref_mut(&mut self) -> &mut Self::Target {
+		&mut self.0
+	}
+}
 
-impl<R> From<Event<R>> for FromClient<R> {
+impl<R, I> From<Event<R>> for FromClient<R, I> {
 	fn from(value: Event<R>) -> Self {
-		Self(value)
+		Self(value, None, PhantomData)
 	}
 }
 
-impl<S, R> Server<S, R>
+impl<S, R, I> Server<S, R, I>
 where
-	S: Serialize + Send + Sync + 'static,
-	R: for<'de> Deserialize<'de> + Send + Sync + 'static,
+	S: NetSerialize + Send + Sync + 'static,
+	R: NetDeserialize + Send + Sync + 'static,
+	I: Send + Sync + 'static,
 {
-	pub fn event_system(server: Res<Server<S, R>>, mut eventwriter: EventWriter<FromClient<R>>) {
+	pub fn new(handle: ServerHandle<S, R>) -> Self {
+		Self(handle, PhantomData)
+	}
+
+	/// Flushes every connection once per frame in [FlushMode::Manual], the writers flush by themselves otherwise.
+	pub fn flush_system(server: Res<Server<S, R, I>>) {
+		if server.config.flush_mode != FlushMode::Manual {
+			return;
+		}
+		for conn in server.connections.iter() {
+			// A disconnected connection reports that through `event_system` instead.
+			let _ = conn.flush();
+		}
+	}
+
+	pub fn event_system(server: Res<Server<S, R, I>>, mut eventwriter: EventWriter<FromClient<R, I>>) {
 		loop {
 			let recv = server.try_recv();
 			if let Ok(opt) = recv {
@@ -61,12 +156,12 @@ where
 		}
 		for conn in server.connections.iter() {
 			loop {
-				let recv = conn.try_recv();
+				let recv = conn.try_recv_stamped();
 				if let Ok(opt) = recv {
-					let Some(val) = opt else {
+					let Some((val, tick)) = opt else {
 						break;
 					};
-					eventwriter.send(Event::Message(val, conn.uuid).into());
+					eventwriter.send(FromClient::stamped(Event::Message(val, conn.uuid), tick));
 				} else if let Err(err) = recv {
 					eventwriter.send(Event::Error(err, conn.uuid).into());
 					break;
@@ -0,0 +1,165 @@
+//! A fixed-rate network tick, independent of the framerate.
+//!
+//! [NetTick] advances at [NetTickConfig::tick_rate]. Every outgoing frame is stamped with the tick it
+//! was sent at, which is exposed on received events through [crate::server::FromClient::tick],
+//! [crate::client::FromServer::tick] and [crate::messaging::protocol::Received::tick].
+
+use std::{
+	sync::{
+		atomic::{AtomicU32, Ordering},
+		Arc,
+	},
+	time::Duration,
+};
+
+use bevy::{ecs::schedule::ShouldRun, prelude::*};
+
+use crate::NetSystem;
+
+/// Number of network ticks since the app started.
+#[derive(Resource, Reflect, FromReflect, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NetTick(pub u32);
+
+impl NetTick {
+	/// The tick `ticks` later, or earlier for negative `ticks`.
+	pub fn offset(self, ticks: i32) -> NetTick {
+		NetTick(self.0.wrapping_add_signed(ticks))
+	}
+
+	/// Number of ticks from `earlier` to `self`.
+	pub fn since(self, earlier: NetTick) -> i32 {
+		self.0.wrapping_sub(earlier.0) as i32
+	}
+}
+
+impl std::fmt::Display for NetTick {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "#{}", self.0)
+	}
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct NetTickConfig {
+	/// Ticks per second.
+	pub tick_rate: f64,
+	/// Only run [crate::NetStage]s once for every tick that elapsed, instead of every frame, see [on_net_tick].
+	pub run_on_tick: bool,
+}
+
+impl Default for NetTickConfig {
+	fn default() -> Self {
+		Self {
+			tick_rate: 60.0,
+			run_on_tick: false,
+		}
+	}
+}
+
+impl NetTickConfig {
+	/// The time between ticks.
+	///
+	/// Panics if [NetTickConfig::tick_rate] isn't a positive number of ticks per second.
+	pub fn step(&self) -> Duration {
+		assert!(
+			self.tick_rate > 0.0 && self.tick_rate.is_finite(),
+			"`NetTickConfig::tick_rate` has to be positive, but is {}.",
+			self.tick_rate
+		);
+		Duration::from_secs_f64(1.0 / self.tick_rate)
+	}
+}
+
+/// The current [NetTick], shared with connection tasks so they can stamp outgoing frames.
+#[derive(Resource, Debug, Default, Clone)]
+pub struct SharedNetTick(Arc<AtomicU32>);
+
+impl SharedNetTick {
+	pub fn get(&self) -> NetTick {
+		NetTick(self.0.load(Ordering::Relaxed))
+	}
+
+	fn set(&self, tick: NetTick) {
+		self.0.store(tick.0, Ordering::Relaxed);
+	}
+}
+
+/// Accumulates frame time into ticks.
+#[derive(Resource, Debug, Default)]
+pub struct NetTickTimer {
+	step: Duration,
+	accumulator: Duration,
+	elapsed: u32,
+}
+
+impl NetTickTimer {
+	pub fn new(step: Duration) -> Self {
+		Self {
+			step,
+			..default()
+		}
+	}
+
+	/// Number of ticks that elapsed this frame.
+	pub fn elapsed_ticks(&self) -> u32 {
+		self.elapsed
+	}
+
+	/// Whether the tick advanced this frame.
+	pub fn ticked(&self) -> bool {
+		self.elapsed > 0
+	}
+
+	/// Time since the last tick, as a fraction of a tick.
+	pub fn overstep(&self) -> f32 {
+		self.accumulator.as_secs_f32() / self.step.as_secs_f32()
+	}
+
+	pub fn step(&self) -> Duration {
+		self.step
+	}
+}
+
+/// Run criteria that runs systems once for every tick the [NetTick] advanced by this frame,
+/// so a stage keeps up with the tick rate when the framerate is lower.
+///
+/// The [NetTick] is already at the latest tick in each of those runs.
+pub fn on_net_tick(timer: Res<NetTickTimer>, mut runs: Local<u32>) -> ShouldRun {
+	if *runs < timer.elapsed_ticks() {
+		*runs += 1;
+		ShouldRun::YesAndCheckAgain
+	} else {
+		*runs = 0;
+		ShouldRun::No
+	}
+}
+
+/// Advances the [NetTick] by every tick that elapsed since the last frame,
+/// so it keeps pace with the clock even when the framerate is lower than the tick rate.
+fn advance_tick(
+	time: Res<Time>,
+	mut timer: ResMut<NetTickTimer>,
+	mut tick: ResMut<NetTick>,
+	shared: Res<SharedNetTick>,
+) {
+	let timer = &mut *timer;
+	timer.accumulator += time.delta();
+	timer.elapsed = 0;
+	while timer.accumulator >= timer.step {
+		timer.accumulator -= timer.step;
+		timer.elapsed += 1;
+	}
+	if timer.elapsed > 0 {
+		tick.0 = tick.0.wrapping_add(timer.elapsed);
+		shared.set(*tick);
+	}
+}
+
+pub(crate) fn build(app: &mut App) -> NetTickConfig {
+	let config = app.world.get_resource_or_insert_with(NetTickConfig::default).clone();
+	app.world.get_resource_or_insert_with(SharedNetTick::default);
+	app.register_type::<NetTick>()
+		.init_resource::<NetTick>()
+		.insert_resource(NetTickTimer::new(config.step()))
+		.add_system_to_stage(CoreStage::PreUpdate, advance_tick.label(NetSystem::Tick));
+	config
+}
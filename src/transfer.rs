@@ -0,0 +1,643 @@
+//! Streaming transfers of large payloads, like custom maps, replays or save files.
+//!
+//! [Transfers::send] reads the payload from any [Read] in chunks of [TransferConfig::chunk_size], so the sender
+//! never holds it in memory as a whole. Chunks are only read while the connection has fewer than
+//! [TransferConfig::max_pending] messages waiting to be written, and are sent on [TransferConfig::channel], so other
+//! traffic can be given priority over them, see [crate::connection::channel].
+//!
+//! Both sides report [TransferProgress] in every frame a transfer advances, and either side can cancel it.
+//! The receiver checks the CRC-32 of the payload before handing it to the application as a [TransferCompleted],
+//! and cancels the transfer if it doesn't match, so it fails on both sides.
+//! It collects the payload in memory, unless [Transfers::set_sink] picks a writer for it, and rejects transfers
+//! above [TransferConfig::max_size]. Transfers fail on both sides once their connection closes.
+
+use std::io::{self, Cursor, Read, Write};
+
+use bevy::{prelude::*, utils::HashMap};
+use thiserror::Error;
+
+use crate::{
+	client::Client,
+	connection::{
+		channel::{ChannelId, DEFAULT_CHANNEL},
+		ConnectionError, ConnectionHandle, ConnectionId,
+	},
+	messaging::{
+		protocol::{NetAppExt, Received},
+		NetMsg, RawBytes,
+	},
+	server::Server,
+	NetStage, NetSystem,
+};
+
+/// Identifies a transfer together with the connection it is sent to, assigned by the sender.
+pub type TransferId = u64;
+
+/// Announces a transfer of `size` bytes.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct TransferStart {
+	pub id: TransferId,
+	pub name: String,
+	pub size: u64,
+}
+
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct TransferChunk {
+	pub id: TransferId,
+	pub data: RawBytes,
+}
+
+/// Sent after the last chunk, with the [Crc32] of the whole payload.
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct TransferEnd {
+	pub id: TransferId,
+	pub checksum: u32,
+}
+
+/// Sent by the receiver once it accepted the payload after the [TransferEnd].
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct TransferReceived {
+	pub id: TransferId,
+}
+
+#[derive(Reflect, FromReflect, Debug, Clone, Default)]
+pub struct TransferCancel {
+	pub id: TransferId,
+	/// Whether the sender of the transfer cancelled it, rather than the receiver.
+	pub by_sender: bool,
+}
+
+#[derive(Resource, Debug, Clone)]
+pub struct TransferConfig {
+	/// Bytes per [TransferChunk].
+	pub chunk_size: usize,
+	/// Backlog of a connection above which no more chunks are read, see [ConnectionHandle::pending].
+	pub max_pending: usize,
+	/// The channel chunks are sent on.
+	pub channel: ChannelId,
+	/// Largest payload accepted from a peer, in bytes. Larger transfers are cancelled when they start.
+	pub max_size: u64,
+}
+
+impl Default for TransferConfig {
+	fn default() -> Self {
+		Self {
+			chunk_size: 64 * 1024,
+			max_pending: 8,
+			channel: DEFAULT_CHANNEL,
+			max_size: 64 * 1024 * 1024,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferDirection {
+	Sending,
+	Receiving,
+}
+
+/// Sent on both sides in every frame a transfer advances.
+#[derive(Debug, Clone)]
+pub struct TransferProgress {
+	pub connection: ConnectionId,
+	pub id: TransferId,
+	pub direction: TransferDirection,
+	/// Bytes sent or received so far.
+	pub transferred: u64,
+	pub size: u64,
+}
+
+/// A received payload that matched its checksum.
+#[derive(Debug, Clone)]
+pub struct TransferCompleted {
+	pub from: ConnectionId,
+	pub id: TransferId,
+	pub name: String,
+	/// The payload, empty if it was written to a sink, see [Transfers::set_sink].
+	pub data: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct TransferFailed {
+	pub connection: ConnectionId,
+	pub id: TransferId,
+	pub direction: TransferDirection,
+	pub error: TransferError,
+}
+
+#[derive(Error, Debug)]
+pub enum TransferError {
+	#[error("Cancelled.")]
+	Cancelled,
+	#[error("Cancelled by the peer.")]
+	CancelledByPeer,
+	#[error("The payload doesn't match its checksum.")]
+	ChecksumMismatch,
+	#[error("Transferred {transferred} bytes, but {size} were announced.")]
+	SizeMismatch { transferred: u64, size: u64 },
+	#[error("Announced {size} bytes, more than the maximum of {max}.")]
+	TooLarge { size: u64, max: u64 },
+	#[error("Started again while in progress.")]
+	AlreadyStarted,
+	#[error("Unable to read or write the payload: {0}")]
+	IOError(#[from] io::Error),
+	#[error("Connection error: {0}")]
+	ConnectionError(#[from] ConnectionError),
+}
+
+/// CRC-32 (IEEE), computed while a payload streams by.
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32(u32);
+
+const CRC32_TABLE: [u32; 256] = {
+	let mut table = [0; 256];
+	let mut i = 0;
+	while i < 256 {
+		let mut crc = i as u32;
+		let mut bit = 0;
+		while bit < 8 {
+			crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+			bit += 1;
+		}
+		table[i] = crc;
+		i += 1;
+	}
+	table
+};
+
+impl Default for Crc32 {
+	fn default() -> Self {
+		Self(!0)
+	}
+}
+
+impl Crc32 {
+	pub fn update(&mut self, data: &[u8]) {
+		for byte in data {
+			self.0 = CRC32_TABLE[((self.0 ^ *byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+		}
+	}
+
+	pub fn finish(&self) -> u32 {
+		!self.0
+	}
+}
+
+struct Outgoing {
+	to: ConnectionId,
+	name: String,
+	size: u64,
+	source: io::Take<Box<dyn Read + Send + Sync>>,
+	started: bool,
+	sent: u64,
+	crc: Crc32,
+}
+
+struct Incoming {
+	name: String,
+	size: u64,
+	received: u64,
+	crc: Crc32,
+	/// Where the payload is written, collected in `data` if there is none.
+	sink: Option<Box<dyn Write + Send + Sync>>,
+	data: Vec<u8>,
+}
+
+impl Incoming {
+	fn write(&mut self, data: &[u8]) -> io::Result<()> {
+		match &mut self.sink {
+			Some(sink) => sink.write_all(data)?,
+			None => self.data.extend_from_slice(data),
+		}
+		self.crc.update(data);
+		self.received += data.len() as u64;
+		Ok(())
+	}
+}
+
+/// Picks the writer for the payload of an incoming transfer, see [Transfers::set_sink].
+pub type SinkFn = dyn Fn(ConnectionId, &TransferStart) -> Option<Box<dyn Write + Send + Sync>> + Send + Sync;
+
+/// Cancels queued by [Transfers::cancel_send] and [Transfers::cancel_receive] or by a failed receive,
+/// reported to the peer in [NetStage::Send].
+struct Cancel {
+	connection: ConnectionId,
+	id: TransferId,
+	by_sender: bool,
+	error: TransferError,
+}
+
+/// The transfers in progress, in both directions.
+#[derive(Resource, Default)]
+pub struct Transfers {
+	next_id: TransferId,
+	outgoing: HashMap<TransferId, Outgoing>,
+	/// Our transfers that were sent completely, until the receiver accepts or rejects the payload.
+	ended: HashMap<TransferId, ConnectionId>,
+	incoming: HashMap<(ConnectionId, TransferId), Incoming>,
+	cancels: Vec<Cancel>,
+	/// Payloads we accepted, confirmed to their sender in [NetStage::Send].
+	accepted: Vec<(ConnectionId, TransferId)>,
+	sink: Option<Box<SinkFn>>,
+}
+
+impl Transfers {
+	/// Writes the payloads of incoming transfers to the writer `sink` returns for them, like a file, instead of
+	/// collecting them in memory. Payloads it returns `None` for are still collected.
+	pub fn set_sink(
+		&mut self,
+		sink: impl Fn(ConnectionId, &TransferStart) -> Option<Box<dyn Write + Send + Sync>> + Send + Sync + 'static,
+	) {
+		self.sink = Some(Box::new(sink));
+	}
+
+	/// Streams `size` bytes read from `source` to the connection `to`, whether it is a client of our [Server] or
+	/// our [Client]'s server.
+	pub fn send(
+		&mut self,
+		to: ConnectionId,
+		name: impl Into<String>,
+		size: u64,
+		source: impl Read + Send + Sync + 'static,
+	) -> TransferId {
+		let id = self.next_id;
+		self.next_id += 1;
+		let source: Box<dyn Read + Send + Sync> = Box::new(source);
+		self.outgoing.insert(
+			id,
+			Outgoing {
+				to,
+				name: name.into(),
+				size,
+				source: source.take(size),
+				started: false,
+				sent: 0,
+				crc: Crc32::default(),
+			},
+		);
+		id
+	}
+
+	/// Sends a payload that is already in memory.
+	pub fn send_bytes(&mut self, to: ConnectionId, name: impl Into<String>, data: Vec<u8>) -> TransferId {
+		let size = data.len() as u64;
+		self.send(to, name, size, Cursor::new(data))
+	}
+
+	/// Cancels one of our transfers, returns whether it was still in progress.
+	pub fn cancel_send(&mut self, id: TransferId) -> bool {
+		let Some(transfer) = self.outgoing.remove(&id) else {
+			return false;
+		};
+		self.cancels.push(Cancel {
+			connection: transfer.to,
+			id,
+			by_sender: true,
+			error: TransferError::Cancelled,
+		});
+		true
+	}
+
+	/// Cancels a transfer we are receiving from `from`, returns whether it was still in progress.
+	pub fn cancel_receive(&mut self, from: ConnectionId, id: TransferId) -> bool {
+		if self.incoming.remove(&(from, id)).is_none() {
+			return false;
+		}
+		self.cancels.push(Cancel {
+			connection: from,
+			id,
+			by_sender: false,
+			error: TransferError::Cancelled,
+		});
+		true
+	}
+
+	pub fn is_sending(&self, id: TransferId) -> bool {
+		self.outgoing.contains_key(&id)
+	}
+
+	pub fn is_receiving(&self, from: ConnectionId, id: TransferId) -> bool {
+		self.incoming.contains_key(&(from, id))
+	}
+}
+
+/// Runs `f` with the connection `to`, whether it is a client of our [Server] or our [Client]'s server.
+fn with_connection<T>(
+	world: &World,
+	to: ConnectionId,
+	f: impl FnOnce(&ConnectionHandle<NetMsg, NetMsg>) -> Result<T, ConnectionError>,
+) -> Result<T, ConnectionError> {
+	if let Some(server) = world.get_resource::<Server<NetMsg, NetMsg>>() {
+		if let Some(conn) = server.connections.get(&to) {
+			return f(&conn);
+		}
+	}
+	if let Some(client) = world.get_resource::<Client<NetMsg, NetMsg>>() {
+		if let Some(conn) = client.as_ref().filter(|conn| conn.uuid == to) {
+			return f(conn);
+		}
+	}
+	Err(ConnectionError::Disconnected)
+}
+
+/// Sends as many chunks of `transfer` as the connection takes, returns whether it is done.
+fn advance(world: &World, id: TransferId, transfer: &mut Outgoing, config: &TransferConfig) -> Result<bool, TransferError> {
+	let to = transfer.to;
+	let send = |msg: NetMsg| with_connection(world, to, |conn| conn.send_on(config.channel, msg));
+	while with_connection(world, to, |conn| Ok(conn.pending()))? < config.max_pending {
+		if !transfer.started {
+			let start = TransferStart {
+				id,
+				name: transfer.name.clone(),
+				size: transfer.size,
+			};
+			send(NetMsg::new(start))?;
+			transfer.started = true;
+			continue;
+		}
+		if transfer.sent == transfer.size {
+			send(NetMsg::new(TransferEnd {
+				id,
+				checksum: transfer.crc.finish(),
+			}))?;
+			return Ok(true);
+		}
+		let mut data = Vec::with_capacity(config.chunk_size);
+		Read::by_ref(&mut transfer.source)
+			.take(config.chunk_size as u64)
+			.read_to_end(&mut data)?;
+		if data.is_empty() {
+			return Err(TransferError::SizeMismatch {
+				transferred: transfer.sent,
+				size: transfer.size,
+			});
+		}
+		transfer.crc.update(&data);
+		transfer.sent += data.len() as u64;
+		send(NetMsg::new(TransferChunk { id, data: RawBytes(data) }))?;
+	}
+	Ok(false)
+}
+
+fn send_transfers(world: &mut World) {
+	let config = world.resource::<TransferConfig>().clone();
+	world.resource_scope(|world, mut transfers: Mut<Transfers>| {
+		for cancel in transfers.cancels.drain(..) {
+			let msg = TransferCancel {
+				id: cancel.id,
+				by_sender: cancel.by_sender,
+			};
+			if let Err(err) = with_connection(world, cancel.connection, |conn| conn.send_on(config.channel, NetMsg::new(msg))) {
+				warn!("Unable to cancel transfer {} with {}: {}", cancel.id, cancel.connection, err);
+			}
+			world.send_event(TransferFailed {
+				connection: cancel.connection,
+				id: cancel.id,
+				direction: if cancel.by_sender {
+					TransferDirection::Sending
+				} else {
+					TransferDirection::Receiving
+				},
+				error: cancel.error,
+			});
+		}
+		for (connection, id) in transfers.accepted.drain(..) {
+			let msg = NetMsg::new(TransferReceived { id });
+			if let Err(err) = with_connection(world, connection, |conn| conn.send_on(config.channel, msg)) {
+				warn!("Unable to confirm transfer {} from {}: {}", id, connection, err);
+			}
+		}
+
+		// Incoming transfers fail once their connection closed, as no more chunks can arrive.
+		let mut failed = Vec::new();
+		transfers.incoming.retain(|(connection, id), _| {
+			if with_connection(world, *connection, |_| Ok(())).is_ok() {
+				return true;
+			}
+			failed.push(TransferFailed {
+				connection: *connection,
+				id: *id,
+				direction: TransferDirection::Receiving,
+				error: ConnectionError::Disconnected.into(),
+			});
+			false
+		});
+		// Without a connection, the receiver can no longer reject a payload we sent completely.
+		transfers.ended.retain(|_, to| with_connection(world, *to, |_| Ok(())).is_ok());
+
+		// Outgoing transfers fail once their connection closed while advancing them.
+		let mut progress = Vec::new();
+		let mut ended = Vec::new();
+		transfers.outgoing.retain(|id, transfer| {
+			let sent = transfer.sent;
+			let result = advance(world, *id, transfer, &config);
+			if transfer.sent != sent || matches!(result, Ok(true)) {
+				progress.push(TransferProgress {
+					connection: transfer.to,
+					id: *id,
+					direction: TransferDirection::Sending,
+					transferred: transfer.sent,
+					size: transfer.size,
+				});
+			}
+			match result {
+				Ok(true) => {
+					ended.push((*id, transfer.to));
+					false
+				}
+				Ok(false) => true,
+				Err(error) => {
+					failed.push(TransferFailed {
+						connection: transfer.to,
+						id: *id,
+						direction: TransferDirection::Sending,
+						error,
+					});
+					false
+				}
+			}
+		});
+		transfers.ended.extend(ended);
+		world.resource_mut::<Events<TransferProgress>>().extend(progress);
+		world.resource_mut::<Events<TransferFailed>>().extend(failed);
+	});
+}
+
+#[allow(clippy::too_many_arguments)]
+fn receive_transfers(
+	mut starts: EventReader<Received<TransferStart>>,
+	mut chunks: EventReader<Received<TransferChunk>>,
+	mut ends: EventReader<Received<TransferEnd>>,
+	mut received: EventReader<Received<TransferReceived>>,
+	mut cancels: EventReader<Received<TransferCancel>>,
+	mut transfers: ResMut<Transfers>,
+	config: Res<TransferConfig>,
+	mut progress: EventWriter<TransferProgress>,
+	mut completed: EventWriter<TransferCompleted>,
+	mut failed: EventWriter<TransferFailed>,
+) {
+	// The messages of one transfer are sent in this order, so handling them by type keeps them in order.
+	for Received { msg, from, .. } in starts.iter() {
+		if msg.size > config.max_size {
+			transfers.cancels.push(Cancel {
+				connection: *from,
+				id: msg.id,
+				by_sender: false,
+				error: TransferError::TooLarge {
+					size: msg.size,
+					max: config.max_size,
+				},
+			});
+			continue;
+		}
+		if transfers.incoming.remove(&(*from, msg.id)).is_some() {
+			// The sender fails the transfer once it is cancelled, so the one in progress is dropped as well.
+			transfers.cancels.push(Cancel {
+				connection: *from,
+				id: msg.id,
+				by_sender: false,
+				error: TransferError::AlreadyStarted,
+			});
+			continue;
+		}
+		let incoming = Incoming {
+			name: msg.name.clone(),
+			size: msg.size,
+			received: 0,
+			crc: Crc32::default(),
+			sink: transfers.sink.as_ref().and_then(|sink| sink(*from, msg)),
+			data: Vec::new(),
+		};
+		transfers.incoming.insert((*from, msg.id), incoming);
+	}
+
+	let mut advanced = Vec::new();
+	for Received { msg, from, .. } in chunks.iter() {
+		let key = (*from, msg.id);
+		let Some(incoming) = transfers.incoming.get_mut(&key) else {
+			// Cancelled, or failed already.
+			continue;
+		};
+		let transferred = incoming.received + msg.data.len() as u64;
+		let result = if transferred > incoming.size {
+			Err(TransferError::SizeMismatch {
+				transferred,
+				size: incoming.size,
+			})
+		} else {
+			incoming.write(&msg.data).map_err(TransferError::from)
+		};
+		if let Err(error) = result {
+			transfers.incoming.remove(&key);
+			transfers.cancels.push(Cancel {
+				connection: *from,
+				id: msg.id,
+				by_sender: false,
+				error,
+			});
+			continue;
+		}
+		if !advanced.contains(&key) {
+			advanced.push(key);
+		}
+	}
+	for (connection, id) in advanced {
+		if let Some(incoming) = transfers.incoming.get(&(connection, id)) {
+			progress.send(TransferProgress {
+				connection,
+				id,
+				direction: TransferDirection::Receiving,
+				transferred: incoming.received,
+				size: incoming.size,
+			});
+		}
+	}
+
+	for Received { msg, from, .. } in ends.iter() {
+		let Some(mut incoming) = transfers.incoming.remove(&(*from, msg.id)) else {
+			continue;
+		};
+		let error = if incoming.received != incoming.size {
+			Some(TransferError::SizeMismatch {
+				transferred: incoming.received,
+				size: incoming.size,
+			})
+		} else if incoming.crc.finish() != msg.checksum {
+			Some(TransferError::ChecksumMismatch)
+		} else {
+			incoming.sink.as_mut().and_then(|sink| sink.flush().err()).map(TransferError::from)
+		};
+		match error {
+			Some(error) => transfers.cancels.push(Cancel {
+				connection: *from,
+				id: msg.id,
+				by_sender: false,
+				error,
+			}),
+			None => {
+				transfers.accepted.push((*from, msg.id));
+				completed.send(TransferCompleted {
+					from: *from,
+					id: msg.id,
+					name: incoming.name,
+					data: incoming.data,
+				});
+			}
+		}
+	}
+
+	for Received { msg, from, .. } in received.iter() {
+		if transfers.ended.get(&msg.id) == Some(from) {
+			transfers.ended.remove(&msg.id);
+		}
+	}
+
+	for Received { msg, from, .. } in cancels.iter() {
+		let direction = if msg.by_sender {
+			if transfers.incoming.remove(&(*from, msg.id)).is_none() {
+				continue;
+			}
+			TransferDirection::Receiving
+		} else {
+			if transfers.ended.get(&msg.id) == Some(from) {
+				transfers.ended.remove(&msg.id);
+			} else if matches!(transfers.outgoing.get(&msg.id), Some(transfer) if transfer.to == *from) {
+				transfers.outgoing.remove(&msg.id);
+			} else {
+				continue;
+			}
+			TransferDirection::Sending
+		};
+		failed.send(TransferFailed {
+			connection: *from,
+			id: msg.id,
+			direction,
+			error: TransferError::CancelledByPeer,
+		});
+	}
+}
+
+/// Sends and receives [Transfers].
+///
+/// Add it after [crate::MultiplayerPlugin] on both sides, and insert a [TransferConfig] first to change its settings.
+#[derive(Debug, Default)]
+pub struct TransferPlugin;
+
+impl Plugin for TransferPlugin {
+	fn build(&self, app: &mut App) {
+		app.world.get_resource_or_insert_with(TransferConfig::default);
+		app.register_type::<String>()
+			.register_type::<RawBytes>()
+			.add_net_message::<TransferStart>()
+			.add_net_message::<TransferChunk>()
+			.add_net_message::<TransferEnd>()
+			.add_net_message::<TransferReceived>()
+			.add_net_message::<TransferCancel>()
+			.init_resource::<Transfers>()
+			.add_event::<TransferProgress>()
+			.add_event::<TransferCompleted>()
+			.add_event::<TransferFailed>()
+			.add_system_to_stage(NetStage::Receive, receive_transfers.after(NetSystem::Dispatch))
+			.add_system_to_stage(NetStage::Send, send_transfers);
+	}
+}
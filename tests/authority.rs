@@ -0,0 +1,86 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::client::Client;
+use multiplayer_test::messaging::{NetEntityId, NetMsg};
+use multiplayer_test::replication::authority::{
+	AuthoritativeUpdate, Authority, AuthorityTransfers, LocalAuthority, UpdateRejected,
+};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt};
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Health(pub u32);
+
+#[derive(Resource, Default)]
+struct Rejections(u32);
+
+fn count_rejections(mut rejected: EventReader<UpdateRejected>, mut rejections: ResMut<Rejections>) {
+	rejections.0 += rejected.iter().count() as u32;
+}
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.replicate::<Health>();
+	app
+}
+
+#[test]
+fn authority() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server
+		.init_resource::<Rejections>()
+		.add_system(count_rejections);
+	let addr = common::listen(&mut server, &rt);
+	let owned = server.world.spawn((Replicated, Health(10))).id();
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+
+	let mut query = client.world.query_filtered::<(Entity, &NetEntityId), With<Health>>();
+	let mut remote = None;
+	run_until(&mut server, &mut client, |_, client| {
+		remote = query.iter(client).next().map(|(entity, net_id)| (entity, *net_id));
+		remote.is_some()
+	});
+	let (remote, net_id) = remote.unwrap();
+
+	// Updates from a client without authority are rejected.
+	let update = AuthoritativeUpdate { net_id, changed: vec![] };
+	client
+		.world
+		.resource::<Client<NetMsg, NetMsg>>()
+		.as_ref()
+		.unwrap()
+		.send_blocking(NetMsg::new(update))?;
+	run_until(&mut server, &mut client, |server, _| server.resource::<Rejections>().0 == 1);
+
+	// Hand authority to the client, which has to acknowledge it.
+	let connection = common::accept(&mut server, &mut client);
+	server
+		.world
+		.resource_mut::<AuthorityTransfers>()
+		.transfer(owned, Authority::Client(connection));
+	run_until(&mut server, &mut client, |server, client| {
+		client.get::<LocalAuthority>(remote).is_some()
+			&& server.get::<Authority>(owned) == Some(&Authority::Client(connection))
+	});
+
+	// The owner's changes are applied on the server.
+	client.world.get_mut::<Health>(remote).unwrap().0 = 42;
+	run_until(&mut server, &mut client, |server, _| server.get::<Health>(owned) == Some(&Health(42)));
+
+	// And after taking authority back, the server's changes are replicated again.
+	server
+		.world
+		.resource_mut::<AuthorityTransfers>()
+		.transfer(owned, Authority::Server);
+	run_until(&mut server, &mut client, |_, client| client.get::<LocalAuthority>(remote).is_none());
+	server.world.get_mut::<Health>(owned).unwrap().0 = 7;
+	run_until(&mut server, &mut client, |_, client| client.get::<Health>(remote) == Some(&Health(7)));
+	assert_eq!(server.world.resource::<Rejections>().0, 1);
+	Ok(())
+}
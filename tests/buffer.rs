@@ -0,0 +1,100 @@
+#![cfg(test)]
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use bytes::BytesMut;
+use multiplayer_test::connection::ConnectionError;
+use multiplayer_test::messaging::buffer::{BorrowedMsg, BufferPool, Frame};
+use multiplayer_test::messaging::codec::{Codec, CodecError, DecodeFn, PostcardCodec};
+use multiplayer_test::messaging::protocol::NetMessageRegistry;
+use multiplayer_test::messaging::{NetContext, NetDeserialize};
+
+#[test]
+fn buffer_pool_reuses_buffers() {
+	let pool = BufferPool::new(2, 1024);
+	let mut buf = pool.take(512);
+	assert!(buf.capacity() >= 512);
+	buf.extend_from_slice(&[1; 100]);
+	let capacity = buf.capacity();
+	pool.give(buf);
+
+	// The same buffer comes back, cleared.
+	let buf = pool.take(0);
+	assert!(buf.is_empty());
+	assert_eq!(buf.capacity(), capacity);
+}
+
+#[test]
+fn buffer_pool_limits() {
+	let pool = BufferPool::new(2, 1024);
+
+	// Buffers above the maximum capacity are dropped.
+	pool.give(BytesMut::with_capacity(4096));
+	assert_eq!(pool.take(0).capacity(), 0);
+
+	// As are buffers beyond the maximum number of buffers.
+	for _ in 0..3 {
+		pool.give(BytesMut::with_capacity(64));
+	}
+	assert!(pool.take(0).capacity() >= 64);
+	assert!(pool.take(0).capacity() >= 64);
+	assert_eq!(pool.take(0).capacity(), 0);
+}
+
+struct Greeting;
+
+impl BorrowedMsg for Greeting {
+	type Msg<'a> = (&'a str, u32);
+}
+
+fn frame(codec: Arc<dyn Codec>) -> Frame<Greeting> {
+	let bytes = PostcardCodec.encode(&("hello", 3u32)).unwrap();
+	let mut buf = BytesMut::from(&bytes[..]);
+	let ctx = NetContext::new(AppTypeRegistry::default(), NetMessageRegistry::default());
+	let frame = Frame::<Greeting>::from_frame(&mut buf, &ctx, &codec).unwrap();
+	// The frame takes the buffer's contents.
+	assert!(buf.is_empty());
+	frame
+}
+
+#[test]
+fn frame_borrows() {
+	let frame = frame(Arc::new(PostcardCodec));
+	let (text, count) = frame.get().unwrap();
+	assert_eq!((text, count), ("hello", 3));
+
+	// The string points into the frame instead of being copied out of it.
+	let bytes = frame.bytes().as_ptr_range();
+	assert!(bytes.contains(&text.as_ptr()));
+
+	// And can be deserialized again.
+	assert_eq!(frame.get().unwrap(), ("hello", 3));
+}
+
+/// Claims to decode frames without looking at them.
+#[derive(Debug)]
+struct Skipping;
+
+impl Codec for Skipping {
+	fn name(&self) -> &'static str {
+		"skipping"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		PostcardCodec.encode(value)
+	}
+
+	fn decode<'de>(&self, _bytes: &'de [u8], _visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		Ok(())
+	}
+}
+
+#[test]
+fn frame_must_be_visited() {
+	let frame = frame(Arc::new(Skipping));
+	assert!(matches!(
+		frame.get(),
+		Err(ConnectionError::SerializationError(CodecError::NotVisited("skipping")))
+	));
+}
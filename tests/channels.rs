@@ -0,0 +1,174 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::connection::channel::{ChannelConfig, ChannelOrdering};
+use multiplayer_test::connection::ext::Event;
+use multiplayer_test::connection::{ConnectionConfig, ConnectionError};
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin, FromServer},
+	MultiplayerPlugin,
+};
+
+const BULK: u8 = 0;
+const URGENT: u8 = 1;
+const STATE: u8 = 2;
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Packet {
+	pub id: u32,
+	pub data: Vec<u8>,
+}
+
+#[derive(Resource, Default)]
+struct Log(Vec<Packet>);
+
+fn log_packets(mut packets: EventReader<Received<Packet>>, mut log: ResMut<Log>) {
+	log.0.extend(packets.iter().map(|received| received.msg.clone()));
+}
+
+fn app() -> App {
+	app_with(vec![
+		ChannelConfig::default(),
+		ChannelConfig {
+			priority: 8,
+			..default()
+		},
+		ChannelConfig {
+			ordering: ChannelOrdering::Sequenced,
+			priority: 4,
+		},
+	])
+}
+
+fn app_with(channels: Vec<ChannelConfig>) -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.insert_resource(ConnectionConfig {
+			channels,
+			fragment_size: 1024,
+			..default()
+		})
+		.add_plugin(MultiplayerPlugin)
+		.register_type::<Vec<u8>>()
+		.add_net_message::<Packet>()
+		.init_resource::<Log>()
+		.add_system(log_packets);
+	app
+}
+
+/// Connects a client app to a server app, returning them once the server accepted the client.
+fn connect(
+	rt: &tokio::runtime::Runtime,
+	mut server: App,
+	mut client: App,
+) -> Result<(App, App), Box<dyn std::error::Error>> {
+	server.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+
+	let start = Instant::now();
+	while server.world.resource::<Server<NetMsg, NetMsg>>().connections.is_empty() {
+		assert!(start.elapsed() < Duration::from_secs(5), "Never connected.");
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	Ok((server, client))
+}
+
+#[test]
+fn channels() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let (mut server, mut client) = connect(&rt, app(), app())?;
+
+	// A big download is split into fragments, so the urgent message sent after it overtakes it.
+	let blob: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+	{
+		let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+		let conn = server.connections.iter().next().unwrap();
+		conn.send_on(BULK, NetMsg::new(Packet { id: 0, data: blob.clone() }))?;
+		conn.send_on(URGENT, NetMsg::new(Packet { id: 1, data: vec![] }))?;
+		for id in 2..5 {
+			conn.send_on(STATE, NetMsg::new(Packet { id, data: vec![] }))?;
+		}
+		assert!(matches!(
+			conn.send_on(3, NetMsg::new(Packet::default())),
+			Err(ConnectionError::UnknownChannel(3))
+		));
+	}
+
+	let start = Instant::now();
+	while !client.world.resource::<Log>().0.iter().any(|packet| packet.id == 0) {
+		assert!(start.elapsed() < Duration::from_secs(10), "The download never completed.");
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	let log = &client.world.resource::<Log>().0;
+	let position = |id| log.iter().position(|packet| packet.id == id);
+	assert!(
+		position(1).unwrap() < position(0).unwrap(),
+		"The urgent message should arrive before the download."
+	);
+	assert_eq!(log[position(0).unwrap()].data, blob);
+	// Older state may be replaced, but the latest always arrives.
+	assert!(position(4).is_some());
+	Ok(())
+}
+
+/// Channels the server sent on that the client rejected.
+#[derive(Resource, Default)]
+struct Rejected(Vec<u8>);
+
+fn log_rejected(mut events: EventReader<FromServer<NetMsg>>, mut rejected: ResMut<Rejected>) {
+	for event in events.iter() {
+		if let Event::Error(ConnectionError::UnknownChannel(channel), _) = &**event {
+			rejected.0.push(*channel);
+		}
+	}
+}
+
+#[test]
+fn unknown_channel() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	// The client only knows the default channel.
+	let mut client = app_with(vec![ChannelConfig::default()]);
+	client.init_resource::<Rejected>().add_system(log_rejected);
+	let (mut server, mut client) = connect(&rt, app(), client)?;
+
+	{
+		let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+		let conn = server.connections.iter().next().unwrap();
+		conn.send_on(STATE, NetMsg::new(Packet { id: 0, data: vec![] }))?;
+	}
+
+	let start = Instant::now();
+	while client.world.resource::<Rejected>().0.is_empty() {
+		assert!(start.elapsed() < Duration::from_secs(5), "The message was never rejected.");
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(client.world.resource::<Rejected>().0, vec![STATE]);
+	assert!(client.world.resource::<Log>().0.is_empty());
+	Ok(())
+}
@@ -0,0 +1,71 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::clock::{ClockSyncConfig, ClockSyncPlugin, ServerClock};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::tick::NetTick;
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin,
+};
+
+fn app() -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin)
+		.insert_resource(ClockSyncConfig {
+			interval: Duration::from_millis(20),
+			..default()
+		})
+		.add_plugin(ClockSyncPlugin);
+	app
+}
+
+#[test]
+fn clock() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let mut server = app();
+	server.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+	// Let the server get ahead of the client.
+	for _ in 0..20 {
+		server.update();
+		std::thread::sleep(Duration::from_millis(5));
+	}
+
+	let mut client = app();
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+
+	let start = Instant::now();
+	while start.elapsed() < Duration::from_millis(300) || !client.world.resource::<ServerClock>().is_synced() {
+		assert!(start.elapsed() < Duration::from_secs(5), "Clock never synced.");
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	let clock = client.world.resource::<ServerClock>();
+	let server_tick = *server.world.resource::<NetTick>();
+	let estimate = clock.now().unwrap();
+	assert!(server_tick.since(estimate).abs() <= 2, "{} vs {}", server_tick, estimate);
+	assert!(*client.world.resource::<NetTick>() < server_tick);
+	assert!(clock.confidence().unwrap() < Duration::from_millis(50));
+	assert!(clock.drift().abs() < 0.1);
+
+	let instant = clock.instant_at(estimate.offset(10)).unwrap();
+	assert_eq!(clock.tick_at(instant + Duration::from_micros(1)), Some(estimate.offset(10)));
+	Ok(())
+}
@@ -0,0 +1,192 @@
+#![cfg(test)]
+mod common;
+
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::client::{Client, FromServer};
+use multiplayer_test::connection::ext::Event;
+use multiplayer_test::connection::{ConnectionConfig, ConnectionError};
+use multiplayer_test::messaging::codec::{Codec, CodecError, DecodeFn, PostcardCodec};
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::{NetMsg, RawBytes};
+use multiplayer_test::server::Server;
+use tokio::runtime::Runtime;
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Greeting {
+	pub text: String,
+	pub count: u32,
+	pub scores: Vec<f32>,
+	pub reply: Option<bool>,
+	pub payload: RawBytes,
+}
+
+#[derive(Resource, Default)]
+struct Log {
+	greetings: Vec<Greeting>,
+	errors: Vec<ConnectionError>,
+}
+
+fn log_greetings(mut greetings: EventReader<Received<Greeting>>, mut log: ResMut<Log>) {
+	log.greetings.extend(greetings.iter().map(|received| received.msg.clone()));
+}
+
+fn log_errors(mut events: ResMut<Events<FromServer<NetMsg>>>, mut log: ResMut<Log>) {
+	for event in events.drain() {
+		if let Event::Error(err, _) = event.0 {
+			log.errors.push(err);
+		}
+	}
+}
+
+fn app(codec: Arc<dyn Codec>) -> App {
+	let mut app = common::app();
+	app.insert_resource(ConnectionConfig { codec, ..default() })
+		.register_type::<String>()
+		.register_type::<Vec<f32>>()
+		.register_type::<Option<bool>>()
+		.register_type::<RawBytes>()
+		.add_net_message::<Greeting>()
+		.init_resource::<Log>()
+		.add_system(log_greetings);
+	app
+}
+
+fn connect(rt: &Runtime, server_codec: Arc<dyn Codec>, client_codec: Arc<dyn Codec>) -> (App, App) {
+	let mut server = app(server_codec);
+	let addr = common::listen(&mut server, rt);
+	let mut client = app(client_codec);
+	common::connect(&mut client, addr, rt);
+	client.add_system(log_errors);
+	(server, client)
+}
+
+/// Sends a [Greeting] to the server and back.
+fn round_trip(codec: Arc<dyn Codec>) {
+	let rt = common::runtime();
+	let (mut server, mut client) = connect(&rt, codec.clone(), codec);
+	common::accept(&mut server, &mut client);
+
+	let greeting = Greeting {
+		text: "hello".to_owned(),
+		count: 3,
+		scores: vec![0.5, -1.0],
+		reply: None,
+		payload: RawBytes(vec![0, 1, 255]),
+	};
+	client
+		.world
+		.resource::<Client<NetMsg, NetMsg>>()
+		.as_ref()
+		.unwrap()
+		.send_blocking(NetMsg::new(greeting.clone()))
+		.unwrap();
+	run_until(&mut server, &mut client, |server, _| !server.resource::<Log>().greetings.is_empty());
+	assert_eq!(server.world.resource::<Log>().greetings, vec![greeting.clone()]);
+
+	let reply = Greeting {
+		reply: Some(true),
+		..greeting
+	};
+	let conn = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = conn.connections.iter().next().unwrap();
+	conn.send_blocking(NetMsg::new(reply.clone())).unwrap();
+	drop(conn);
+	run_until(&mut server, &mut client, |_, client| !client.resource::<Log>().greetings.is_empty());
+	assert_eq!(client.world.resource::<Log>().greetings, vec![reply]);
+	assert!(client.world.resource::<Log>().errors.is_empty());
+}
+
+#[test]
+fn postcard() {
+	round_trip(Arc::new(PostcardCodec));
+}
+
+#[cfg(feature = "bincode")]
+#[test]
+fn bincode() {
+	round_trip(Arc::new(multiplayer_test::messaging::codec::BincodeCodec));
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn msgpack() {
+	round_trip(Arc::new(multiplayer_test::messaging::codec::MessagePackCodec));
+}
+
+#[cfg(feature = "json")]
+#[test]
+fn json() {
+	round_trip(Arc::new(multiplayer_test::messaging::codec::JsonCodec));
+}
+
+/// Postcard under another name.
+#[derive(Debug)]
+struct Renamed;
+
+impl Codec for Renamed {
+	fn name(&self) -> &'static str {
+		"renamed"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		PostcardCodec.encode(value)
+	}
+
+	fn decode<'de>(&self, bytes: &'de [u8], visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		PostcardCodec.decode(bytes, visit)
+	}
+}
+
+/// Claims to decode frames without looking at them.
+#[derive(Debug)]
+struct Skipping;
+
+impl Codec for Skipping {
+	fn name(&self) -> &'static str {
+		"postcard"
+	}
+
+	fn encode(&self, value: &dyn erased_serde::Serialize) -> Result<Vec<u8>, CodecError> {
+		PostcardCodec.encode(value)
+	}
+
+	fn decode<'de>(&self, _bytes: &'de [u8], _visit: DecodeFn<'_, 'de>) -> Result<(), CodecError> {
+		Ok(())
+	}
+}
+
+#[test]
+fn codec_mismatch() {
+	let rt = common::runtime();
+	let (mut server, mut client) = connect(&rt, Arc::new(PostcardCodec), Arc::new(Renamed));
+	run_until(&mut server, &mut client, |_, client| !client.resource::<Log>().errors.is_empty());
+	let errors = &client.world.resource::<Log>().errors;
+	assert!(
+		matches!(
+			&errors[0],
+			ConnectionError::CodecMismatch { ours, theirs } if ours == "renamed" && theirs == "postcard"
+		),
+		"{errors:?}"
+	);
+}
+
+#[test]
+fn codec_must_visit() {
+	let rt = common::runtime();
+	let (mut server, mut client) = connect(&rt, Arc::new(PostcardCodec), Arc::new(Skipping));
+	common::accept(&mut server, &mut client);
+	let conn = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = conn.connections.iter().next().unwrap();
+	conn.send_blocking(NetMsg::new(Greeting::default())).unwrap();
+	drop(conn);
+	run_until(&mut server, &mut client, |_, client| !client.resource::<Log>().errors.is_empty());
+	let errors = &client.world.resource::<Log>().errors;
+	assert!(
+		matches!(&errors[0], ConnectionError::SerializationError(CodecError::NotVisited("postcard"))),
+		"{errors:?}"
+	);
+	assert!(client.world.resource::<Log>().greetings.is_empty());
+}
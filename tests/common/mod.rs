@@ -0,0 +1,102 @@
+//! Setup shared by the tests that run a server and a client app in the same process.
+#![allow(dead_code)]
+
+use std::{
+	net::SocketAddr,
+	time::{Duration, Instant},
+};
+
+use bevy::prelude::*;
+use multiplayer_test::{
+	client::{Client, ClientPlugin},
+	connection::ConnectionId,
+	messaging::NetMsg,
+	replication::{ReplicationConfig, ReplicationPlugin},
+	server::{Server, ServerPlugin},
+	MultiplayerPlugin,
+};
+use tokio::runtime::Runtime;
+
+/// How long [run_until] waits for its condition before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+pub fn runtime() -> Runtime {
+	tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()
+		.expect("Unable to build the runtime.")
+}
+
+/// An app with the [MultiplayerPlugin], for either side.
+pub fn app() -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins).add_plugin(MultiplayerPlugin);
+	app
+}
+
+/// Replicates often enough for changes to arrive within a few updates.
+pub fn replication_config() -> ReplicationConfig {
+	ReplicationConfig {
+		tick_rate: 200.0,
+		..default()
+	}
+}
+
+/// An [app] with the [ReplicationPlugin], using [replication_config].
+pub fn replication_app() -> App {
+	let mut app = app();
+	app.insert_resource(replication_config()).add_plugin(ReplicationPlugin);
+	app
+}
+
+/// Adds a [ServerPlugin] to `server` and binds it to a free port, returning its address.
+pub fn listen(server: &mut App, rt: &Runtime) -> SocketAddr {
+	server.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())
+		.expect("Unable to bind the server.")
+}
+
+/// Adds a [ClientPlugin] to `client` and connects it to the server at `addr`.
+pub fn connect(client: &mut App, addr: SocketAddr, rt: &Runtime) {
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+}
+
+/// Updates both apps until `done` holds.
+pub fn run_until(server: &mut App, client: &mut App, mut done: impl FnMut(&mut World, &mut World) -> bool) {
+	let start = Instant::now();
+	while !done(&mut server.world, &mut client.world) {
+		assert!(start.elapsed() < TIMEOUT, "Never caught up.");
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+}
+
+/// Updates both apps until the server accepted the client, returning the client's connection on the server.
+pub fn accept(server: &mut App, client: &mut App) -> ConnectionId {
+	run_until(server, client, |server, _| {
+		!server.resource::<Server<NetMsg, NetMsg>>().connections.is_empty()
+	});
+	*server
+		.world
+		.resource::<Server<NetMsg, NetMsg>>()
+		.connections
+		.iter()
+		.next()
+		.unwrap()
+		.key()
+}
+
+/// The server's connection on the client, which messages from the server are [Received] from.
+///
+/// [Received]: multiplayer_test::messaging::protocol::Received
+pub fn server_connection(client: &App) -> ConnectionId {
+	client.world.resource::<Client<NetMsg, NetMsg>>().as_ref().unwrap().uuid
+}
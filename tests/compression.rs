@@ -0,0 +1,149 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::client::{Client, FromServer};
+use multiplayer_test::connection::ext::Event;
+use multiplayer_test::connection::{Compression, ConnectionConfig, ConnectionError, ConnectionStats};
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::Server;
+use tokio::runtime::Runtime;
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Blob(pub Vec<u8>);
+
+#[derive(Resource, Default)]
+struct Log {
+	blobs: Vec<Blob>,
+	too_large: bool,
+}
+
+fn log_blobs(mut blobs: EventReader<Received<Blob>>, mut log: ResMut<Log>) {
+	log.blobs.extend(blobs.iter().map(|received| received.msg.clone()));
+}
+
+fn log_errors(mut events: EventReader<FromServer<NetMsg>>, mut log: ResMut<Log>) {
+	for event in events.iter() {
+		if let Event::Error(ConnectionError::MessageTooLarge { .. }, _) = &**event {
+			log.too_large = true;
+		}
+	}
+}
+
+fn app(config: ConnectionConfig) -> App {
+	let mut app = common::app();
+	app.insert_resource(config)
+		.register_type::<Vec<u8>>()
+		.add_net_message::<Blob>()
+		.init_resource::<Log>()
+		.add_system(log_blobs);
+	app
+}
+
+fn compressed(threshold: usize) -> ConnectionConfig {
+	ConnectionConfig {
+		compression: Some(Compression { threshold }),
+		..default()
+	}
+}
+
+/// Connects a client with `client_config` to a server with `server_config`.
+fn connect(rt: &Runtime, server_config: ConnectionConfig, client_config: ConnectionConfig) -> (App, App) {
+	let mut server = app(server_config);
+	let addr = common::listen(&mut server, rt);
+	let mut client = app(client_config);
+	common::connect(&mut client, addr, rt);
+	client.add_system(log_errors);
+	common::accept(&mut server, &mut client);
+	(server, client)
+}
+
+fn send(server: &App, blob: Blob) {
+	let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = server.connections.iter().next().unwrap();
+	conn.send_blocking(NetMsg::new(blob)).unwrap();
+}
+
+fn server_stats(server: &App, f: impl FnOnce(&ConnectionStats) -> u64) -> u64 {
+	let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = server.connections.iter().next().unwrap();
+	f(conn.stats())
+}
+
+fn client_stats(client: &App, f: impl FnOnce(&ConnectionStats) -> u64) -> u64 {
+	f(client.world.resource::<Client<NetMsg, NetMsg>>().as_ref().unwrap().stats())
+}
+
+fn received(client: &World) -> usize {
+	client.resource::<Log>().blobs.len()
+}
+
+#[test]
+fn compression() {
+	let rt = common::runtime();
+	let (mut server, mut client) = connect(&rt, compressed(256), compressed(256));
+
+	// Below the threshold, messages are sent as they are.
+	let small = Blob(vec![1; 16]);
+	send(&server, small.clone());
+	run_until(&mut server, &mut client, |_, client| received(client) == 1);
+	assert_eq!(server_stats(&server, ConnectionStats::bytes_saved), 0);
+
+	let large = Blob(vec![7; 64 * 1024]);
+	send(&server, large.clone());
+	run_until(&mut server, &mut client, |_, client| received(client) == 2);
+	assert_eq!(client.world.resource::<Log>().blobs, vec![small, large]);
+
+	let saved = server_stats(&server, ConnectionStats::bytes_saved);
+	let sent = server_stats(&server, ConnectionStats::bytes_sent);
+	assert!(saved > 60 * 1024, "Only saved {saved} bytes.");
+	assert!(sent < 4 * 1024, "Sent {sent} bytes.");
+	assert_eq!(server_stats(&server, ConnectionStats::messages_sent), 2);
+	assert_eq!(client_stats(&client, ConnectionStats::messages_received), 2);
+	assert_eq!(client_stats(&client, ConnectionStats::bytes_received), sent);
+}
+
+#[test]
+fn compression_needs_both_sides() {
+	let rt = common::runtime();
+	let (mut server, mut client) = connect(&rt, compressed(256), ConnectionConfig::default());
+
+	send(&server, Blob(vec![7; 64 * 1024]));
+	run_until(&mut server, &mut client, |_, client| received(client) == 1);
+	assert_eq!(server_stats(&server, ConnectionStats::bytes_saved), 0);
+	assert!(server_stats(&server, ConnectionStats::bytes_sent) > 64 * 1024);
+}
+
+#[test]
+fn oversized_messages() {
+	let rt = common::runtime();
+	let limited = |config: ConnectionConfig| ConnectionConfig {
+		max_message_size: 4096,
+		..config
+	};
+
+	// Compressed, the frame is small, but would decompress to more than the maximum.
+	let (mut server, mut client) = connect(&rt, compressed(256), limited(compressed(256)));
+	send(&server, Blob(vec![0; 64 * 1024]));
+	run_until(&mut server, &mut client, |_, client| client.resource::<Log>().too_large);
+	assert_eq!(received(&client.world), 0);
+
+	// Uncompressed, the frame itself is too large.
+	let uncompressed = ConnectionConfig::default;
+	let (mut server, mut client) = connect(&rt, uncompressed(), limited(uncompressed()));
+	send(&server, Blob(vec![0; 64 * 1024]));
+	run_until(&mut server, &mut client, |_, client| client.resource::<Log>().too_large);
+	assert_eq!(received(&client.world), 0);
+
+	// Fragmented, every frame is small enough, but not the message they add up to.
+	let fragmented = || ConnectionConfig {
+		fragment_size: 1024,
+		..default()
+	};
+	let (mut server, mut client) = connect(&rt, fragmented(), limited(fragmented()));
+	send(&server, Blob(vec![0; 64 * 1024]));
+	run_until(&mut server, &mut client, |_, client| client.resource::<Log>().too_large);
+	assert_eq!(received(&client.world), 0);
+}
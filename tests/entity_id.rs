@@ -0,0 +1,272 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::client::Client;
+use multiplayer_test::messaging::protocol::Received;
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::replication::spawn::{ClientSpawned, PredictedSpawn, SpawnPredicted, SpawnRejected};
+use multiplayer_test::replication::{ReplicationAppExt, ReplicationConfig, ReplicationPlugin, ReplicationServer};
+use multiplayer_test::server::Server;
+use multiplayer_test::{NetEntityAllocator, NetEntityConfig, NetEntityDespawned, NetEntityRegistry};
+
+fn id(index: u32, generation: u32) -> NetEntityId {
+	NetEntityId { index, generation }
+}
+
+#[test]
+fn allocator() {
+	let mut allocator = NetEntityAllocator::default();
+	assert_eq!(allocator.allocate(), id(0, 0));
+	assert_eq!(allocator.allocate(), id(1, 0));
+
+	// Freed indices are reused with the next generation.
+	assert!(allocator.free(id(0, 0)));
+	assert!(!allocator.free(id(0, 0)));
+	assert_eq!(allocator.reserve(4), 2..6);
+	assert_eq!(allocator.allocate(), id(0, 1));
+	assert_eq!(allocator.allocate(), id(6, 0));
+	// Reserved indices are never freed here.
+	assert!(!allocator.free(id(3, 0)));
+
+	// Unless a client spawned an entity with them.
+	assert!(allocator.claim(id(2, 0)));
+	assert!(!allocator.claim(id(2, 0)));
+	assert!(!allocator.claim(id(3, 1)));
+	assert!(allocator.free(id(2, 0)));
+
+	// Released once the client left, the unclaimed indices are allocated again.
+	assert!(allocator.claim(id(3, 0)));
+	allocator.release(2..6);
+	assert_eq!(allocator.allocate(), id(2, 1));
+	assert_eq!(allocator.allocate(), id(4, 0));
+	assert_eq!(allocator.allocate(), id(5, 0));
+	assert_eq!(allocator.allocate(), id(7, 0));
+
+	let mut client = NetEntityAllocator::default();
+	assert_eq!(client.allocate_reserved(), None);
+	client.add_reserved(2..4);
+	client.add_reserved(8..9);
+	assert_eq!(client.allocate_reserved(), Some(id(2, 0)));
+	assert_eq!(client.allocate_reserved(), Some(id(3, 0)));
+	assert_eq!(client.allocate_reserved(), Some(id(8, 0)));
+	assert_eq!(client.allocate_reserved(), None);
+}
+
+#[test]
+fn registry() {
+	let registry = NetEntityRegistry::default();
+	let a = Entity::from_raw(0);
+	let b = Entity::from_raw(1);
+	let id_a = registry.register(a);
+	assert_eq!(registry.get(id_a), Some(a));
+	assert_eq!(registry.get_id(a), Some(id_a));
+
+	// Mapping an id to another entity unlinks the previous one.
+	registry.insert(id_a, b);
+	assert_eq!(registry.get_id(a), None);
+	assert_eq!(registry.get_id(b), Some(id_a));
+
+	// As does giving an entity another id.
+	registry.insert(id(9, 0), b);
+	assert_eq!(registry.get(id_a), None);
+	assert_eq!(registry.len(), 1);
+
+	assert_eq!(registry.deregister_entity(b), Some(id(9, 0)));
+	assert!(registry.is_empty());
+	assert_eq!(registry.get_id(b), None);
+}
+
+fn app() -> App {
+	let mut app = common::app();
+	app.insert_resource(ReplicationConfig {
+		reserved_ids: 16,
+		..common::replication_config()
+	})
+	.add_plugin(ReplicationPlugin);
+	app
+}
+
+#[test]
+fn reserved_ids() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+
+	let connection = common::accept(&mut server, &mut client);
+	let predicted = client.world.spawn_empty().id();
+	let mut reserved = None;
+	run_until(&mut server, &mut client, |_, client| {
+		reserved = client.resource::<NetEntityRegistry>().register_reserved(predicted);
+		reserved.is_some()
+	});
+	let reserved = reserved.unwrap();
+	assert_eq!(client.world.resource::<NetEntityRegistry>().get(reserved), Some(predicted));
+
+	let replication = server.world.resource::<ReplicationServer>();
+	assert!(replication.is_reserved_for(connection, reserved));
+	assert!(!replication.is_reserved_for(connection, id(reserved.index + 16, 0)));
+
+	// The server's own ids don't overlap with the reserved range.
+	let entity = server.world.spawn_empty().id();
+	let allocated = server.world.resource::<NetEntityRegistry>().register(entity);
+	let replication = server.world.resource::<ReplicationServer>();
+	assert!(!replication.is_reserved_for(connection, allocated));
+	Ok(())
+}
+
+#[test]
+fn despawn_cleanup() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server.insert_resource(NetEntityConfig { notify_despawns: true });
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+
+	// A networked entity that isn't replicated, which both sides agree on the id of.
+	let entity = server.world.spawn_empty().id();
+	let net_id = server.world.resource::<NetEntityRegistry>().register(entity);
+	server.world.entity_mut(entity).insert(net_id);
+	let remote = client.world.spawn(net_id).id();
+	common::accept(&mut server, &mut client);
+	run_until(&mut server, &mut client, |_, client| {
+		client.resource::<NetEntityRegistry>().get(net_id) == Some(remote)
+	});
+	assert_eq!(client.world.resource::<NetEntityRegistry>().get_id(remote), Some(net_id));
+
+	// Despawning it cleans up the registry, and despawns the client's copy.
+	server.world.despawn(entity);
+	run_until(&mut server, &mut client, |server, client| {
+		server.resource::<NetEntityRegistry>().get(net_id).is_none() && client.get_entity(remote).is_none()
+	});
+	assert!(client.world.resource::<NetEntityRegistry>().get_id(remote).is_none());
+	assert_eq!(server.world.resource::<NetEntityRegistry>().get_id(entity), None);
+	Ok(())
+}
+
+#[test]
+fn client_despawns_ignored() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server.insert_resource(NetEntityConfig { notify_despawns: true });
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	client.insert_resource(NetEntityConfig { notify_despawns: true });
+	common::connect(&mut client, addr, &rt);
+
+	let entity = server.world.spawn_empty().id();
+	let net_id = server.world.resource::<NetEntityRegistry>().register(entity);
+	server.world.entity_mut(entity).insert(net_id);
+	let remote = client.world.spawn(net_id).id();
+	common::accept(&mut server, &mut client);
+	run_until(&mut server, &mut client, |_, client| {
+		client.resource::<NetEntityRegistry>().get(net_id) == Some(remote)
+	});
+
+	// The server hears about the client's despawn, but keeps its entity.
+	client.world.despawn(remote);
+	let mut reader = server.world.resource::<Events<Received<NetEntityDespawned>>>().get_reader();
+	let mut reported = Vec::new();
+	run_until(&mut server, &mut client, |server, _| {
+		let events = server.resource::<Events<Received<NetEntityDespawned>>>();
+		reported.extend(reader.iter(events).map(|received| received.msg.net_id));
+		!reported.is_empty()
+	});
+	assert_eq!(reported, vec![net_id]);
+	server.update();
+	assert!(server.world.get_entity(entity).is_some());
+	assert_eq!(server.world.resource::<NetEntityRegistry>().get(net_id), Some(entity));
+	Ok(())
+}
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Health(pub u32);
+
+fn healths(world: &mut World) -> Vec<(NetEntityId, u32)> {
+	let mut query = world.query::<(&NetEntityId, &Health)>();
+	let mut healths: Vec<_> = query.iter(world).map(|(net_id, health)| (*net_id, health.0)).collect();
+	healths.sort_by_key(|(net_id, _)| net_id.index);
+	healths
+}
+
+#[test]
+fn predicted_spawns() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+	let app = || {
+		let mut app = common::app();
+		app.insert_resource(ReplicationConfig {
+			reserved_ids: 4,
+			..common::replication_config()
+		})
+		.add_plugin(ReplicationPlugin)
+		.replicate::<Health>();
+		app
+	};
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+
+	// More entities than the first range holds, so the server has to reserve more.
+	let spawned: Vec<_> = (0..10).map(|i| client.world.spawn((SpawnPredicted, Health(i))).id()).collect();
+	let mut accepted = Vec::new();
+	run_until(&mut server, &mut client, |server, _| {
+		accepted.extend(server.resource_mut::<Events<ClientSpawned>>().drain());
+		accepted.len() == 10
+	});
+	assert!(accepted.iter().all(|spawned| spawned.connection == connection));
+	// Both sides agree on the ids, which are allocated from the reserved ranges in order.
+	let expected = healths(&mut client.world);
+	assert_eq!(healths(&mut server.world), expected);
+	let ids: Vec<_> = expected.iter().map(|(net_id, _)| *net_id).collect();
+	assert_eq!(ids, (0..10).map(|i| id(i, 0)).collect::<Vec<_>>());
+
+	// The client's entities are the ones the snapshots are applied to.
+	for _ in 0..20 {
+		server.update();
+		client.update();
+	}
+	assert_eq!(healths(&mut client.world), expected);
+	for entity in spawned {
+		assert!(client.world.get::<NetEntityId>(entity).is_some());
+	}
+
+	// Ids that weren't reserved for the client, or were used before, are rejected.
+	let forged = client.world.resource::<Client<NetMsg, NetMsg>>();
+	let forged = forged.as_ref().unwrap();
+	for net_id in [id(3, 0), id(100, 0)] {
+		forged.send_blocking(NetMsg::new(PredictedSpawn {
+			net_id,
+			components: Vec::new(),
+		}))?;
+	}
+	let mut rejected = Vec::new();
+	run_until(&mut server, &mut client, |server, _| {
+		rejected.extend(server.resource_mut::<Events<SpawnRejected>>().drain().map(|rejection| rejection.net_id));
+		rejected.len() == 2
+	});
+	assert_eq!(rejected, vec![id(3, 0), id(100, 0)]);
+	assert_eq!(healths(&mut server.world).len(), 10);
+
+	// Once the client left, its unused ids are allocated again.
+	server.world.resource::<Server<NetMsg, NetMsg>>().connections.remove(&connection);
+	run_until(&mut server, &mut client, |server, _| {
+		server.resource::<ReplicationServer>().clients().next().is_none()
+	});
+	let entity = server.world.spawn_empty().id();
+	assert_eq!(server.world.resource::<NetEntityRegistry>().register(entity), id(10, 0));
+	Ok(())
+}
@@ -0,0 +1,104 @@
+#![cfg(test)]
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::connection::{ConnectionConfig, FlushMode};
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::Server;
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Ping(pub u32);
+
+#[derive(Resource, Default)]
+struct Pings(Vec<u32>);
+
+fn record_pings(mut pings: EventReader<Received<Ping>>, mut log: ResMut<Pings>) {
+	log.0.extend(pings.iter().map(|ping| ping.msg.0));
+}
+
+fn app(flush_mode: FlushMode) -> App {
+	let mut app = common::app();
+	app.insert_resource(ConnectionConfig {
+		flush_mode,
+		..default()
+	})
+	.add_net_message::<Ping>()
+	.init_resource::<Pings>()
+	.add_system(record_pings);
+	app
+}
+
+/// Sends `count` pings from the server to its only client, without updating the server.
+fn send_pings(server: &App, count: u32) {
+	let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = server.connections.iter().next().unwrap();
+	for i in 0..count {
+		conn.send_blocking(NetMsg::new(Ping(i))).unwrap();
+	}
+}
+
+fn server_flushes(server: &App) -> u64 {
+	let server = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let conn = server.connections.iter().next().unwrap();
+	conn.stats().flushes()
+}
+
+#[test]
+fn manual_flush() {
+	let rt = common::runtime();
+
+	let mut server = app(FlushMode::Manual);
+	let addr = common::listen(&mut server, &rt);
+	let mut client = app(FlushMode::Immediate);
+	common::connect(&mut client, addr, &rt);
+	common::accept(&mut server, &mut client);
+	assert_eq!(server_flushes(&server), 0);
+
+	// Nothing arrives until the server's flush stage runs.
+	send_pings(&server, 20);
+	let start = Instant::now();
+	while start.elapsed() < Duration::from_millis(100) {
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert!(client.world.resource::<Pings>().0.is_empty());
+
+	// And then everything queued so far is written in a single batch.
+	server.update();
+	run_until(&mut server, &mut client, |_, client| client.resource::<Pings>().0.len() == 20);
+	assert_eq!(client.world.resource::<Pings>().0, (0..20).collect::<Vec<_>>());
+	assert_eq!(server_flushes(&server), 1);
+
+	// Frames without anything to send don't flush.
+	for _ in 0..10 {
+		server.update();
+	}
+	assert_eq!(server_flushes(&server), 1);
+}
+
+#[test]
+fn immediate_flush() {
+	let rt = common::runtime();
+
+	let mut server = app(FlushMode::Immediate);
+	let addr = common::listen(&mut server, &rt);
+	let mut client = app(FlushMode::Immediate);
+	common::connect(&mut client, addr, &rt);
+	common::accept(&mut server, &mut client);
+
+	// Messages are flushed without the server being updated.
+	send_pings(&server, 100);
+	let start = Instant::now();
+	while client.world.resource::<Pings>().0.len() < 100 {
+		assert!(start.elapsed() < common::TIMEOUT, "Pings were never flushed.");
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(client.world.resource::<Pings>().0, (0..100).collect::<Vec<_>>());
+	let flushes = server_flushes(&server);
+	assert!((1..=100).contains(&flushes), "Flushed {flushes} times.");
+}
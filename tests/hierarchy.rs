@@ -0,0 +1,73 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::replication::hierarchy::PendingParents;
+use multiplayer_test::replication::interest::NetVisibility;
+use multiplayer_test::replication::{Replicated, ReplicationAppExt};
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Health(pub u32);
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.replicate::<Health>();
+	app
+}
+
+fn find(world: &mut World, health: u32) -> Option<Entity> {
+	let mut query = world.query_filtered::<(Entity, &Health), With<NetEntityId>>();
+	query.iter(world).find(|(_, h)| h.0 == health).map(|(entity, _)| entity)
+}
+
+#[test]
+fn hierarchy() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+
+	// The parent is hidden at first, so the child arrives before it.
+	let mut visibility = NetVisibility::default();
+	visibility.hide(connection);
+	let parent = server.world.spawn((Replicated, Health(1), visibility)).id();
+	let child = server.world.spawn((Replicated, Health(2))).id();
+	server.world.entity_mut(parent).push_children(&[child]);
+
+	run_until(&mut server, &mut client, |_, client| find(client, 2).is_some());
+	let remote_child = find(&mut client.world, 2).unwrap();
+	assert!(client.world.get::<Parent>(remote_child).is_none());
+	assert_eq!(client.world.resource::<PendingParents>().len(), 1);
+
+	server.world.get_mut::<NetVisibility>(parent).unwrap().show(connection);
+	run_until(&mut server, &mut client, |_, client| client.get::<Parent>(remote_child).is_some());
+	let remote_parent = find(&mut client.world, 1).unwrap();
+	assert_eq!(client.world.get::<Parent>(remote_child).unwrap().get(), remote_parent);
+	assert_eq!(&**client.world.get::<Children>(remote_parent).unwrap(), &[remote_child]);
+	assert!(client.world.resource::<PendingParents>().is_empty());
+
+	// Moving to a parent that hasn't arrived yet detaches the child until it does.
+	let mut visibility = NetVisibility::default();
+	visibility.hide(connection);
+	let other = server.world.spawn((Replicated, Health(3), visibility)).id();
+	server.world.entity_mut(other).push_children(&[child]);
+	run_until(&mut server, &mut client, |_, client| client.get::<Parent>(remote_child).is_none());
+	assert!(client.world.get::<Children>(remote_parent).is_none());
+	assert_eq!(client.world.resource::<PendingParents>().len(), 1);
+
+	server.world.get_mut::<NetVisibility>(other).unwrap().show(connection);
+	run_until(&mut server, &mut client, |_, client| client.get::<Parent>(remote_child).is_some());
+	let remote_other = find(&mut client.world, 3).unwrap();
+	assert_eq!(client.world.get::<Parent>(remote_child).unwrap().get(), remote_other);
+
+	// Detaching is replicated too.
+	server.world.entity_mut(other).remove_children(&[child]);
+	run_until(&mut server, &mut client, |_, client| client.get::<Parent>(remote_child).is_none());
+	Ok(())
+}
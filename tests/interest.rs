@@ -0,0 +1,103 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::replication::interest::{
+	InterestChanged, InterestManagement, InterestViewer, NetVisibility, Relevancy,
+};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt};
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Tag(pub u32);
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.replicate::<Tag>();
+	app
+}
+
+#[derive(Resource, Default)]
+struct Changes(Vec<InterestChanged>);
+
+fn record_changes(mut events: EventReader<InterestChanged>, mut changes: ResMut<Changes>) {
+	changes.0.extend(events.iter().copied());
+}
+
+fn tags(world: &mut World) -> Vec<u32> {
+	let mut query = world.query_filtered::<&Tag, With<NetEntityId>>();
+	let mut tags: Vec<_> = query.iter(world).map(|tag| tag.0).collect();
+	tags.sort();
+	tags
+}
+
+fn run_until(server: &mut App, client: &mut App, expected: &[u32]) {
+	common::run_until(server, client, |_, client| tags(client) == expected);
+}
+
+#[test]
+fn interest() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server
+		.insert_resource(InterestManagement::new(Relevancy::Radius(10.0)))
+		.init_resource::<Changes>()
+		.add_system(record_changes);
+	let addr = common::listen(&mut server, &rt);
+	server
+		.world
+		.resource_mut::<InterestManagement>()
+		// Odd tags are secret.
+		.add_rule(|world, _, entity| world.get::<Tag>(entity).filter(|tag| tag.0 % 2 == 1).map(|_| false));
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+
+	server
+		.world
+		.spawn((InterestViewer { connection }, Transform::default()));
+	let near = server
+		.world
+		.spawn((Replicated, Tag(2), Transform::from_xyz(5.0, 0.0, 0.0)))
+		.id();
+	let far = server
+		.world
+		.spawn((Replicated, Tag(4), Transform::from_xyz(100.0, 0.0, 0.0)))
+		.id();
+	let global = server.world.spawn((Replicated, Tag(6))).id();
+	server
+		.world
+		.spawn((Replicated, Tag(7), Transform::from_xyz(1.0, 0.0, 0.0)));
+
+	run_until(&mut server, &mut client, &[2, 6]);
+
+	// Moving in and out of range spawns and despawns on the client.
+	server.world.get_mut::<Transform>(far).unwrap().translation.x = 3.0;
+	server.world.get_mut::<Transform>(near).unwrap().translation.x = -50.0;
+	run_until(&mut server, &mut client, &[4, 6]);
+
+	// Overrides take precedence over rules and range.
+	let mut visibility = NetVisibility::default();
+	visibility.hide(connection);
+	server.world.entity_mut(global).insert(visibility);
+	let mut visibility = NetVisibility::default();
+	visibility.show(connection);
+	server.world.entity_mut(near).insert(visibility);
+	run_until(&mut server, &mut client, &[2, 4]);
+
+	let changes = &server.world.resource::<Changes>().0;
+	let global = *server.world.get::<NetEntityId>(global).unwrap();
+	assert!(changes.contains(&InterestChanged {
+		connection,
+		net_id: global,
+		relevant: true,
+	}));
+	assert!(changes.contains(&InterestChanged {
+		connection,
+		net_id: global,
+		relevant: false,
+	}));
+	Ok(())
+}
@@ -0,0 +1,112 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::messaging::{NetEntityId, NetMsg};
+use multiplayer_test::replication::interpolation::{Interpolated, InterpolationAppExt, InterpolationConfig};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt, ReplicationConfig, ReplicationPlugin};
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::tick::NetTick;
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin,
+};
+
+#[test]
+fn sample() {
+	let mut interpolated = Interpolated::<Transform>::default();
+	assert!(interpolated.sample(0.0, 0.0).is_none());
+
+	interpolated.push(NetTick(10), Transform::from_xyz(0.0, 0.0, 0.0));
+	assert_eq!(interpolated.sample(20.0, 5.0).unwrap().translation.x, 0.0);
+
+	// Out of order states are sorted by tick.
+	interpolated.push(NetTick(14), Transform::from_xyz(4.0, 0.0, 0.0));
+	interpolated.push(NetTick(12), Transform::from_xyz(1.0, 0.0, 0.0));
+
+	let x = |interpolated: &Interpolated<Transform>, tick| interpolated.sample(tick, 3.0).unwrap().translation.x;
+	assert_eq!(x(&interpolated, 5.0), 0.0);
+	assert_eq!(x(&interpolated, 11.0), 0.5);
+	assert_eq!(x(&interpolated, 13.0), 2.5);
+	// Extrapolated from the last two states, up to 3 ticks.
+	assert_eq!(x(&interpolated, 15.0), 5.5);
+	assert_eq!(x(&interpolated, 30.0), 8.5);
+
+	interpolated.prune(13.0);
+	assert_eq!(interpolated.states().count(), 2);
+	assert_eq!(x(&interpolated, 13.0), 2.5);
+}
+
+fn app() -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin)
+		.insert_resource(ReplicationConfig {
+			tick_rate: 200.0,
+			..default()
+		})
+		.insert_resource(InterpolationConfig {
+			delay: Duration::from_millis(20),
+			..default()
+		})
+		.add_plugin(ReplicationPlugin)
+		.register_type::<Vec3>()
+		.register_type::<Quat>()
+		.replicate::<Transform>()
+		.interpolate::<Transform>();
+	app
+}
+
+#[test]
+fn interpolation() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let mut server = app();
+	server.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+	let moving = server.world.spawn((Replicated, Transform::default())).id();
+
+	let mut client = app();
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+
+	let mut query = client.world.query_filtered::<Entity, (With<NetEntityId>, With<Transform>)>();
+	let start = Instant::now();
+	let remote = loop {
+		assert!(start.elapsed() < Duration::from_secs(5), "Entity never arrived.");
+		server.update();
+		client.update();
+		if let Some(entity) = query.iter(&client.world).next() {
+			break entity;
+		}
+		std::thread::sleep(Duration::from_millis(1));
+	};
+	client
+		.world
+		.entity_mut(remote)
+		.insert(Interpolated::<Transform>::default());
+
+	// Snapshots now go into the buffer, and the rendered value follows them.
+	let start = Instant::now();
+	let mut x = 0.0;
+	while client.world.get::<Transform>(remote).unwrap().translation.x < 10.0 {
+		assert!(start.elapsed() < Duration::from_secs(5), "Interpolation never caught up.");
+		x = (x + 1.0f32).min(10.0);
+		server.world.get_mut::<Transform>(moving).unwrap().translation.x = x;
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(2));
+	}
+	let states = client.world.get::<Interpolated<Transform>>(remote).unwrap().states().count();
+	assert!(states >= 1);
+	Ok(())
+}
@@ -0,0 +1,88 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::replication::lag_compensation::{self, LagCompensationPlugin, TransformHistory};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt, ReplicationConfig, ReplicationPlugin, ReplicationServer};
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::tick::NetTick;
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin,
+};
+
+/// Moves every replicated entity to the current tick along the x axis.
+fn follow_tick(tick: Res<NetTick>, mut query: Query<&mut Transform, With<Replicated>>) {
+	for mut transform in query.iter_mut() {
+		transform.translation.x = tick.0 as f32;
+	}
+}
+
+fn app() -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin)
+		.insert_resource(ReplicationConfig {
+			tick_rate: 200.0,
+			..default()
+		})
+		.add_plugin(ReplicationPlugin)
+		.register_type::<Vec3>()
+		.register_type::<Quat>()
+		.replicate::<Transform>();
+	app
+}
+
+#[test]
+fn lag_compensation() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let mut server = app();
+	server
+		.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default())
+		.add_plugin(LagCompensationPlugin)
+		.add_system(follow_tick);
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+	let target = server.world.spawn((Replicated, Transform::default())).id();
+
+	let mut client = app();
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+
+	let start = Instant::now();
+	let connection = loop {
+		assert!(start.elapsed() < Duration::from_secs(5), "Round trip time was never measured.");
+		server.update();
+		client.update();
+		let replication = server.world.resource::<ReplicationServer>();
+		let measured = replication.clients().find(|conn| replication.rtt(*conn).is_some());
+		let history = server.world.get::<TransformHistory>(target).map_or(0, |history| history.states().count());
+		if let (Some(connection), true) = (measured, history > 10) {
+			break connection;
+		}
+		std::thread::sleep(Duration::from_millis(2));
+	};
+
+	let now = *server.world.resource::<NetTick>();
+	let (past, _) = *server.world.get::<TransformHistory>(target).unwrap().states().nth(5).unwrap();
+	let x = lag_compensation::rewind(&mut server.world, past, |world| {
+		world.get::<Transform>(target).unwrap().translation.x
+	});
+	assert_eq!(x, past.0 as f32);
+	assert_eq!(server.world.get::<Transform>(target).unwrap().translation.x, now.0 as f32);
+
+	// At least the default interpolation delay of 100ms, or 6 ticks, in the past.
+	let tick = lag_compensation::rewind_tick(&server.world, connection);
+	assert!(now.since(tick) >= 6);
+	Ok(())
+}
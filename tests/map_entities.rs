@@ -0,0 +1,80 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::replication::interest::NetVisibility;
+use multiplayer_test::replication::map_entities::{self, MapNetEntities, UnresolvedEntities};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt};
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Health(pub u32);
+
+#[derive(Component, Reflect, FromReflect, MapNetEntities, Debug, PartialEq)]
+pub struct Targeting {
+	#[net_entity]
+	pub target: Entity,
+	#[net_entity]
+	pub fallback: Option<Entity>,
+	pub damage: u32,
+}
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.register_type::<Option<Entity>>()
+		.replicate::<Health>()
+		.replicate_mapped::<Targeting>();
+	app
+}
+
+fn find(world: &mut World, health: u32) -> Option<Entity> {
+	let mut query = world.query_filtered::<(Entity, &Health), With<NetEntityId>>();
+	query.iter(world).find(|(_, h)| h.0 == health).map(|(entity, _)| entity)
+}
+
+#[test]
+fn map_entities() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+
+	// The target is hidden at first, so the reference to it can't be resolved.
+	let mut visibility = NetVisibility::default();
+	visibility.hide(connection);
+	let target = server.world.spawn((Replicated, Health(1), visibility)).id();
+	let local_only = server.world.spawn_empty().id();
+	server.world.spawn((
+		Replicated,
+		Health(2),
+		Targeting {
+			target,
+			fallback: Some(local_only),
+			damage: 5,
+		},
+	));
+
+	run_until(&mut server, &mut client, |_, client| find(client, 2).is_some());
+	let attacker = find(&mut client.world, 2).unwrap();
+	assert!(client.world.get::<Targeting>(attacker).is_none());
+	assert_eq!(client.world.resource::<UnresolvedEntities>().len(), 1);
+
+	server.world.get_mut::<NetVisibility>(target).unwrap().show(connection);
+	run_until(&mut server, &mut client, |_, client| client.get::<Targeting>(attacker).is_some());
+	let remote_target = find(&mut client.world, 1).unwrap();
+	assert_eq!(
+		client.world.get::<Targeting>(attacker),
+		Some(&Targeting {
+			target: remote_target,
+			fallback: Some(map_entities::dangling()),
+			damage: 5,
+		})
+	);
+	assert!(client.world.resource::<UnresolvedEntities>().is_empty());
+	Ok(())
+}
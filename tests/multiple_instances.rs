@@ -0,0 +1,96 @@
+#![cfg(test)]
+use assert_in_order::*;
+use bevy::log::{Level, LogPlugin};
+use bevy::{app::AppExit, prelude::*};
+use multiplayer_test::client::FromServer;
+use multiplayer_test::connection::ext::Event;
+use multiplayer_test::server::{FromClient, Server, ServerPlugin};
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin, RuntimeResource,
+};
+
+in_order_init!(TEST);
+
+pub struct GameServer;
+pub struct ChatRelay;
+
+#[test]
+fn multiple_instances() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	App::new()
+		.add_plugins(MinimalPlugins)
+		.add_plugin(LogPlugin {
+			level: Level::WARN,
+			..default()
+		})
+		.add_plugin(MultiplayerPlugin)
+		.add_plugin(ClientPlugin::<String, String, GameServer>::default())
+		.add_plugin(ServerPlugin::<String, String, GameServer>::default())
+		.add_plugin(ClientPlugin::<String, String, ChatRelay>::default())
+		.add_plugin(ServerPlugin::<String, String, ChatRelay>::default())
+		.insert_resource(RuntimeResource(rt))
+		.add_startup_system(setup)
+		.add_system(client_on_connect::<GameServer>)
+		.add_system(client_on_connect::<ChatRelay>)
+		.add_system(server_on_msg)
+		.run();
+	Ok(())
+}
+
+pub fn setup(
+	mut game_client: ResMut<Client<String, String, GameServer>>,
+	mut game_server: ResMut<Server<String, String, GameServer>>,
+	mut chat_client: ResMut<Client<String, String, ChatRelay>>,
+	mut chat_server: ResMut<Server<String, String, ChatRelay>>,
+	rt: Res<RuntimeResource>,
+) {
+	in_order!(TEST: binding);
+	let game_addr = game_server.listen("127.0.0.1:0", rt.handle().clone()).unwrap();
+	let chat_addr = chat_server.listen("127.0.0.1:0", rt.handle().clone()).unwrap();
+	in_order!(TEST: connecting after binding);
+	game_client.connect(game_addr, rt.handle().clone());
+	chat_client.connect(chat_addr, rt.handle().clone());
+}
+
+pub fn client_on_connect<I: Send + Sync + 'static>(
+	mut connecteds: EventReader<FromServer<String, I>>,
+	client: Res<Client<String, String, I>>,
+) {
+	for event in connecteds.iter() {
+		let Event::Connected(_, _) = &**event else {
+			continue;
+		};
+		let client = (**client).as_ref().unwrap();
+		client.send_blocking(std::any::type_name::<I>().to_owned()).unwrap();
+	}
+}
+
+pub fn server_on_msg(
+	mut game_events: EventReader<FromClient<String, GameServer>>,
+	mut chat_events: EventReader<FromClient<String, ChatRelay>>,
+	mut received: Local<u32>,
+	mut exit: EventWriter<AppExit>,
+) {
+	for event in game_events.iter() {
+		let Event::Message(msg, _) = &**event else {
+			continue;
+		};
+		assert_eq!(msg, std::any::type_name::<GameServer>());
+		*received += 1;
+	}
+	for event in chat_events.iter() {
+		let Event::Message(msg, _) = &**event else {
+			continue;
+		};
+		assert_eq!(msg, std::any::type_name::<ChatRelay>());
+		*received += 1;
+	}
+	if *received >= 2 {
+		exit.send(AppExit);
+	}
+}
@@ -0,0 +1,135 @@
+#![cfg(test)]
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::connection::ConnectionId;
+use multiplayer_test::messaging::events::{NetEventAppExt, NetEventDirection, Reliability};
+use multiplayer_test::messaging::protocol::Received;
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Explosion {
+	pub radius: f32,
+}
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone, PartialEq)]
+pub struct Chat {
+	pub text: String,
+}
+
+#[derive(Resource, Default)]
+struct Log {
+	explosions: Vec<Explosion>,
+	chat: Vec<Chat>,
+	senders: Vec<ConnectionId>,
+	/// Chat messages that came from a peer.
+	received_chat: usize,
+}
+
+fn log_events(
+	mut explosions: EventReader<Explosion>,
+	mut chat: EventReader<Chat>,
+	mut received: EventReader<Received<Chat>>,
+	mut log: ResMut<Log>,
+) {
+	log.explosions.extend(explosions.iter().cloned());
+	log.chat.extend(chat.iter().cloned());
+	for Received { from, .. } in received.iter() {
+		log.senders.push(*from);
+		log.received_chat += 1;
+	}
+}
+
+fn app() -> App {
+	let mut app = common::app();
+	app.register_type::<String>()
+		.add_network_event::<Explosion>(NetEventDirection::ServerToClient, Reliability::Reliable)
+		.add_network_event::<Chat>(NetEventDirection::Both, Reliability::Reliable)
+		.init_resource::<Log>()
+		.add_system(log_events);
+	app
+}
+
+#[test]
+fn network_event() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+
+	server.world.send_event(Explosion { radius: 2.0 });
+	run_until(&mut server, &mut client, |_, client| !client.resource::<Log>().explosions.is_empty());
+	assert_eq!(client.world.resource::<Log>().explosions, vec![Explosion { radius: 2.0 }]);
+
+	// Events coming from a client are tagged with its connection, and not sent back to it.
+	let hello = Chat { text: "hello".into() };
+	client.world.send_event(hello.clone());
+	run_until(&mut server, &mut client, |server, _| !server.resource::<Log>().chat.is_empty());
+	assert_eq!(server.world.resource::<Log>().chat, vec![hello]);
+	assert_eq!(server.world.resource::<Log>().senders, vec![connection]);
+
+	// Explosions don't go the other way.
+	client.world.send_event(Explosion { radius: 5.0 });
+	for _ in 0..20 {
+		server.update();
+		client.update();
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	assert_eq!(client.world.resource::<Log>().received_chat, 0);
+	// Only the server's own explosion.
+	assert_eq!(server.world.resource::<Log>().explosions, vec![Explosion { radius: 2.0 }]);
+	Ok(())
+}
+
+/// Updates the apps until `done`, like [run_until] for a chain of three apps.
+fn run_chain_until(mut apps: [&mut App; 3], done: impl Fn(&World, &World, &World) -> bool) {
+	let start = Instant::now();
+	while !done(&apps[0].world, &apps[1].world, &apps[2].world) {
+		assert!(start.elapsed() < common::TIMEOUT, "Never caught up.");
+		for app in apps.iter_mut() {
+			app.update();
+		}
+		std::thread::sleep(Duration::from_millis(1));
+	}
+}
+
+#[test]
+fn relay() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	// The relay is a server for the client, and a client of the upstream server.
+	let mut upstream = app();
+	let upstream_addr = common::listen(&mut upstream, &rt);
+	let mut relay = app();
+	let relay_addr = common::listen(&mut relay, &rt);
+	common::connect(&mut relay, upstream_addr, &rt);
+	common::accept(&mut upstream, &mut relay);
+	let mut client = app();
+	common::connect(&mut client, relay_addr, &rt);
+	common::accept(&mut relay, &mut client);
+
+	// Events from its server are accepted by the relay.
+	upstream.world.send_event(Explosion { radius: 2.0 });
+	run_chain_until([&mut upstream, &mut relay, &mut client], |_, relay, _| !relay.resource::<Log>().explosions.is_empty());
+
+	// Its own events go to both sides.
+	relay.world.send_event(Chat { text: "hello".into() });
+	run_chain_until([&mut upstream, &mut relay, &mut client], |upstream, _, client| {
+		!upstream.resource::<Log>().chat.is_empty() && !client.resource::<Log>().chat.is_empty()
+	});
+
+	// Events from its client are still only accepted in their direction.
+	client.world.send_event(Explosion { radius: 5.0 });
+	// A chat message sent after it shows when it arrived.
+	client.update();
+	client.world.send_event(Chat { text: "hi".into() });
+	run_chain_until([&mut upstream, &mut relay, &mut client], |_, relay, _| relay.resource::<Log>().received_chat == 1);
+	assert_eq!(relay.world.resource::<Log>().explosions, vec![Explosion { radius: 2.0 }]);
+	Ok(())
+}
@@ -0,0 +1,135 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::messaging::protocol::Received;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::replication::prediction::{
+	ClientInput, InputBuffer, Predicted, PredictionAppExt, PredictionHistory,
+};
+use multiplayer_test::replication::{Replicated, ReplicationAppExt, ReplicationClient};
+use multiplayer_test::tick::NetTick;
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Position(pub i32);
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone)]
+pub struct Move {
+	pub dx: i32,
+}
+
+#[derive(Reflect, FromReflect, Default, Debug, Clone)]
+pub struct Look {
+	pub yaw: i32,
+}
+
+/// The server has a wall at 3 that the client doesn't know about.
+const WALL: i32 = 3;
+
+fn apply_moves(mut moves: EventReader<Received<ClientInput<Move>>>, mut positions: Query<&mut Position>) {
+	for Received { msg, .. } in moves.iter() {
+		for mut position in positions.iter_mut() {
+			position.0 = (position.0 + msg.input.dx).min(WALL);
+		}
+	}
+}
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.replicate::<Position>()
+		.add_prediction::<Move, _>(|world, entity, input| {
+			world.get_mut::<Position>(entity).unwrap().0 += input.dx;
+		});
+	app
+}
+
+fn position(world: &mut World) -> Option<i32> {
+	let mut query = world.query_filtered::<&Position, With<NetEntityId>>();
+	query.iter(world).next().map(|position| position.0)
+}
+
+/// Records `moves` for consecutive ticks starting at `tick`.
+fn record(world: &mut World, tick: &mut u32, moves: &[i32]) {
+	let mut buffer = world.resource_mut::<InputBuffer<Move>>();
+	for dx in moves {
+		*tick += 1;
+		buffer.record(NetTick(*tick), Move { dx: *dx });
+	}
+}
+
+#[test]
+fn prediction() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server.add_system(apply_moves);
+	let addr = common::listen(&mut server, &rt);
+	server.world.spawn((Replicated, Position(0)));
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+
+	run_until(&mut server, &mut client, |_, client| position(client) == Some(0));
+	let mut query = client.world.query_filtered::<Entity, With<Position>>();
+	let player = query.single(&client.world);
+	client.world.entity_mut(player).insert(Predicted);
+
+	// Inputs are applied right away.
+	let mut tick = 0;
+	record(&mut client.world, &mut tick, &[1, 1, 1, 1, 1]);
+	client.update();
+	assert_eq!(position(&mut client.world), Some(5));
+
+	// The server stopped at the wall, so the client is rolled back.
+	run_until(&mut server, &mut client, |_, client| position(client) == Some(WALL));
+	assert_eq!(client.world.resource::<PredictionHistory<Move>>().rollbacks(), 1);
+
+	// Predictions the server agrees with are kept.
+	record(&mut client.world, &mut tick, &[-1, -1]);
+	client.update();
+	assert_eq!(position(&mut client.world), Some(1));
+	run_until(&mut server, &mut client, |_, client| {
+		client.resource::<InputBuffer<Move>>().unacknowledged().next().is_none()
+	});
+	assert_eq!(position(&mut client.world), Some(1));
+	assert_eq!(client.world.resource::<PredictionHistory<Move>>().rollbacks(), 1);
+	Ok(())
+}
+
+#[test]
+fn input_acks_per_type() {
+	let rt = common::runtime();
+
+	let peer = || {
+		let mut app = app();
+		app.add_prediction::<Look, _>(|_, _, _| {});
+		app
+	};
+	let mut server = peer();
+	let addr = common::listen(&mut server, &rt);
+	server.world.spawn((Replicated, Position(0)));
+	let mut client = peer();
+	common::connect(&mut client, addr, &rt);
+	run_until(&mut server, &mut client, |_, client| position(client) == Some(0));
+
+	// Each input type is acknowledged up to its own last input.
+	let mut tick = 0;
+	record(&mut client.world, &mut tick, &[1, 1]);
+	let mut buffer = client.world.resource_mut::<InputBuffer<Look>>();
+	for tick in 1..=5 {
+		buffer.record(NetTick(tick), Look { yaw: 1 });
+	}
+	run_until(&mut server, &mut client, |_, client| {
+		let replication = client.resource::<ReplicationClient>();
+		replication.input_ack::<Move>().is_some() && replication.input_ack::<Look>() == Some(NetTick(5))
+	});
+	assert_eq!(client.world.resource::<ReplicationClient>().input_ack::<Move>(), Some(NetTick(2)));
+
+	// Later inputs of the other type are still predicted and acknowledged.
+	record(&mut client.world, &mut tick, &[1]);
+	run_until(&mut server, &mut client, |_, client| {
+		client.resource::<ReplicationClient>().input_ack::<Move>() == Some(NetTick(3))
+	});
+	assert!(client.world.resource::<InputBuffer<Move>>().unacknowledged().next().is_none());
+}
@@ -0,0 +1,52 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::replication::resource::ReplicateResource;
+
+#[derive(Resource, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Score {
+	pub red: u32,
+	pub blue: u32,
+}
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.add_plugin(ReplicateResource::<Score>::default());
+	app
+}
+
+#[test]
+fn replicate_resource() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server
+		.insert_resource(Score { red: 3, blue: 1 });
+	let addr = common::listen(&mut server, &rt);
+
+	// The resource is sent in full once the client connects, without having changed.
+	server.update();
+	server.update();
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	run_until(&mut server, &mut client, |_, client| {
+		client.get_resource::<Score>() == Some(&Score { red: 3, blue: 1 })
+	});
+
+	// Changes are sent afterwards.
+	server.world.resource_mut::<Score>().blue = 2;
+	run_until(&mut server, &mut client, |_, client| {
+		client.get_resource::<Score>() == Some(&Score { red: 3, blue: 2 })
+	});
+
+	// As is its removal, and inserting it again.
+	server.world.remove_resource::<Score>();
+	run_until(&mut server, &mut client, |_, client| client.get_resource::<Score>().is_none());
+	server.insert_resource(Score { red: 4, blue: 2 });
+	run_until(&mut server, &mut client, |_, client| {
+		client.get_resource::<Score>() == Some(&Score { red: 4, blue: 2 })
+	});
+	Ok(())
+}
@@ -0,0 +1,79 @@
+#![cfg(test)]
+mod common;
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::messaging::NetEntityId;
+use multiplayer_test::replication::{Replicated, ReplicationAppExt, ReplicationConfig, ReplicationPlugin};
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Health(pub u32);
+
+#[derive(Component, Reflect, FromReflect, Default, Debug, PartialEq)]
+pub struct Nickname(pub String);
+
+fn app() -> App {
+	let mut app = common::replication_app();
+	app.replicate::<Health>().replicate::<Nickname>();
+	app
+}
+
+fn replicated(world: &mut World) -> Vec<(NetEntityId, Option<u32>, Option<String>)> {
+	let mut query = world.query::<(&NetEntityId, Option<&Health>, Option<&Nickname>)>();
+	let mut entities: Vec<_> = query
+		.iter(world)
+		.map(|(net_id, health, name)| (*net_id, health.map(|h| h.0), name.map(|n| n.0.clone())))
+		.collect();
+	entities.sort_by_key(|(_, health, _)| *health);
+	entities
+}
+
+#[test]
+fn replication() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let player = server
+		.world
+		.spawn((Replicated, Health(10), Nickname("player".to_owned())))
+		.id();
+	let crate_ = server.world.spawn((Replicated, Health(50))).id();
+	// Not replicated.
+	server.world.spawn(Health(1));
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+
+	run_until(&mut server, &mut client, |_, client| replicated(client).len() == 2);
+	let entities = replicated(&mut client.world);
+	assert_eq!(entities[0].1, Some(10));
+	assert_eq!(entities[0].2.as_deref(), Some("player"));
+	assert_eq!(entities[1].1, Some(50));
+	assert_eq!(entities[1].2, None);
+	assert_eq!(entities[0].0, *server.world.get::<NetEntityId>(player).unwrap());
+
+	// Changes are sent as deltas against the acknowledged snapshot.
+	server.world.get_mut::<Health>(player).unwrap().0 = 5;
+	server.world.entity_mut(player).remove::<Nickname>();
+	server.world.despawn(crate_);
+	run_until(&mut server, &mut client, |_, client| {
+		replicated(client) == vec![(entities[0].0, Some(5), None)]
+	});
+
+	server.world.spawn((Replicated, Nickname("late".to_owned())));
+	run_until(&mut server, &mut client, |_, client| replicated(client).len() == 2);
+	Ok(())
+}
+
+#[test]
+#[should_panic(expected = "`ReplicationConfig::tick_rate` has to be positive")]
+fn zero_tick_rate() {
+	let mut app = common::app();
+	app.insert_resource(ReplicationConfig {
+		tick_rate: 0.0,
+		..default()
+	})
+	.add_plugin(ReplicationPlugin);
+}
@@ -0,0 +1,193 @@
+#![cfg(test)]
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::connection::ConnectionId;
+use multiplayer_test::rpc::{NetRequest, RpcAppExt, RpcCall, RpcCalls, RpcError};
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin,
+};
+
+#[derive(Reflect, FromReflect, Default)]
+pub struct JoinLobby {
+	pub lobby: u32,
+}
+
+#[derive(Reflect, FromReflect, Default)]
+pub struct LobbyState {
+	pub lobby: u32,
+	pub players: u32,
+}
+
+impl NetRequest for JoinLobby {
+	type Response = LobbyState;
+}
+
+#[test]
+fn rpc() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let mut server = App::new();
+	server
+		.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin)
+		.add_rpc_handler::<JoinLobby, _>(|_world, req, _from| {
+			if req.lobby == 0 {
+				return Err(RpcError::Failed("lobby 0 is closed".to_owned()));
+			}
+			Ok(LobbyState {
+				lobby: req.lobby,
+				players: 1,
+			})
+		})
+		.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+
+	let mut client = App::new();
+	client
+		.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin)
+		.add_rpc::<JoinLobby>()
+		.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+
+	let conn = client.world.resource::<Client<NetMsg, NetMsg>>();
+	let calls = client.world.resource::<RpcCalls>();
+	let joined = calls.call(conn.as_ref().unwrap(), JoinLobby { lobby: 3 })?;
+	let closed = calls.call(conn.as_ref().unwrap(), JoinLobby { lobby: 0 })?;
+
+	let (mut joined_result, mut closed_result) = (None, None);
+	let start = Instant::now();
+	while joined_result.is_none() || closed_result.is_none() {
+		assert!(start.elapsed() < Duration::from_secs(5), "Calls were never answered.");
+		client.update();
+		server.update();
+		joined_result = joined_result.or_else(|| joined.try_recv());
+		closed_result = closed_result.or_else(|| closed.try_recv());
+		std::thread::sleep(Duration::from_millis(1));
+	}
+
+	let state = joined_result.unwrap().unwrap();
+	assert_eq!((state.lobby, state.players), (3, 1));
+	assert_eq!(
+		closed_result.unwrap().err(),
+		Some(RpcError::Failed("lobby 0 is closed".to_owned()))
+	);
+	Ok(())
+}
+
+#[derive(Resource, Default)]
+struct Handled(u32);
+
+/// Answers with the number of requests this app handled so far.
+fn count_players(world: &mut World, req: JoinLobby, _from: ConnectionId) -> Result<LobbyState, RpcError> {
+	let mut handled = world.resource_mut::<Handled>();
+	handled.0 += 1;
+	Ok(LobbyState {
+		lobby: req.lobby,
+		players: handled.0,
+	})
+}
+
+fn wait(server: &mut App, client: &mut App, call: &RpcCall<LobbyState>) -> LobbyState {
+	let mut result = None;
+	common::run_until(server, client, |_, _| {
+		result = result.take().or_else(|| call.try_recv());
+		result.is_some()
+	});
+	result.unwrap().unwrap()
+}
+
+#[test]
+fn rpc_both_ways() {
+	let rt = common::runtime();
+
+	// Both sides call and answer the same request, and register it more than once.
+	let peer = || {
+		let mut app = common::app();
+		app.init_resource::<Handled>()
+			.add_rpc::<JoinLobby>()
+			.add_rpc_handler::<JoinLobby, _>(|_, _, _| Err(RpcError::Failed("replaced".to_owned())))
+			.add_rpc_handler::<JoinLobby, _>(count_players)
+			.add_rpc::<JoinLobby>();
+		app
+	};
+	let mut server = peer();
+	let addr = common::listen(&mut server, &rt);
+	let mut client = peer();
+	common::connect(&mut client, addr, &rt);
+	let id = common::accept(&mut server, &mut client);
+
+	for players in 1..=2 {
+		let conn = client.world.resource::<Client<NetMsg, NetMsg>>();
+		let call = client
+			.world
+			.resource::<RpcCalls>()
+			.call(conn.as_ref().unwrap(), JoinLobby { lobby: 1 })
+			.unwrap();
+		let state = wait(&mut server, &mut client, &call);
+		assert_eq!((state.lobby, state.players), (1, players));
+
+		let conn = server.world.resource::<Server<NetMsg, NetMsg>>();
+		let call = server
+			.world
+			.resource::<RpcCalls>()
+			.call(&conn.connections.get(&id).unwrap(), JoinLobby { lobby: 2 })
+			.unwrap();
+		let state = wait(&mut server, &mut client, &call);
+		assert_eq!((state.lobby, state.players), (2, players));
+	}
+	assert_eq!(server.world.resource::<Handled>().0, 2);
+	assert_eq!(client.world.resource::<Handled>().0, 2);
+}
+
+#[test]
+fn rpc_disconnected() {
+	let rt = common::runtime();
+
+	// Neither side answers, so the calls only end when the connection closes.
+	let mut server = common::app();
+	server.add_rpc::<JoinLobby>();
+	let addr = common::listen(&mut server, &rt);
+	let mut client = common::app();
+	client.add_rpc::<JoinLobby>();
+	common::connect(&mut client, addr, &rt);
+	let id = common::accept(&mut server, &mut client);
+
+	let conn = client.world.resource::<Client<NetMsg, NetMsg>>();
+	let to_server = client
+		.world
+		.resource::<RpcCalls>()
+		.call(conn.as_ref().unwrap(), JoinLobby { lobby: 1 })
+		.unwrap();
+	let conn = server.world.resource::<Server<NetMsg, NetMsg>>();
+	let to_client = server
+		.world
+		.resource::<RpcCalls>()
+		.call(&conn.connections.get(&id).unwrap(), JoinLobby { lobby: 2 })
+		.unwrap();
+	server.world.resource::<Server<NetMsg, NetMsg>>().connections.remove(&id);
+
+	let (mut server_result, mut client_result) = (None, None);
+	common::run_until(&mut server, &mut client, |_, _| {
+		server_result = server_result.take().or_else(|| to_client.try_recv());
+		client_result = client_result.take().or_else(|| to_server.try_recv());
+		server_result.is_some() && client_result.is_some()
+	});
+	assert_eq!(server_result.unwrap().err(), Some(RpcError::Disconnected));
+	assert_eq!(client_result.unwrap().err(), Some(RpcError::Disconnected));
+}
@@ -0,0 +1,76 @@
+#![cfg(test)]
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::{Server, ServerPlugin};
+use multiplayer_test::{
+	self,
+	client::{Client, ClientPlugin},
+	MultiplayerPlugin,
+};
+
+#[derive(Reflect, FromReflect, Default)]
+pub struct Ping {
+	pub value: u32,
+}
+
+#[derive(Reflect, FromReflect, Default)]
+pub struct Pong;
+
+fn app() -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.add_plugin(MultiplayerPlugin);
+	app
+}
+
+/// Two apps in one process, each serializing [NetMsg]s with its own type registry.
+#[test]
+fn separate_apps() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = tokio::runtime::Builder::new_multi_thread()
+		.enable_io()
+		.build()?;
+
+	let mut server = app();
+	// `Pong` is registered only on the server, so `Ping` has different ids on both sides.
+	server.add_net_message::<Pong>().add_net_message::<Ping>();
+	// Registering again must not add a second dispatcher.
+	server.add_net_message::<Ping>();
+	server.add_plugin(ServerPlugin::<NetMsg, NetMsg>::default());
+	let addr = server
+		.world
+		.resource_mut::<Server<NetMsg, NetMsg>>()
+		.listen("127.0.0.1:0", rt.handle().clone())?;
+
+	let mut client = app();
+	client.add_net_message::<Ping>();
+	client.add_plugin(ClientPlugin::<NetMsg, NetMsg>::default());
+	client
+		.world
+		.resource_mut::<Client<NetMsg, NetMsg>>()
+		.connect(addr, rt.handle().clone());
+	client
+		.world
+		.resource::<Client<NetMsg, NetMsg>>()
+		.as_ref()
+		.unwrap()
+		.send_blocking(NetMsg::new(Ping { value: 42 }))?;
+
+	let start = Instant::now();
+	loop {
+		assert!(start.elapsed() < Duration::from_secs(5), "Message was never received.");
+		client.update();
+		server.update();
+		let events = server.world.resource::<Events<Received<Ping>>>();
+		let received: Vec<_> = events.iter_current_update_events().collect();
+		if let Some(first) = received.first() {
+			assert_eq!(first.msg.value, 42);
+			assert_eq!(received.len(), 1, "`Ping` was dispatched twice.");
+			break;
+		}
+		std::thread::sleep(Duration::from_millis(1));
+	}
+	Ok(())
+}
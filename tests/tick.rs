@@ -0,0 +1,134 @@
+#![cfg(test)]
+mod common;
+
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+use multiplayer_test::messaging::protocol::{NetAppExt, Received};
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::tick::{NetTick, NetTickConfig};
+use multiplayer_test::{client::Client, MultiplayerPlugin, NetStage};
+
+#[derive(Reflect, FromReflect, Default, Debug)]
+pub struct Ping {
+	pub sent_at: u32,
+}
+
+#[derive(Resource, Default)]
+struct Runs {
+	receive: u32,
+	send: u32,
+}
+
+fn count_receive_runs(mut runs: ResMut<Runs>) {
+	runs.receive += 1;
+}
+
+fn count_send_runs(mut runs: ResMut<Runs>) {
+	runs.send += 1;
+}
+
+/// An app whose clock only moves through [update].
+fn app(tick_rate: f64, run_on_tick: bool) -> App {
+	let mut app = App::new();
+	app.add_plugins(MinimalPlugins)
+		.insert_resource(TimeUpdateStrategy::ManualInstant(Instant::now()))
+		.insert_resource(NetTickConfig {
+			tick_rate,
+			run_on_tick,
+		})
+		.add_plugin(MultiplayerPlugin)
+		.add_net_message::<Ping>()
+		.init_resource::<Runs>()
+		.add_system_to_stage(NetStage::Receive, count_receive_runs)
+		.add_system_to_stage(NetStage::Send, count_send_runs);
+	app
+}
+
+/// Advances the app's clock by `delta` and updates it.
+fn update(app: &mut App, delta: Duration) {
+	if let TimeUpdateStrategy::ManualInstant(now) = &mut *app.world.resource_mut::<TimeUpdateStrategy>() {
+		*now += delta;
+	}
+	app.update();
+}
+
+fn tick(app: &App) -> u32 {
+	app.world.resource::<NetTick>().0
+}
+
+fn runs(app: &App) -> (u32, u32) {
+	let runs = app.world.resource::<Runs>();
+	(runs.receive, runs.send)
+}
+
+#[test]
+fn run_on_tick() {
+	let mut app = app(20.0, true);
+	// The first frame only starts the clock.
+	update(&mut app, Duration::ZERO);
+	assert_eq!((tick(&app), runs(&app)), (0, (0, 0)));
+
+	// A tick every fifth frame.
+	for frame in 1..=12 {
+		update(&mut app, Duration::from_millis(10));
+		let ticks = frame / 5;
+		assert_eq!((tick(&app), runs(&app)), (ticks, (ticks, ticks)), "frame {frame}");
+	}
+
+	// A long frame runs the stages once for every tick it spans.
+	update(&mut app, Duration::from_millis(225));
+	assert_eq!((tick(&app), runs(&app)), (6, (6, 6)));
+	update(&mut app, Duration::from_millis(10));
+	assert_eq!((tick(&app), runs(&app)), (7, (7, 7)));
+	update(&mut app, Duration::from_millis(10));
+	assert_eq!((tick(&app), runs(&app)), (7, (7, 7)));
+}
+
+#[test]
+fn tick_stamps() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app(100.0, false);
+	let addr = common::listen(&mut server, &rt);
+	let mut client = app(20.0, true);
+	common::connect(&mut client, addr, &rt);
+	// The clocks stand still while connecting, the first frames only start them.
+	common::accept(&mut server, &mut client);
+	update(&mut server, Duration::ZERO);
+	update(&mut client, Duration::ZERO);
+	assert_eq!((tick(&server), tick(&client)), (0, 0));
+
+	let server_runs = runs(&server);
+	for _ in 0..10 {
+		update(&mut server, Duration::from_millis(10));
+		update(&mut client, Duration::from_millis(10));
+	}
+	// The faster server is further along, and runs its stages every frame.
+	assert_eq!((tick(&server), tick(&client)), (10, 2));
+	assert_eq!(runs(&server), (server_runs.0 + 10, server_runs.1 + 10));
+	assert_eq!(runs(&client), (2, 2));
+
+	let sent_at = tick(&client);
+	client
+		.world
+		.resource::<Client<NetMsg, NetMsg>>()
+		.as_ref()
+		.unwrap()
+		.send_blocking(NetMsg::new(Ping { sent_at }))?;
+
+	let mut pings = Vec::new();
+	common::run_until(&mut server, &mut client, |server, _| {
+		pings.extend(server.resource_mut::<Events<Received<Ping>>>().drain());
+		!pings.is_empty()
+	});
+	assert_eq!(pings[0].tick, NetTick(sent_at));
+	Ok(())
+}
+
+#[test]
+#[should_panic(expected = "`NetTickConfig::tick_rate` has to be positive")]
+fn zero_tick_rate() {
+	app(0.0, false);
+}
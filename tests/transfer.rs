@@ -0,0 +1,237 @@
+#![cfg(test)]
+mod common;
+
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use common::run_until;
+use multiplayer_test::client::Client;
+use multiplayer_test::connection::ConnectionError;
+use multiplayer_test::messaging::NetMsg;
+use multiplayer_test::server::Server;
+use multiplayer_test::transfer::{
+	Crc32, TransferCompleted, TransferConfig, TransferDirection, TransferError, TransferFailed, TransferPlugin,
+	TransferProgress, TransferStart, Transfers,
+};
+
+#[derive(Resource, Default)]
+struct Log {
+	progress: Vec<TransferProgress>,
+	completed: Vec<TransferCompleted>,
+	failed: Vec<TransferFailed>,
+}
+
+fn log_transfers(
+	mut progress: EventReader<TransferProgress>,
+	mut completed: EventReader<TransferCompleted>,
+	mut failed: ResMut<Events<TransferFailed>>,
+	mut log: ResMut<Log>,
+) {
+	log.progress.extend(progress.iter().cloned());
+	log.completed.extend(completed.iter().cloned());
+	log.failed.extend(failed.drain());
+}
+
+fn app() -> App {
+	let mut app = common::app();
+	app.insert_resource(TransferConfig {
+		chunk_size: 16 * 1024,
+		max_size: u64::MAX,
+		..default()
+	})
+	.add_plugin(TransferPlugin)
+	.init_resource::<Log>()
+	.add_system(log_transfers);
+	app
+}
+
+#[test]
+fn crc32() {
+	let mut crc = Crc32::default();
+	crc.update(b"1234");
+	crc.update(b"56789");
+	assert_eq!(crc.finish(), 0xcbf4_3926);
+}
+
+#[test]
+fn transfer() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+	let to_server = common::server_connection(&client);
+
+	// A save file uploaded by the client.
+	let save: Vec<u8> = (0..100_000).map(|i| (i % 253) as u8).collect();
+	let id = client.world.resource_mut::<Transfers>().send_bytes(to_server, "save", save.clone());
+	run_until(&mut server, &mut client, |server, _| !server.resource::<Log>().completed.is_empty());
+	let completed = &server.world.resource::<Log>().completed[0];
+	assert_eq!((completed.from, completed.id, completed.name.as_str()), (connection, id, "save"));
+	assert_eq!(completed.data, save);
+
+	// Both sides reported their progress.
+	let last_sent = client.world.resource::<Log>().progress.last().cloned().unwrap();
+	assert_eq!((last_sent.direction, last_sent.transferred), (TransferDirection::Sending, 100_000));
+	let received = &server.world.resource::<Log>().progress;
+	assert_eq!(received.last().map(|progress| progress.transferred), Some(100_000));
+	assert!(received.iter().all(|progress| progress.direction == TransferDirection::Receiving));
+	assert!(!client.world.resource::<Transfers>().is_sending(id));
+
+	// An endless stream is only read as fast as it is sent, until the receiver cancels it.
+	let id = server
+		.world
+		.resource_mut::<Transfers>()
+		.send(connection, "replay", u64::MAX, std::io::repeat(7));
+	run_until(&mut server, &mut client, |_, client| {
+		let progress = &client.resource::<Log>().progress;
+		progress.iter().any(|progress| progress.direction == TransferDirection::Receiving)
+	});
+	assert!(client.world.resource_mut::<Transfers>().cancel_receive(to_server, id));
+	run_until(&mut server, &mut client, |server, _| !server.resource::<Log>().failed.is_empty());
+	let failed = &server.world.resource::<Log>().failed[0];
+	assert_eq!((failed.id, failed.direction), (id, TransferDirection::Sending));
+	assert!(matches!(failed.error, TransferError::CancelledByPeer));
+	assert!(!server.world.resource::<Transfers>().is_sending(id));
+	assert!(matches!(
+		client.world.resource::<Log>().failed[0].error,
+		TransferError::Cancelled
+	));
+	Ok(())
+}
+
+/// Shares what is written to it with the test.
+#[derive(Clone, Default)]
+struct SharedSink(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedSink {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		self.0.lock().unwrap().write(buf)
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn transfer_limits() -> Result<(), Box<dyn std::error::Error>> {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server.world.resource_mut::<TransferConfig>().max_size = 64 * 1024;
+	let sink = SharedSink::default();
+	let shared = sink.clone();
+	server.world.resource_mut::<Transfers>().set_sink(move |_, start| {
+		(start.name == "map").then(|| Box::new(shared.clone()) as Box<dyn Write + Send + Sync>)
+	});
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+	let to_server = common::server_connection(&client);
+
+	// Written to the sink instead of the event.
+	let map: Vec<u8> = (0..50_000).map(|i| (i % 241) as u8).collect();
+	client.world.resource_mut::<Transfers>().send_bytes(to_server, "map", map.clone());
+	run_until(&mut server, &mut client, |server, _| !server.resource::<Log>().completed.is_empty());
+	assert!(server.world.resource::<Log>().completed[0].data.is_empty());
+	assert_eq!(*sink.0.lock().unwrap(), map);
+
+	// Larger payloads than the server accepts are cancelled as soon as they start.
+	let id = client
+		.world
+		.resource_mut::<Transfers>()
+		.send(to_server, "huge", 1 << 40, std::io::repeat(1));
+	run_until(&mut server, &mut client, |server, client| {
+		!server.resource::<Log>().failed.is_empty() && !client.resource::<Log>().failed.is_empty()
+	});
+	assert!(matches!(client.world.resource::<Log>().failed[0].error, TransferError::CancelledByPeer));
+	let failed = &server.world.resource::<Log>().failed[0];
+	assert_eq!((failed.connection, failed.id), (connection, id));
+	assert!(matches!(failed.error, TransferError::TooLarge { size, max } if (size, max) == (1 << 40, 64 * 1024)));
+
+	// A closed connection fails the transfers on both sides.
+	server.world.resource_mut::<TransferConfig>().max_size = u64::MAX;
+	let id = client
+		.world
+		.resource_mut::<Transfers>()
+		.send(to_server, "replay", u64::MAX, std::io::repeat(7));
+	run_until(&mut server, &mut client, |server, _| server.resource::<Transfers>().is_receiving(connection, id));
+	server.world.resource::<Server<NetMsg, NetMsg>>().connections.remove(&connection);
+	run_until(&mut server, &mut client, |server, client| {
+		server.resource::<Log>().failed.len() == 2 && client.resource::<Log>().failed.len() == 2
+	});
+	let failed = &server.world.resource::<Log>().failed[1];
+	assert_eq!((failed.id, failed.direction), (id, TransferDirection::Receiving));
+	assert!(matches!(failed.error, TransferError::ConnectionError(ConnectionError::Disconnected)));
+	assert!(!server.world.resource::<Transfers>().is_receiving(connection, id));
+	let failed = &client.world.resource::<Log>().failed[1];
+	assert_eq!((failed.id, failed.direction), (id, TransferDirection::Sending));
+	assert!(!client.world.resource::<Transfers>().is_sending(id));
+	Ok(())
+}
+
+/// Accepts the payload, but fails to flush it.
+struct BrokenSink;
+
+impl Write for BrokenSink {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		Err(std::io::ErrorKind::WriteZero.into())
+	}
+}
+
+#[test]
+fn transfer_rejected() {
+	let rt = common::runtime();
+
+	let mut server = app();
+	server
+		.world
+		.resource_mut::<Transfers>()
+		.set_sink(|_, _| Some(Box::new(BrokenSink) as Box<dyn Write + Send + Sync>));
+	let addr = common::listen(&mut server, &rt);
+
+	let mut client = app();
+	common::connect(&mut client, addr, &rt);
+	let connection = common::accept(&mut server, &mut client);
+	let to_server = common::server_connection(&client);
+
+	// A payload the receiver rejects after the last chunk fails on both sides.
+	let id = client.world.resource_mut::<Transfers>().send_bytes(to_server, "save", vec![1; 1000]);
+	run_until(&mut server, &mut client, |server, client| {
+		!server.resource::<Log>().failed.is_empty() && !client.resource::<Log>().failed.is_empty()
+	});
+	let failed = &server.world.resource::<Log>().failed[0];
+	assert_eq!((failed.connection, failed.id), (connection, id));
+	assert!(matches!(failed.error, TransferError::IOError(_)));
+	let failed = &client.world.resource::<Log>().failed[0];
+	assert_eq!((failed.id, failed.direction), (id, TransferDirection::Sending));
+	assert!(matches!(failed.error, TransferError::CancelledByPeer));
+	assert!(server.world.resource::<Log>().completed.is_empty());
+
+	// A transfer that is started twice is rejected.
+	let conn = client.world.resource::<Client<NetMsg, NetMsg>>();
+	for _ in 0..2 {
+		let start = TransferStart {
+			id: 100,
+			name: "map".to_owned(),
+			size: 10,
+		};
+		conn.as_ref().unwrap().send_blocking(NetMsg::new(start)).unwrap();
+	}
+	run_until(&mut server, &mut client, |server, _| server.resource::<Log>().failed.len() == 2);
+	let failed = &server.world.resource::<Log>().failed[1];
+	assert_eq!((failed.id, failed.direction), (100, TransferDirection::Receiving));
+	assert!(matches!(failed.error, TransferError::AlreadyStarted));
+	assert!(!server.world.resource::<Transfers>().is_receiving(connection, 100));
+}